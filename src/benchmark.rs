@@ -0,0 +1,247 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    config::{Config, Target},
+    copy::copy_file,
+    sftp::Remotes,
+};
+
+/// Generated files of one size, copied one after another.
+pub struct FileSet {
+    pub name: &'static str,
+    pub count: usize,
+    pub size: u64,
+}
+
+/// The files copied to each target by `--benchmark` (about 150 MB).
+pub const FILE_SETS: &[FileSet] = &[
+    FileSet {
+        name: "small files",
+        count: 500,
+        size: 16 * 1024,
+    },
+    FileSet {
+        name: "medium files",
+        count: 50,
+        size: 1024 * 1024,
+    },
+    FileSet {
+        name: "large files",
+        count: 2,
+        size: 64 * 1024 * 1024,
+    },
+];
+
+#[derive(Debug, Default)]
+struct Measurement {
+    files: usize,
+    bytes: u64,
+    duration: Duration,
+}
+
+impl Measurement {
+    fn format(&self, fmt_size: &dyn Fn(u64) -> String) -> String {
+        let secs = self.duration.as_secs_f64().max(1e-9);
+
+        format!(
+            "{:>8}/s, {:>7.1} files/s ({} files, {} in {:.2} s)",
+            fmt_size((self.bytes as f64 / secs) as u64),
+            self.files as f64 / secs,
+            self.files,
+            fmt_size(self.bytes),
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+/// Copies generated files to a temporary directory and to every target and prints the throughput,
+/// to tell a slow target from a slow copy. Everything created is removed again.
+pub fn run(
+    config: &Config,
+    remotes: &Remotes,
+    file_sets: &[FileSet],
+    fmt_size: &dyn Fn(u64) -> String,
+) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("sortnbackup-benchmark-{}", std::process::id()));
+    println!("Generating files in {}...", dir.display());
+    let result = generate(&dir.join("src"), file_sets).and_then(|files| {
+        println!("Copying files one at a time...");
+        println!();
+
+        let baseline = Target {
+            path: dir.join("dst"),
+            ..Default::default()
+        };
+        let mut targets = vec![("temporary directory (baseline)".to_owned(), &baseline, None)];
+        let mut names = config.targets.keys().collect::<Vec<_>>();
+        names.sort();
+        targets.extend(names.into_iter().map(|name| {
+            (
+                format!("target '{}'", name),
+                &config.targets[name],
+                remotes.get(name),
+            )
+        }));
+
+        for (name, target, remote) in targets {
+            let bench_dir = target
+                .path
+                .join(format!(".sortnbackup-benchmark-{}", std::process::id()));
+
+            println!("{}:", name);
+            for (set, files) in file_sets.iter().zip(&files) {
+                let measurement = files.iter().try_fold(
+                    Measurement::default(),
+                    |mut measurement, from| -> Result<_> {
+                        let to = bench_dir.join(from.file_name().unwrap());
+                        let started = Instant::now();
+                        match remote {
+                            Some(pool) => pool.copy(from, &to, &config.settings, target)?,
+                            None => {
+                                std::fs::create_dir_all(&bench_dir)?;
+                                copy_file(from, &to, &config.settings, target)?
+                            }
+                        };
+                        measurement.duration += started.elapsed();
+                        measurement.files += 1;
+                        measurement.bytes += set.size;
+
+                        Ok(measurement)
+                    },
+                );
+                match measurement {
+                    Ok(measurement) => {
+                        println!("  {:<12} {}", set.name, measurement.format(fmt_size))
+                    }
+                    Err(e) => println!("  {:<12} failed: {:#}", set.name, e),
+                }
+            }
+
+            // files of failed sets may not exist, so only a remaining directory is reported
+            let cleanup = match remote {
+                Some(pool) => {
+                    for from in files.iter().flatten() {
+                        let _ = pool.remove_file(&bench_dir.join(from.file_name().unwrap()));
+                    }
+                    pool.remove_dir(&bench_dir)
+                }
+                None if bench_dir.exists() => Ok(std::fs::remove_dir_all(&bench_dir)?),
+                None => Ok(()),
+            };
+            if let Err(e) = cleanup {
+                eprintln!("WARNING: Cannot remove {}: {:#}", bench_dir.display(), e);
+            }
+        }
+
+        Ok(())
+    });
+
+    let _ = std::fs::remove_dir_all(&dir);
+
+    result
+}
+
+/// Writes the files of each set to `dir`. The content is pseudo-random, so it can't be compressed.
+fn generate(dir: &Path, file_sets: &[FileSet]) -> Result<Vec<Vec<PathBuf>>> {
+    std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut chunk = vec![0; 64 * 1024];
+    file_sets
+        .iter()
+        .enumerate()
+        .map(|(i, set)| {
+            (0..set.count)
+                .map(|j| {
+                    let path = dir.join(format!("{}-{}.bin", i, j));
+                    let mut file = BufWriter::new(File::create(&path)?);
+                    let mut remaining = set.size;
+                    while remaining > 0 {
+                        for byte in &mut chunk {
+                            // xorshift64
+                            state ^= state << 13;
+                            state ^= state >> 7;
+                            state ^= state << 17;
+                            *byte = state as u8;
+                        }
+                        let len = remaining.min(chunk.len() as u64);
+                        file.write_all(&chunk[..len as usize])?;
+                        remaining -= len;
+                    }
+                    file.flush()?;
+
+                    Ok(path)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_run() {
+        let dir = TempDir::new("bench");
+        let mut targets = std::collections::HashMap::new();
+        targets.insert(
+            "disk".to_owned(),
+            Target {
+                path: dir.to_path_buf(),
+                ..Default::default()
+            },
+        );
+        let config = Config::with_targets(targets);
+        let file_sets = [FileSet {
+            name: "tiny files",
+            count: 3,
+            size: 100_000,
+        }];
+
+        run(&config, &Remotes::new(&config), &file_sets, &|size| {
+            size.to_string()
+        })
+        .unwrap();
+
+        // the target is left as it was
+        assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+    }
+
+    #[test]
+    fn test_generate() {
+        let dir = TempDir::new("gen");
+        let file_sets = [
+            FileSet {
+                name: "a",
+                count: 2,
+                size: 10,
+            },
+            FileSet {
+                name: "b",
+                count: 1,
+                size: 100_000,
+            },
+        ];
+
+        let files = generate(&dir, &file_sets).unwrap();
+
+        assert_eq!(vec![2, 1], files.iter().map(Vec::len).collect::<Vec<_>>());
+        let content = std::fs::read(&files[1][0]).unwrap();
+        assert_eq!(100_000, content.len());
+        assert!(content.iter().any(|&b| b != content[0]));
+        assert_ne!(
+            std::fs::read(&files[0][0]).unwrap(),
+            std::fs::read(&files[0][1]).unwrap()
+        );
+    }
+}
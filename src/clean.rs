@@ -0,0 +1,201 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use walkdir::WalkDir;
+
+use crate::{
+    config::{Config, Settings},
+    copy::is_part_file,
+};
+
+/// Files sortnbackup leaves in the working directory, for `--continue`.
+const STATE_FILES: &[&str] = &["index.yaml", "progress.yaml", "completed.yaml"];
+
+/// Names of the probes of the preflight check and the folders of `--benchmark`, which are
+/// removed again unless the run is interrupted.
+const PROBE_PREFIXES: &[&str] = &[".sortnbackup-preflight-", ".sortnbackup-benchmark-"];
+
+/// Removes what interrupted runs leave behind (`--clean`), or only lists it with `dry_run`.
+///
+/// Only files that are certainly ours are touched: the state files in `dir` (unless kept on
+/// purpose with `keep_index` / `keep_progress`) and their temporary copies, probes and benchmark
+/// folders on the targets, and part files of copies (also on targets without `staging`, where
+/// clones are made in them). SFTP targets are skipped.
+pub fn run(config: &Config, dir: &Path, dry_run: bool) -> Result<()> {
+    let mut names = config.targets.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        if config.targets[name].remote.is_some() {
+            println!("Skipping SFTP target '{}'", name);
+        }
+    }
+
+    let leftovers = find_leftovers(config, dir);
+    if leftovers.is_empty() {
+        println!("Nothing to clean");
+
+        return Ok(());
+    }
+
+    println!(
+        "{} {} leftovers of interrupted runs:",
+        if dry_run { "Would remove" } else { "Removing" },
+        leftovers.len()
+    );
+    let mut failed = 0;
+    for path in &leftovers {
+        println!("  {}", path.display());
+        if dry_run {
+            continue;
+        }
+
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("WARNING: Cannot remove {}: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if !dry_run {
+        println!(
+            "Removed {} of {}",
+            leftovers.len() - failed,
+            leftovers.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn find_leftovers(config: &Config, dir: &Path) -> BTreeSet<PathBuf> {
+    let mut leftovers = BTreeSet::new();
+    for &name in STATE_FILES {
+        if !is_kept(&config.settings, name) && dir.join(name).is_file() {
+            leftovers.insert(dir.join(name));
+        }
+        let temp = dir.join(format!("{}.tmp", name));
+        if temp.is_file() {
+            leftovers.insert(temp);
+        }
+    }
+
+    for target in config.targets.values().filter(|t| t.remote.is_none()) {
+        if !target.path.is_dir() {
+            continue;
+        }
+
+        let mut walker = WalkDir::new(&target.path).min_depth(1).into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("WARNING: Cannot clean {}", e);
+                    continue;
+                }
+            };
+            let name = entry.file_name().to_string_lossy();
+            if PROBE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                leftovers.insert(entry.into_path());
+            } else if entry.file_type().is_file() && is_part_file(&name) {
+                leftovers.insert(entry.into_path());
+            }
+        }
+    }
+
+    leftovers
+}
+
+fn is_kept(settings: &Settings, state_file: &str) -> bool {
+    match state_file {
+        "index.yaml" => settings.keep_index,
+        _ => settings.keep_progress,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Target;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_find_leftovers() {
+        let dir = TempDir::new("clean");
+        for sub in [
+            "work",
+            "staged/Photos",
+            "plain",
+            "staged/.sortnbackup-benchmark-1",
+        ] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in [
+            "work/index.yaml",
+            "work/progress.yaml.tmp",
+            "work/manifest.yaml",
+            "staged/Photos/a.jpg",
+            "staged/Photos/.b.jpg.sortnbackup-part",
+            "staged/Photos/notes.part",
+            "staged/Photos/.sortnbackup-preflight-7",
+            "staged/.sortnbackup-benchmark-1/0-0.bin",
+            "plain/.download.sortnbackup-part",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let target = |path: &str, staging| Target {
+            path: dir.join(path),
+            staging,
+            ..Default::default()
+        };
+        let mut config = Config::with_targets(
+            vec![
+                ("staged".to_owned(), target("staged", true)),
+                ("plain".to_owned(), target("plain", false)),
+                ("missing".to_owned(), target("missing", true)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let found = find_leftovers(&config, &dir.join("work"));
+        let expected = [
+            "plain/.download.sortnbackup-part",
+            "staged/.sortnbackup-benchmark-1",
+            "staged/Photos/.sortnbackup-preflight-7",
+            "staged/Photos/.b.jpg.sortnbackup-part",
+            "work/index.yaml",
+            "work/progress.yaml.tmp",
+        ]
+        .iter()
+        .map(|path| dir.join(path))
+        .collect::<BTreeSet<_>>();
+        assert_eq!(expected, found);
+
+        config.settings.keep_index = true;
+        assert!(!find_leftovers(&config, &dir.join("work")).contains(&dir.join("work/index.yaml")));
+
+        run(&config, &dir.join("work"), true).unwrap();
+        assert!(dir.join("staged/Photos/.b.jpg.sortnbackup-part").exists());
+        run(&config, &dir.join("work"), false).unwrap();
+        assert!(!dir.join("staged/Photos/.b.jpg.sortnbackup-part").exists());
+        assert!(!dir.join("staged/.sortnbackup-benchmark-1").exists());
+        assert!(dir.join("staged/Photos/a.jpg").exists());
+        assert!(dir.join("staged/Photos/notes.part").exists());
+        assert!(!dir.join("plain/.download.sortnbackup-part").exists());
+    }
+}
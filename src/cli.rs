@@ -1,8 +1,40 @@
+use std::path::PathBuf;
+
 use clap::{App, Arg, ArgMatches};
 
 pub struct Options {
     pub yes: bool,
     pub continue_: bool,
+    pub tree: bool,
+    pub force: bool,
+    pub dry_run: bool,
+    pub since: Option<String>,
+    pub config: String,
+    pub progress_json: Option<PathBuf>,
+    pub target_prefix: Option<PathBuf>,
+    pub export_index: Option<PathBuf>,
+    pub export_format: ExportFormat,
+    pub interactive: bool,
+    pub benchmark: bool,
+    pub verbose: bool,
+    pub test_file: Option<PathBuf>,
+    pub source: Option<String>,
+    pub max_errors: Option<usize>,
+    pub no_progress: bool,
+    pub save_plan: Option<PathBuf>,
+    pub run_plan: Option<PathBuf>,
+    pub retry_failed: bool,
+    pub clean: bool,
+    pub verify: bool,
+    pub verify_content: bool,
+    pub profile: bool,
+    pub compare_targets: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
 }
 
 pub fn cli_options() -> Options {
@@ -11,6 +43,36 @@ pub fn cli_options() -> Options {
     Options {
         yes: matches.is_present("yes"),
         continue_: matches.is_present("continue"),
+        tree: matches.is_present("tree"),
+        force: matches.is_present("force"),
+        dry_run: matches.is_present("dry-run"),
+        since: matches.value_of("since").map(ToOwned::to_owned),
+        config: matches.value_of("config").unwrap().to_owned(),
+        progress_json: matches.value_of_os("progress-json").map(PathBuf::from),
+        target_prefix: matches.value_of_os("target-prefix").map(PathBuf::from),
+        export_index: matches.value_of_os("export-index").map(PathBuf::from),
+        export_format: match matches.value_of("format") {
+            Some("json") => ExportFormat::Json,
+            _ => ExportFormat::Csv,
+        },
+        interactive: matches.is_present("interactive"),
+        benchmark: matches.is_present("benchmark"),
+        verbose: matches.is_present("verbose"),
+        test_file: matches.value_of_os("test-file").map(PathBuf::from),
+        source: matches.value_of("source").map(ToOwned::to_owned),
+        max_errors: matches.value_of("max-errors").map(|n| n.parse().unwrap()),
+        no_progress: matches.is_present("no-progress"),
+        save_plan: matches.value_of_os("save-plan").map(PathBuf::from),
+        run_plan: matches.value_of_os("run-plan").map(PathBuf::from),
+        retry_failed: matches.is_present("retry-failed"),
+        clean: matches.is_present("clean"),
+        verify: matches.is_present("verify"),
+        verify_content: matches.is_present("verify-content"),
+        profile: matches.is_present("profile"),
+        compare_targets: matches.values_of("compare-targets").map(|mut names| {
+            let a = names.next().unwrap().to_owned();
+            (a, names.next().unwrap().to_owned())
+        }),
     }
 }
 
@@ -19,5 +81,29 @@ fn matches() -> ArgMatches<'static> {
         .about("Copy files from multiple sources to multiple targets using highly customizable filters and rules")
         .arg(Arg::with_name("yes").help("Answer all questions with yes (non-interactive mode)").long("yes"))
         .arg(Arg::with_name("continue").help("Continue a previously started backup").short("c").long("continue"))
+        .arg(Arg::with_name("tree").help("Print the destination tree of all files to copy and exit").long("tree"))
+        .arg(Arg::with_name("dry-run").help("Build the index and report destination collisions and files that would be overwritten, without copying").long("dry-run"))
+        .arg(Arg::with_name("force").help("Copy even if some targets are not writable").long("force"))
+        .arg(Arg::with_name("since").help("Only back up files modified since DATE (e.g. 2024-01-31), DURATION ago (e.g. 7d) or the last successful run (last)").long("since").takes_value(true).value_name("DATE|DURATION|last"))
+        .arg(Arg::with_name("config").help("Config file to use, '-' reads it from stdin").long("config").takes_value(true).value_name("FILE").default_value("config.yaml"))
+        .arg(Arg::with_name("progress-json").help("Write the progress of copying as newline-delimited JSON events to PATH (e.g. /dev/fd/3)").long("progress-json").takes_value(true).value_name("PATH"))
+        .arg(Arg::with_name("target-prefix").help("Put all targets below PATH, e.g. to try a config on a scratch disk").long("target-prefix").takes_value(true).value_name("PATH"))
+        .arg(Arg::with_name("export-index").help("Write all copy instructions (source, from, to, size, target) to PATH").long("export-index").takes_value(true).value_name("PATH"))
+        .arg(Arg::with_name("format").help("Format of --export-index").long("format").takes_value(true).possible_values(&["csv", "json"]).default_value("csv"))
+        .arg(Arg::with_name("interactive").help("Ask whether to overwrite, skip or rename each file that already exists at its destination").short("i").long("interactive"))
+        .arg(Arg::with_name("benchmark").help("Copy generated files to every target, print the throughput and exit").long("benchmark"))
+        .arg(Arg::with_name("verbose").help("Print the file group and rule of every file while indexing").short("v").long("verbose"))
+        .arg(Arg::with_name("test-file").help("Show which file groups match PATH and where it would be copied to, and exit").long("test-file").takes_value(true).value_name("PATH"))
+        .arg(Arg::with_name("source").help("Source to use for --test-file (default: the one containing the file)").long("source").takes_value(true).value_name("NAME").requires("test-file"))
+        .arg(Arg::with_name("max-errors").help("Stop copying after N failed copies (overrides the max_errors setting)").long("max-errors").takes_value(true).value_name("N").validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("no-progress").help("Print the progress of copying as plain lines instead of progress bars (the default if stderr is not a terminal)").long("no-progress"))
+        .arg(Arg::with_name("save-plan").help("Write all copy instructions as a plan to PATH for --run-plan, and exit").long("save-plan").takes_value(true).value_name("PATH"))
+        .arg(Arg::with_name("run-plan").help("Copy exactly the files of the plan at PATH instead of evaluating the file groups").long("run-plan").takes_value(true).value_name("PATH").conflicts_with_all(&["continue", "since", "save-plan", "test-file"]))
+        .arg(Arg::with_name("retry-failed").help("Copy only the files that failed in the last run (per manifest.yaml) again, without indexing").long("retry-failed").conflicts_with_all(&["continue", "since", "save-plan", "run-plan", "test-file"]))
+        .arg(Arg::with_name("clean").help("Remove the leftovers of interrupted runs (partial copies, index and progress files) and exit, with --dry-run only list them").long("clean").conflicts_with_all(&["continue", "run-plan", "retry-failed", "save-plan", "test-file", "benchmark"]))
+        .arg(Arg::with_name("verify").help("Check that every file the config would copy is already on its target with the right size, report missing, mismatched and unexpected files, and exit").long("verify").conflicts_with_all(&["continue", "dry-run", "tree", "save-plan", "retry-failed", "test-file", "benchmark", "clean"]))
+        .arg(Arg::with_name("verify-content").help("With --verify, also compare the md5 hashes of the files with their originals").long("verify-content").requires("verify"))
+        .arg(Arg::with_name("profile").help("Print the time spent in the filter of each file group after indexing").long("profile").conflicts_with_all(&["continue", "run-plan", "retry-failed", "test-file", "benchmark", "clean"]))
+        .arg(Arg::with_name("compare-targets").help("List the files only on one of the two targets or of different sizes on them (e.g. mirrors), and exit").long("compare-targets").takes_value(true).number_of_values(2).value_names(&["A", "B"]).conflicts_with_all(&["continue", "run-plan", "retry-failed", "save-plan", "test-file", "benchmark", "clean", "verify"]))
         .get_matches()
 }
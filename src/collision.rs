@@ -0,0 +1,117 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+/// A destination that more than one file would be copied to; all but one copy would be lost.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Collision {
+    pub to: PathBuf,
+    /// Source and original path of the competing files.
+    pub from: Vec<(String, PathBuf)>,
+}
+
+/// Finds destinations shared by several copy instructions, given as source, original path and
+/// destination.
+pub fn find<'a>(
+    instructions: impl IntoIterator<Item = (&'a str, &'a Path, &'a Path)>,
+) -> Vec<Collision> {
+    let mut by_destination = BTreeMap::<&Path, Vec<(String, PathBuf)>>::new();
+    for (source, from, to) in instructions {
+        by_destination
+            .entry(to)
+            .or_default()
+            .push((source.to_owned(), from.to_owned()));
+    }
+
+    by_destination
+        .into_iter()
+        .filter(|(_, from)| from.len() > 1)
+        .map(|(to, mut from)| {
+            from.sort();
+
+            Collision {
+                to: to.to_owned(),
+                from,
+            }
+        })
+        .collect()
+}
+
+/// Groups of destinations that differ only in case, so they are the same file on case-insensitive
+/// file systems (the default on macOS and Windows). Each group is sorted; destinations that are
+/// exactly the same are left to [`find`].
+pub fn find_case_insensitive<'a>(
+    destinations: impl IntoIterator<Item = &'a Path>,
+) -> Vec<Vec<PathBuf>> {
+    let mut by_key = BTreeMap::<String, BTreeSet<&Path>>::new();
+    for to in destinations {
+        by_key.entry(case_key(to)).or_default().insert(to);
+    }
+
+    by_key
+        .into_values()
+        .filter(|variants| variants.len() > 1)
+        .map(|variants| variants.into_iter().map(Path::to_owned).collect())
+        .collect()
+}
+
+/// What paths are compared by on case-insensitive file systems.
+pub fn case_key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let instructions = [
+            ("phone", "/phone/DCIM/img.jpg", "/backup/img.jpg"),
+            ("camera", "/camera/img.jpg", "/backup/img.jpg"),
+            ("camera", "/camera/other.jpg", "/backup/other.jpg"),
+        ];
+
+        let collisions = find(
+            instructions
+                .iter()
+                .map(|(source, from, to)| (*source, Path::new(from), Path::new(to))),
+        );
+
+        assert_eq!(
+            vec![Collision {
+                to: "/backup/img.jpg".into(),
+                from: vec![
+                    ("camera".to_owned(), "/camera/img.jpg".into()),
+                    ("phone".to_owned(), "/phone/DCIM/img.jpg".into()),
+                ],
+            }],
+            collisions
+        );
+    }
+
+    #[test]
+    fn test_find_case_insensitive() {
+        let destinations = [
+            "/backup/Photo.jpg",
+            "/backup/photo.jpg",
+            "/backup/photo.jpg",
+            "/backup/PHOTO.JPG",
+            "/backup/Docs/a.txt",
+            "/backup/docs/b.txt",
+            "/backup/other.jpg",
+        ];
+
+        let collisions = find_case_insensitive(destinations.iter().map(Path::new));
+
+        assert_eq!(
+            vec![vec![
+                PathBuf::from("/backup/PHOTO.JPG"),
+                PathBuf::from("/backup/Photo.jpg"),
+                PathBuf::from("/backup/photo.jpg"),
+            ]],
+            collisions
+        );
+    }
+}
@@ -1,18 +1,26 @@
 use std::{
     collections::HashMap,
-    path::{Path, PathBuf},
+    convert::TryFrom,
+    ffi::{OsStr, OsString},
+    fmt,
+    io::Read,
+    path::{Component, Path, PathBuf},
+    time::{Instant, SystemTime},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fakemap::FakeMap;
 use humansize::file_size_opts::{FileSizeOpts, BINARY, DECIMAL};
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_yaml::with::singleton_map_recursive;
+use unicode_normalization::UnicodeNormalization;
 
 use crate::{
-    date_time::DateTimeFormatString,
+    date_time::{AgeBuckets, DateTimeFormatString},
     file_path::FilePath,
-    util::{fix_cross_path, is_root_path_of},
+    profile::Profile,
+    util::{fix_cross_path, is_root_path_of, relocate},
 };
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -20,33 +28,449 @@ use crate::{
 pub struct Config {
     pub file_groups: FakeMap<String, FileGroup>,
     pub sources: HashMap<String, Source>,
-    pub targets: HashMap<String, PathBuf>,
+    pub targets: HashMap<String, Target>,
 
     pub settings: Settings,
 }
 
 impl Config {
-    pub fn file_group(&self, src_name: &str, fp: &mut FilePath) -> Option<(&str, &FileGroup)> {
+    /// A config with only `targets` and default settings, for tests.
+    #[cfg(test)]
+    pub fn with_targets(targets: HashMap<String, Target>) -> Self {
+        Config {
+            file_groups: FakeMap::new(),
+            sources: HashMap::new(),
+            targets,
+            settings: Default::default(),
+        }
+    }
+
+    /// Parses a YAML config; enum variants with data are written as single-key maps
+    /// (e.g. `copy_to: ...`). Configs written with YAML tags instead (`!copy_to ...`), the only
+    /// form accepted before, are still read.
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let mut yaml = String::new();
+        reader.read_to_string(&mut yaml)?;
+        let mut config: Config =
+            match singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(&yaml)) {
+                Ok(config) => config,
+                // report the error for the documented form
+                Err(e) => serde_yaml::from_str(&yaml).map_err(|_| e)?,
+            };
+        config.resolve_target_bases()?;
+        config.expand_extension_routes();
+        config.resolve_categories()?;
+
+        Ok(config)
+    }
+
+    /// Turns the paths of targets given with `base` (relative to it until now) into full paths,
+    /// also for chains of bases. SFTP bases make the targets based on them SFTP targets, too.
+    fn resolve_target_bases(&mut self) -> Result<()> {
+        let mut names = self.targets.keys().cloned().collect::<Vec<_>>();
+        names.sort_unstable();
+
+        for name in names {
+            let mut chain = vec![name.as_str()];
+            let mut subpaths = Vec::new();
+            let mut current = &self.targets[&name];
+            while let Some(base) = &current.base {
+                subpaths.push(&current.path);
+                if chain.contains(&base.as_str()) {
+                    chain.push(base);
+                    bail!("targets form a cycle: {}", chain.join(" -> "));
+                }
+                chain.push(base);
+                current = self.targets.get(base).ok_or_else(|| {
+                    anyhow!("target '{}' is based on unknown target '{}'", name, base)
+                })?;
+            }
+            let mut path = current.path.clone();
+            for subpath in subpaths.into_iter().rev() {
+                // pushing an empty path would add a trailing separator
+                if !subpath.as_os_str().is_empty() {
+                    path.push(subpath);
+                }
+            }
+            let remote = current.remote.clone();
+
+            let target = self.targets.get_mut(&name).unwrap();
+            if target.base.is_some() {
+                if remote.is_some() && target.deduplicate {
+                    bail!(
+                        "target '{}': deduplicate is not supported for SFTP targets",
+                        name
+                    );
+                }
+                target.path = path;
+                target.remote = remote;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends a file group for each of `extension_routes`, after the explicit ones so those take
+    /// precedence.
+    fn expand_extension_routes(&mut self) {
+        let mut routes = self.settings.extension_routes.iter().collect::<Vec<_>>();
+        routes.sort_unstable();
+
+        for (extension, target) in routes {
+            let extension = extension.trim_start_matches('.');
+            self.file_groups.insert(
+                format!("extension_routes.{}", extension),
+                FileGroup {
+                    sources: SourceFilter::All,
+                    filter: FileFilter::All(vec![
+                        FileFilter::IsFile,
+                        FileFilter::HasExtension(vec![extension.to_owned()]),
+                    ]),
+                    rule: Rule::CopyTo {
+                        target: target.clone(),
+                        path: vec![PathElement::OriginalPath],
+                        skip_duplicates: false,
+                        min_size: None,
+                        max_size: None,
+                    },
+                },
+            );
+        }
+    }
+
+    /// Looks up the extensions of the categories used by `is_category` filters, in
+    /// `settings.categories` first and then in the built-in ones.
+    fn resolve_categories(&mut self) -> Result<()> {
+        fn resolve(
+            filter: &mut FileFilter,
+            categories: &HashMap<String, Vec<String>>,
+        ) -> Result<()> {
+            match filter {
+                FileFilter::All(filters) | FileFilter::Any(filters) => filters
+                    .iter_mut()
+                    .try_for_each(|filter| resolve(filter, categories)),
+                FileFilter::Not(filter) => resolve(filter, categories),
+                FileFilter::IsCategory(category) => {
+                    category.extensions = match categories.get(&category.name) {
+                        Some(extensions) => extensions.clone(),
+                        None => CATEGORIES
+                            .iter()
+                            .find(|(name, _)| *name == category.name)
+                            .map(|(_, extensions)| {
+                                extensions.iter().map(|&ext| ext.to_owned()).collect()
+                            })
+                            .ok_or_else(|| anyhow!("Unknown category: '{}'", category.name))?,
+                    };
+
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+
+        let categories = &self.settings.categories;
+        for (name, group) in self.file_groups.iter_mut() {
+            resolve(&mut group.filter, categories)
+                .with_context(|| format!("invalid filter of file group '{}'", name))?;
+        }
+
+        Ok(())
+    }
+
+    /// The first file group for `src_name` matching the file. With `profile`, the time spent in
+    /// each group's filter is recorded there.
+    pub fn file_group(
+        &self,
+        src_name: &str,
+        fp: &mut FilePath,
+        profile: Option<&Profile>,
+    ) -> Option<(&str, &FileGroup)> {
         self.file_groups
             .iter()
-            .map(|(k, v)| (k as &str, v))
-            .filter(|(_, v)| v.sources.includes(src_name))
-            .find(|(_k, v)| v.filter.matches(fp))
+            .enumerate()
+            .filter(|(_, (_, v))| v.sources.includes(src_name))
+            .find(|(i, (_, v))| match profile {
+                Some(profile) => {
+                    let started = Instant::now();
+                    let matched = v.filter.matches(fp);
+                    profile.record(*i, started.elapsed(), matched);
+
+                    matched
+                }
+                None => v.filter.matches(fp),
+            })
+            .map(|(_, (k, v))| (k as &str, v))
     }
 
-    pub fn target(&self, target: &str) -> Result<&PathBuf> {
+    pub fn target(&self, target: &str) -> Result<&Target> {
         self.targets
             .get(target)
             .ok_or_else(|| anyhow!("Unknown target: '{}'", target))
     }
 
+    /// Where `rule` copies the file to, or for `log_file` the log file it's listed in; `None` for
+    /// rules that don't copy (`ignore`, `traverse`). Counters are left as placeholders, they're
+    /// only assigned once all files are known.
+    pub fn destination(
+        &self,
+        rule: &Rule,
+        source: &str,
+        fp: &mut FilePath,
+    ) -> Option<Result<PathBuf>> {
+        Some(match rule {
+            Rule::Ignore | Rule::Traverse => return None,
+            Rule::CopyExact { target, .. } => self
+                .target_path_of(target, &fp.path.clone())
+                .and_then(|to| self.namespaced(source, target, to)),
+            Rule::CopyTo { target, path, .. } => self
+                .target_path(target, path, fp)
+                .and_then(|to| self.namespaced(source, target, to)),
+            Rule::CopyToMatchedTarget { target, path, .. } => {
+                self.matched_target(target, fp).and_then(|target| {
+                    let to = self.target_path(&target, path, fp)?;
+                    self.namespaced(source, &target, to)
+                })
+            }
+            Rule::LogFile {
+                target, log_file, ..
+            } => self.log_file_path(target, log_file, fp),
+        })
+    }
+
+    /// The target whose name `elements` evaluate to for the file (`copy_to_matched_target`).
+    pub fn matched_target(&self, elements: &[PathElement], fp: &mut FilePath) -> Result<String> {
+        let context =
+            |fp: &FilePath| format!("cannot choose target for {}", fp.full_path.display());
+        let name = PathElement::join_all(elements, fp, PathBuf::new(), self.settings.strict)
+            .with_context(|| context(fp))?;
+        let name = name.to_string_lossy();
+        self.target(&name).with_context(|| context(fp))?;
+
+        Ok(name.into_owned())
+    }
+
+    /// Pairs of enabled sources `(outer, inner)` where `inner` lies within (or is) `outer`, so its
+    /// files would be indexed twice. Sources whose path is ignored by the outer one don't count.
+    pub fn overlapping_sources(&self) -> Vec<(&str, &str)> {
+        let paths = self
+            .sources
+            .iter()
+            .filter(|(_, source)| !source.disabled)
+            .map(|(name, source)| {
+                let path = source
+                    .path
+                    .canonicalize()
+                    .unwrap_or_else(|_| source.path.clone());
+                (name.as_str(), source, path)
+            })
+            .collect::<Vec<_>>();
+
+        let mut overlapping = paths
+            .iter()
+            .flat_map(|(outer, source, outer_path)| {
+                paths
+                    .iter()
+                    .filter(move |(inner, _, inner_path)| {
+                        if inner == outer {
+                            return false;
+                        }
+                        // identical paths are reported once
+                        if inner_path == outer_path {
+                            return outer < inner;
+                        }
+
+                        is_root_path_of(inner_path, outer_path)
+                            && !source.ignore_paths.iter().any(|ignored| {
+                                inner_path
+                                    .strip_prefix(outer_path)
+                                    .is_ok_and(|sub_path| sub_path.starts_with(ignored))
+                            })
+                    })
+                    .map(move |(inner, _, _)| (*outer, *inner))
+            })
+            .collect::<Vec<_>>();
+        overlapping.sort_unstable();
+
+        overlapping
+    }
+
+    /// Makes relative source and target paths relative to `dir` (the config's directory) instead
+    /// of the working directory.
+    pub fn resolve_relative_paths(&mut self, dir: &Path) {
+        let paths = self
+            .sources
+            .values_mut()
+            .map(|source| &mut source.path)
+            .chain(self.targets.values_mut().map(|target| &mut target.path))
+            .chain(self.settings.cache_dir.as_mut());
+        for path in paths {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+
+    /// Disables the sources whose names match one of the comma-separated `patterns` (which may
+    /// contain `*` and `?` wildcards), in addition to those disabled in the config. Returns the
+    /// newly disabled sources and the patterns that didn't match any source.
+    pub fn disable_sources(&mut self, patterns: &str) -> (Vec<String>, Vec<String>) {
+        let patterns = patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .collect::<Vec<_>>();
+
+        let mut disabled = Vec::new();
+        for (name, source) in &mut self.sources {
+            if patterns.iter().any(|pattern| glob_matches(pattern, name)) && !source.disabled {
+                source.disabled = true;
+                disabled.push(name.clone());
+            }
+        }
+        disabled.sort_unstable();
+        let unmatched = patterns
+            .into_iter()
+            .filter(|pattern| !self.sources.keys().any(|name| glob_matches(pattern, name)))
+            .map(str::to_owned)
+            .collect();
+
+        (disabled, unmatched)
+    }
+
+    /// Moves all targets below `prefix` (`--target-prefix`).
+    pub fn relocate_targets(&mut self, prefix: &Path) {
+        for target in self.targets.values_mut() {
+            target.path = relocate(prefix, &target.path);
+        }
+    }
+
     pub fn target_path(
         &self,
         target: &str,
         paths: &[PathElement],
         fp: &mut FilePath,
     ) -> Result<PathBuf> {
-        PathElement::join_all(paths, fp, self.target(target)?.clone())
+        let path = PathElement::join_all(paths, fp, PathBuf::new(), self.settings.strict)?;
+
+        self.target_path_of(target, &path)
+    }
+
+    /// Like `target_path`, but for log files, which aren't tagged with `filename_prefix` and
+    /// `filename_suffix`.
+    pub fn log_file_path(
+        &self,
+        target: &str,
+        paths: &[PathElement],
+        fp: &mut FilePath,
+    ) -> Result<PathBuf> {
+        let path = PathElement::join_all(paths, fp, PathBuf::new(), self.settings.strict)?;
+
+        self.below_target(target, &path, false)
+    }
+
+    /// With `namespace_by_source`, `to` (a path below `target`) moved into a folder named after
+    /// `source` directly in the target, so files of different sources never collide.
+    pub fn namespaced(&self, source: &str, target: &str, to: PathBuf) -> Result<PathBuf> {
+        if !self.settings.namespace_by_source {
+            return Ok(to);
+        }
+
+        let root = &self.target(target)?.path;
+        Ok(match to.strip_prefix(root) {
+            Ok(path) => root.join(source).join(path),
+            Err(_) => to,
+        })
+    }
+
+    /// `path` below the target's path, normalized according to `normalize_filenames`,
+    /// `filename_prefix` / `filename_suffix`, `lowercase_extension` and `max_filename_length`.
+    pub fn target_path_of(&self, target: &str, path: &Path) -> Result<PathBuf> {
+        self.below_target(target, path, true)
+    }
+
+    fn below_target(&self, target: &str, path: &Path, tag: bool) -> Result<PathBuf> {
+        let mut path = self.settings.normalize_filenames.apply(path);
+        if tag {
+            path = tag_file_name(
+                &path,
+                self.settings.filename_prefix.as_deref().unwrap_or_default(),
+                self.settings.filename_suffix.as_deref().unwrap_or_default(),
+            );
+        }
+        if self.settings.lowercase_extension {
+            path = lowercase_extension(&path);
+        }
+        if let Some(max) = self.settings.max_filename_length {
+            if let Some(shortened) = shorten_file_name(&path, max) {
+                eprintln!(
+                    "WARNING: Shortened file name {} to {} (max_filename_length is {})",
+                    path.display(),
+                    shortened.file_name().unwrap().to_string_lossy(),
+                    max
+                );
+                path = shortened;
+            }
+        }
+
+        Ok(self.target(target)?.path.join(path))
+    }
+}
+
+/// `path` with `prefix` and `suffix` added to its file name, the suffix before the extension, e.g.
+/// `Photos/import_beach_2024.jpg` for `Photos/beach.jpg`.
+fn tag_file_name(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let stem = match path.file_stem() {
+        Some(stem) if !prefix.is_empty() || !suffix.is_empty() => stem,
+        _ => return path.to_owned(),
+    };
+
+    let mut name = OsString::from(prefix);
+    name.push(stem);
+    name.push(suffix);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+
+    path.with_file_name(name)
+}
+
+/// The shortest `max_filename_length` allowed, leaving room for the hash of shortened names.
+const MIN_FILENAME_LENGTH: usize = 16;
+
+/// `path` with its file name shortened to at most `max` bytes, if it's longer: the end of the name
+/// before the extension is replaced by `~` and a short hash of the whole name, so different long
+/// names stay different, e.g. `2023-05-01 Birthday party at the lake~1a2b3c4d.jpg`. Extensions
+/// too long to keep are cut off as well. Names that aren't valid UTF-8 are kept.
+fn shorten_file_name(path: &Path, max: usize) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    if name.len() <= max {
+        return None;
+    }
+
+    let hash = format!("{:x}", md5::compute(name));
+    let suffix = format!("~{}", &hash[..SHORT_HASH_LEN]);
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| format!(".{}", ext))
+        .filter(|ext| suffix.len() + ext.len() <= max)
+        .unwrap_or_default();
+    let stem = &name[..name.len() - extension.len()];
+    let mut keep = max - suffix.len() - extension.len();
+    while !stem.is_char_boundary(keep) {
+        keep -= 1;
+    }
+
+    Some(path.with_file_name(format!("{}{}{}", &stem[..keep], suffix, extension)))
+}
+
+/// `path` with the extension of its file name in lowercase, e.g. `DCIM/IMG_0001.jpg` for
+/// `DCIM/IMG_0001.JPG`.
+fn lowercase_extension(path: &Path) -> PathBuf {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => path.with_extension(ext.to_lowercase()),
+        None => path.to_owned(),
     }
 }
 
@@ -54,17 +478,359 @@ impl Config {
 #[serde(deny_unknown_fields)]
 pub struct Settings {
     pub file_size_style: FileSizeStyle,
+    /// Copy files in-kernel via `copy_file_range` on Linux.
+    #[serde(default)]
+    pub use_copy_file_range: bool,
+    /// Whether to clone files (copy-on-write) instead of copying their content.
+    #[serde(default)]
+    pub reflink: Reflink,
+    /// Flush each copy to the disk before counting it as copied.
+    #[serde(default)]
+    pub fsync: bool,
+    /// With `fsync`, also flush the directory of each copy, so its name survives a power loss.
+    #[serde(default)]
+    pub fsync_dirs: bool,
+    /// Check that files can be opened while indexing and report those that can't instead of
+    /// copying them.
+    #[serde(default)]
+    pub verify_readable: bool,
+    /// Check that all targets are writable before starting to copy.
+    #[serde(default = "default_true")]
+    pub preflight_targets: bool,
+    /// Fail instead of warning about problems with the configuration.
+    #[serde(default)]
+    pub strict: bool,
+    /// When resuming an interrupted copy, check that the part already written matches the source.
+    #[serde(default = "default_true")]
+    pub verify_resume: bool,
+    /// Fail if some files or directories of the sources cannot be read while indexing.
+    #[serde(default)]
+    pub fail_on_inaccessible: bool,
+    /// Delete the copies of files that have been removed from their source since the last run,
+    /// using a snapshot of what has been backed up (`state.yaml`).
+    #[serde(default)]
+    pub sync_deletions: bool,
+    /// Unicode normalization of destination paths (below the target's path).
+    #[serde(default)]
+    pub normalize_filenames: NormalizeFilenames,
+    /// Lowercase the extension of every destination file name.
+    #[serde(default)]
+    pub lowercase_extension: bool,
+    /// Added to the start of every destination file name.
+    #[serde(default)]
+    pub filename_prefix: Option<String>,
+    /// Added to the end of every destination file name, before the extension.
+    #[serde(default)]
+    pub filename_suffix: Option<String>,
+    /// Longest destination file name in bytes; longer ones are shortened.
+    #[serde(default, deserialize_with = "deserialize_max_filename_length")]
+    pub max_filename_length: Option<usize>,
+    /// Keep only one of the files of several sources with the same destination.
+    #[serde(default)]
+    pub on_duplicate_source: Option<DuplicateSource>,
+    /// What to do about destinations differing only in case.
+    #[serde(default)]
+    pub case_collisions: CaseCollisions,
+    /// Put the files of each source into a folder named after it on every target.
+    #[serde(default)]
+    pub namespace_by_source: bool,
+    /// After copying, walk the targets and compare the files found with the files expected.
+    #[serde(default)]
+    pub integrity_report: bool,
+    /// Permissions of copied files (Unix only), e.g. "0640"; by default they are copied.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub dest_mode: Option<u32>,
+    /// Don't copy files if the target already has one with the same size and modification time.
+    /// Copies get the modification time of their original for this.
+    #[serde(default = "default_true")]
+    pub skip_identical: bool,
+    /// Max. number of sources indexed at the same time (default: number of CPUs).
+    #[serde(default)]
+    pub index_jobs: Option<usize>,
+    /// Stop copying after the first failed copy and exit with an error; `--continue` resumes.
+    #[serde(default)]
+    pub fail_fast: bool,
+    /// Stop copying after this many failed copies and exit with an error; `--continue` resumes.
+    #[serde(default)]
+    pub max_errors: Option<usize>,
+    /// File (and folder) names ignored in all sources, e.g. "Thumbs.db"; case-insensitive on
+    /// Windows and macOS.
+    #[serde(default)]
+    pub ignore_file_names: Vec<String>,
+    /// Ignore macOS AppleDouble files (`._<name>`, metadata of `<name>`) in all sources.
+    #[serde(default)]
+    pub skip_apple_double: bool,
+    /// Share of the files to copy (0 to 1); which ones only depends on their paths and
+    /// `sample_seed`, so every run picks the same.
+    #[serde(default, deserialize_with = "deserialize_sample_rate")]
+    pub sample_rate: Option<f64>,
+    /// Picks a different sample for `sample_rate`.
+    #[serde(default)]
+    pub sample_seed: u64,
+    /// Keep `index.yaml` after copying instead of deleting it.
+    #[serde(default)]
+    pub keep_index: bool,
+    /// Keep `progress.yaml` (with the final progress) after copying instead of deleting it.
+    #[serde(default)]
+    pub keep_progress: bool,
+    /// Shortcut for file groups copying all files with an extension to a target, keeping their
+    /// path (extension -> target name).
+    #[serde(default)]
+    pub extension_routes: HashMap<String, String>,
+    /// Extensions of categories for `is_category`, replacing the built-in ones of the same name.
+    #[serde(default)]
+    pub categories: HashMap<String, Vec<String>>,
+    /// Folders nested deeper than this below a source are not walked (0: only the files directly in
+    /// the source).
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Where the image metadata read while indexing is kept for the next runs.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Accepts a mode as octal string ("0640") or as number (`0o640`); an unquoted `0640` would be
+/// decimal in YAML.
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        Octal(String),
+    }
+
+    let mode = match Option::<Mode>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Mode::Number(mode)) => mode,
+        Some(Mode::Octal(s)) => {
+            let digits = s.trim_start_matches("0o");
+            u32::from_str_radix(digits, 8)
+                .map_err(|_| D::Error::custom(format!("invalid octal mode: '{}'", s)))?
+        }
+    };
+    if mode > 0o7777 {
+        return Err(D::Error::custom(format!("invalid mode: {:o}", mode)));
+    }
+
+    Ok(Some(mode))
+}
+
+fn deserialize_max_filename_length<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<usize>, D::Error> {
+    let max = Option::<usize>::deserialize(deserializer)?;
+    if let Some(max) = max.filter(|&max| max < MIN_FILENAME_LENGTH) {
+        return Err(D::Error::custom(format!(
+            "max_filename_length must be at least {}, not {}",
+            MIN_FILENAME_LENGTH, max
+        )));
+    }
+
+    Ok(max)
+}
+
+fn deserialize_sample_rate<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    let rate = Option::<f64>::deserialize(deserializer)?;
+    if let Some(rate) = rate.filter(|rate| !(0.0..=1.0).contains(rate)) {
+        return Err(D::Error::custom(format!(
+            "sample_rate must be between 0 and 1, not {}",
+            rate
+        )));
+    }
+
+    Ok(rate)
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            file_size_style: Default::default(),
+            use_copy_file_range: false,
+            reflink: Default::default(),
+            fsync: false,
+            fsync_dirs: false,
+            verify_readable: false,
+            preflight_targets: true,
+            strict: false,
+            verify_resume: true,
+            fail_on_inaccessible: false,
+            sync_deletions: false,
+            normalize_filenames: Default::default(),
+            lowercase_extension: false,
+            filename_prefix: None,
+            filename_suffix: None,
+            max_filename_length: None,
+            on_duplicate_source: None,
+            case_collisions: Default::default(),
+            namespace_by_source: false,
+            integrity_report: false,
+            dest_mode: None,
+            skip_identical: true,
+            index_jobs: None,
+            fail_fast: false,
+            max_errors: None,
+            ignore_file_names: Vec::new(),
+            skip_apple_double: false,
+            sample_rate: None,
+            sample_seed: 0,
+            keep_index: false,
+            keep_progress: false,
+            extension_routes: HashMap::new(),
+            categories: HashMap::new(),
+            max_depth: None,
+            cache_dir: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Whether a file called `name` is an AppleDouble file ignored because of
+    /// `skip_apple_double`.
+    pub fn is_skipped_apple_double(&self, name: &OsStr) -> bool {
+        self.skip_apple_double
+            && name
+                .to_str()
+                .and_then(|name| name.strip_prefix("._"))
+                .is_some_and(|name| !name.is_empty())
+    }
+
+    /// Whether the file at `path` (relative to its source) is part of the sample copied with
+    /// `sample_rate`. The decision is a hash of the path with `/` as separator and `sample_seed`,
+    /// so it's the same in every run and on every system.
+    pub fn is_sampled(&self, path: &Path) -> bool {
+        let rate = match self.sample_rate {
+            Some(rate) => rate,
+            None => return true,
+        };
+
+        let path = path
+            .iter()
+            .map(|name| name.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut data = self.sample_seed.to_le_bytes().to_vec();
+        data.extend_from_slice(path.as_bytes());
+        let digest = md5::compute(&data);
+        let hash = u64::from_le_bytes(<[u8; 8]>::try_from(&digest.0[..8]).unwrap());
+
+        // uniform in [0, 1), so a rate of 1 takes all files and 0 none
+        ((hash >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+
+    pub fn is_ignored_file_name(&self, name: &OsStr) -> bool {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return false,
+        };
+
+        self.ignore_file_names.iter().any(|ignored| {
+            if cfg!(any(windows, target_os = "macos")) {
+                ignored.to_lowercase() == name.to_lowercase()
+            } else {
+                ignored == name
+            }
+        })
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub enum FileSizeStyle {
+    #[default]
     #[serde(rename = "binary")]
     Binary,
     #[serde(rename = "decimal")]
     Decimal,
 }
 
+/// Cloning a file shares its data with the original until either is changed, so it takes neither
+/// time nor space; only supported within one file system that supports it (e.g. Btrfs, XFS) on
+/// Linux.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum Reflink {
+    /// Clone if possible, copy otherwise.
+    #[serde(rename = "auto")]
+    Auto,
+    /// Fail the copy if the file can't be cloned.
+    #[serde(rename = "always")]
+    Always,
+    #[default]
+    #[serde(rename = "never")]
+    Never,
+}
+
+/// Which file is copied if files of several sources have the same destination.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum DuplicateSource {
+    /// The most recently modified one.
+    #[serde(rename = "newest")]
+    Newest,
+    #[serde(rename = "largest")]
+    Largest,
+    /// The one of the source whose name sorts first.
+    #[serde(rename = "first")]
+    First,
+}
+
+/// What to do about destinations that differ only in case (`Photo.jpg` and `photo.jpg`), which
+/// overwrite each other on case-insensitive file systems.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum CaseCollisions {
+    #[serde(rename = "ignore")]
+    Ignore,
+    #[default]
+    #[serde(rename = "warn")]
+    Warn,
+    /// Fail indexing.
+    #[serde(rename = "error")]
+    Error,
+    /// Copy all but the first to `name (1).ext` etc.
+    #[serde(rename = "rename")]
+    Rename,
+}
+
+/// macOS stores file names decomposed (NFD), most other systems composed (NFC); names that look
+/// the same can then end up as different files.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum NormalizeFilenames {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "nfc")]
+    Nfc,
+    #[serde(rename = "nfd")]
+    Nfd,
+}
+
+impl NormalizeFilenames {
+    /// Normalizes every component of `path` that is valid UTF-8.
+    pub fn apply(self, path: &Path) -> PathBuf {
+        if self == NormalizeFilenames::None {
+            return path.to_owned();
+        }
+
+        path.iter()
+            .map(|component| match component.to_str() {
+                Some(s) if self == NormalizeFilenames::Nfc => {
+                    OsString::from(s.nfc().collect::<String>())
+                }
+                Some(s) => OsString::from(s.nfd().collect::<String>()),
+                None => component.to_owned(),
+            })
+            .collect()
+    }
+}
+
 impl FileSizeStyle {
     pub fn to_file_size_opts(&self) -> &FileSizeOpts {
         match self {
@@ -74,6 +840,184 @@ impl FileSizeStyle {
     }
 }
 
+/// A copy destination; either given as a bare path or with per-target settings.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(try_from = "TargetDef")]
+pub struct Target {
+    /// For remote targets, the path on the server.
+    pub path: PathBuf,
+    /// Set for targets given as `sftp://user@host:port/path`.
+    pub remote: Option<Remote>,
+    /// Compare the md5 hash of source and copy after copying.
+    pub verify: bool,
+    /// Maximum number of files copied to this target at the same time.
+    pub jobs: Option<usize>,
+    /// Copy in chunks of this many bytes instead of using the platform copy.
+    pub buffer_size: Option<usize>,
+    /// Skip files whose content is already somewhere below `path`, no matter under which name.
+    pub deduplicate: bool,
+    /// Copy to a hidden part file and rename it once complete, so partial files are never visible.
+    pub staging: bool,
+    /// The target this one is a folder of; its `path` is given relative to the base's.
+    pub base: Option<String>,
+    /// Files for targets of higher priority are copied before those of lower ones.
+    pub priority: i32,
+}
+
+/// An SFTP server that files are copied to.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Remote {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// Private key to log in with; the SSH agent is used without one.
+    pub identity_file: Option<PathBuf>,
+}
+
+impl Remote {
+    /// Parses `sftp://user@host[:port]/path` into the server and the path on it.
+    fn parse(url: &str) -> Result<Option<(Remote, PathBuf)>, String> {
+        let rest = match url.strip_prefix("sftp://") {
+            Some(rest) => rest,
+            None => return Ok(None),
+        };
+        let invalid = || {
+            format!(
+                "Invalid SFTP target '{}', expected sftp://user@host[:port]/path",
+                url
+            )
+        };
+
+        let (authority, path) = rest.split_at(rest.find('/').ok_or_else(invalid)?);
+        let (user, host) = authority.split_once('@').ok_or_else(invalid)?;
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (host, 22),
+        };
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid());
+        }
+
+        let remote = Remote {
+            user: user.to_owned(),
+            host: host.to_owned(),
+            port,
+            identity_file: None,
+        };
+
+        Ok(Some((remote, path.into())))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TargetDef {
+    Path(PathBuf),
+    Detailed(DetailedTargetDef),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedTargetDef {
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    base: Option<String>,
+    #[serde(default)]
+    subpath: Option<PathBuf>,
+    #[serde(default)]
+    verify: bool,
+    #[serde(default)]
+    jobs: Option<usize>,
+    #[serde(default)]
+    buffer_size: Option<usize>,
+    #[serde(default)]
+    identity_file: Option<PathBuf>,
+    #[serde(default)]
+    deduplicate: bool,
+    #[serde(default)]
+    staging: bool,
+    #[serde(default)]
+    priority: i32,
+}
+
+impl TryFrom<TargetDef> for Target {
+    type Error = String;
+
+    fn try_from(def: TargetDef) -> Result<Self, String> {
+        let def = match def {
+            TargetDef::Path(path) => DetailedTargetDef {
+                path: Some(path),
+                base: None,
+                subpath: None,
+                verify: false,
+                jobs: None,
+                buffer_size: None,
+                identity_file: None,
+                deduplicate: false,
+                staging: false,
+                priority: 0,
+            },
+            TargetDef::Detailed(def) => def,
+        };
+
+        if let Some(base) = def.base {
+            if def.path.is_some() || def.identity_file.is_some() {
+                return Err(format!(
+                    "a target based on '{}' has no path or identity_file of its own (use subpath)",
+                    base
+                ));
+            }
+
+            return Ok(Target {
+                path: def.subpath.unwrap_or_default(),
+                remote: None,
+                verify: def.verify,
+                jobs: def.jobs,
+                buffer_size: def.buffer_size,
+                deduplicate: def.deduplicate,
+                staging: def.staging,
+                base: Some(base),
+                priority: def.priority,
+            });
+        }
+        let path = match (def.path, def.subpath) {
+            (Some(path), None) => path,
+            (None, _) => return Err("a target needs a path or a base".to_owned()),
+            (Some(_), Some(_)) => return Err("subpath is only supported with base".to_owned()),
+        };
+
+        let (path, remote) = match Remote::parse(&path.to_string_lossy())? {
+            Some((remote, path)) => (
+                path,
+                Some(Remote {
+                    identity_file: def.identity_file,
+                    ..remote
+                }),
+            ),
+            None if def.identity_file.is_some() => {
+                return Err("identity_file is only supported for SFTP targets".to_owned())
+            }
+            None => (path, None),
+        };
+        if remote.is_some() && def.deduplicate {
+            return Err("deduplicate is not supported for SFTP targets".to_owned());
+        }
+
+        Ok(Target {
+            path,
+            remote,
+            verify: def.verify,
+            jobs: def.jobs,
+            buffer_size: def.buffer_size,
+            deduplicate: def.deduplicate,
+            staging: def.staging,
+            base: None,
+            priority: def.priority,
+        })
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct FileGroup {
@@ -90,6 +1034,13 @@ pub struct Source {
     pub path: PathBuf,
     #[serde(default)]
     pub disabled: bool,
+    /// Only copy the `keep_newest` most recently modified files of this source.
+    #[serde(default)]
+    pub keep_newest: Option<usize>,
+    /// Index this source one after another with the other sequential sources instead of at the
+    /// same time, e.g. for several sources on the same slow disk.
+    #[serde(default)]
+    pub sequential: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -104,13 +1055,48 @@ pub enum SourceFilter {
 }
 
 impl SourceFilter {
+    /// Source names in `except` and `only` may contain `*` (any characters) and `?` (a single
+    /// character) wildcards, e.g. `phone-*`.
     pub fn includes(&self, s: &str) -> bool {
         match self {
             SourceFilter::All => true,
-            SourceFilter::Except(except) => !except.iter().any(|x| x == s),
-            SourceFilter::Only(only) => only.iter().any(|x| x == s),
+            SourceFilter::Except(except) => !except.iter().any(|x| glob_matches(x, s)),
+            SourceFilter::Only(only) => only.iter().any(|x| glob_matches(x, s)),
+        }
+    }
+}
+
+/// Whether `name` matches `pattern` as a whole; without wildcards, only `pattern` itself does.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // after a `*`, the pattern position following it and the name position it matched up to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the last `*` match one more character
+                Some((after_star, matched)) => {
+                    p = after_star;
+                    n = matched + 1;
+                    star = Some((after_star, matched + 1));
+                }
+                None => return false,
+            },
         }
     }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -128,14 +1114,23 @@ pub enum FileFilter {
     InRootPath(String),
     #[serde(rename = "directly_in_folder")]
     ImmediateParent(String),
+    /// The name of the `depth`-th ancestor (1 = direct parent) equals `name`.
+    #[serde(rename = "ancestor_at")]
+    AncestorAt { depth: usize, name: String },
     #[serde(rename = "has_extension")]
     HasExtension(Vec<String>),
+    /// Has one of the extensions of a category, e.g. `image`.
+    #[serde(rename = "is_category")]
+    IsCategory(Category),
     #[serde(rename = "file_name")]
     FileName(String),
     #[serde(rename = "file_name_matches_regex")]
     FileNameMatchesRegex(#[serde(with = "serde_regex")] Regex),
     #[serde(rename = "path_matches_regex")]
     PathMatchesRegex(#[serde(with = "serde_regex")] Regex),
+    /// Like `PathMatchesRegex`, but matches the absolute path including the source's path.
+    #[serde(rename = "full_path_matches_regex")]
+    FullPathMatchesRegex(#[serde(with = "serde_regex")] Regex),
     #[serde(rename = "has_img_date_time")]
     HasImageDateTime,
     #[serde(rename = "has_img_metadata")]
@@ -144,8 +1139,57 @@ pub enum FileFilter {
     IsFile,
     #[serde(rename = "is_dir")]
     IsDir,
+    /// Unlike `IsFile`, doesn't follow symlinks and excludes special files (FIFOs, sockets, devices).
+    #[serde(rename = "is_regular_file")]
+    IsRegularFile,
+    /// A symlink whose target doesn't exist.
+    #[serde(rename = "is_broken_symlink")]
+    IsBrokenSymlink,
+    /// A file with the same stem and this extension exists next to the file, e.g. an `.xmp` sidecar.
+    #[serde(rename = "has_sidecar")]
+    HasSidecar { extension: String },
+    /// The MIME type detected from the file's content, regardless of its extension; `image/*`
+    /// matches all images.
+    #[serde(rename = "content_type")]
+    ContentType(Vec<String>),
+    /// The encoding of a text file detected from its first bytes, e.g. `utf-8-bom` or `legacy`;
+    /// `utf-8` also matches plain ASCII.
+    #[serde(rename = "text_encoding")]
+    TextEncoding(Vec<String>),
+    /// `min` and `max` bound both dimensions, the others only the width or height.
     #[serde(rename = "img_size")]
-    ImgSize { min: Option<u32>, max: Option<u32> },
+    ImgSize {
+        min: Option<u32>,
+        max: Option<u32>,
+        min_width: Option<u32>,
+        max_width: Option<u32>,
+        min_height: Option<u32>,
+        max_height: Option<u32>,
+    },
+    /// EXIF orientation values (1 to 8), e.g. `[6, 8]` for portrait shots.
+    #[serde(rename = "img_orientation")]
+    ImageOrientation(Vec<u16>),
+    /// Whether the flash fired, per EXIF.
+    #[serde(rename = "flash_fired")]
+    FlashFired(bool),
+    /// Size of a file in bytes, bounds inclusive.
+    #[serde(rename = "file_size")]
+    FileSize { min: Option<u64>, max: Option<u64> },
+    /// Recursive size of a directory in bytes; expensive, so keep it behind cheap filters.
+    #[serde(rename = "dir_size")]
+    DirSize { min: Option<u64>, max: Option<u64> },
+    /// Number of entries directly in a directory (not recursive), e.g. to tell caches apart.
+    #[serde(rename = "file_count")]
+    FileCount {
+        min: Option<usize>,
+        max: Option<usize>,
+    },
+    /// Modified after the reference file (e.g. a marker touched after the last backup).
+    #[serde(rename = "modified_after_file")]
+    ModifiedAfterFile(ReferenceFile),
+    /// Owned by the given user and / or group (Unix only).
+    #[serde(rename = "owned_by")]
+    OwnedBy(Owner),
 }
 
 impl FileFilter {
@@ -155,7 +1199,10 @@ impl FileFilter {
         match self {
             FileFilter::All(v) => v.iter().all(|x| x.matches(file_path)),
             FileFilter::Any(v) => v.iter().any(|x| x.matches(file_path)),
-            FileFilter::HasExtension(exts) => match path.extension().and_then(|s| s.to_str()) {
+            FileFilter::HasExtension(exts)
+            | FileFilter::IsCategory(Category {
+                extensions: exts, ..
+            }) => match path.extension().and_then(|s| s.to_str()) {
                 None => false,
                 Some(s) => exts.iter().any(|ext| ext.eq_ignore_ascii_case(s)),
             },
@@ -169,29 +1216,115 @@ impl FileFilter {
                 None => false,
                 Some(s) => r.is_match(s),
             },
-            FileFilter::ImgSize { min, max } => match file_path.img_metadata() {
+            FileFilter::FullPathMatchesRegex(r) => match file_path.full_path.to_str() {
+                None => false,
+                Some(s) => r.is_match(s),
+            },
+            FileFilter::ContentType(types) => match file_path.content_type() {
+                Some(content_type) => types.iter().any(|t| match t.strip_suffix("/*") {
+                    Some(kind) => content_type
+                        .split('/')
+                        .next()
+                        .is_some_and(|k| k.eq_ignore_ascii_case(kind)),
+                    None => t.eq_ignore_ascii_case(content_type),
+                }),
+                None => false,
+            },
+            FileFilter::TextEncoding(encodings) => match file_path.text_encoding() {
+                Some(encoding) => encodings.iter().any(|e| {
+                    e.eq_ignore_ascii_case(encoding)
+                        || (encoding == "ascii" && e.eq_ignore_ascii_case("utf-8"))
+                }),
+                None => false,
+            },
+            FileFilter::ImgSize {
+                min,
+                max,
+                min_width,
+                max_width,
+                min_height,
+                max_height,
+            } => match file_path.img_metadata() {
                 Some(meta) => {
-                    min.map(|min| meta.dimensions.ensure_min(min))
-                        .unwrap_or(true)
-                        && max
-                            .map(|max| meta.dimensions.ensure_max(max))
-                            .unwrap_or(true)
+                    let dimensions = &meta.dimensions;
+
+                    min.map(|min| dimensions.ensure_min(min)).unwrap_or(true)
+                        && max.map(|max| dimensions.ensure_max(max)).unwrap_or(true)
+                        && dimensions.ensure_width(*min_width, *max_width)
+                        && dimensions.ensure_height(*min_height, *max_height)
                 }
                 None => false,
             },
-            FileFilter::HasImageMetadata => file_path.img_metadata().is_some(),
-            FileFilter::HasImageDateTime => file_path
+            FileFilter::ImageOrientation(orientations) => file_path
                 .img_metadata()
-                .map(|x| x.date_time.is_some())
-                .unwrap_or(false),
-            FileFilter::InRootPath(folder) => {
+                .and_then(|meta| meta.orientation)
+                .is_some_and(|orientation| orientations.contains(&orientation)),
+            FileFilter::FlashFired(fired) => file_path
+                .img_metadata()
+                .and_then(|meta| meta.flash_fired)
+                .is_some_and(|actual| actual == *fired),
+            FileFilter::FileSize { min, max } => match file_path.metadata() {
+                Some(meta) => {
+                    let size = meta.len();
+                    min.is_none_or(|min| size >= min) && max.is_none_or(|max| size <= max)
+                }
+                None => false,
+            },
+            FileFilter::DirSize { min, max } => match file_path.dir_size() {
+                Some(size) => {
+                    min.is_none_or(|min| size >= min) && max.is_none_or(|max| size <= max)
+                }
+                None => false,
+            },
+            FileFilter::FileCount { min, max } => match std::fs::read_dir(&file_path.full_path) {
+                Ok(entries) => {
+                    // no need to count further than one above `max`
+                    let count = entries
+                        .take(max.map_or(usize::MAX, |max| max.saturating_add(1)))
+                        .count();
+                    min.is_none_or(|min| count >= min) && max.is_none_or(|max| count <= max)
+                }
+                Err(_) => false,
+            },
+            FileFilter::ModifiedAfterFile(reference) => file_path
+                .metadata()
+                .and_then(|meta| meta.modified().ok())
+                .is_some_and(|modified| modified > reference.modified),
+            FileFilter::OwnedBy(owner) => owner.matches(file_path),
+            FileFilter::HasImageMetadata => file_path.img_metadata().is_some(),
+            FileFilter::HasImageDateTime => file_path
+                .img_metadata()
+                .map(|x| x.date_time.is_some())
+                .unwrap_or(false),
+            FileFilter::InRootPath(folder) => {
                 is_root_path_of(&file_path.path, &fix_cross_path(folder))
             }
             FileFilter::Not(f) => !f.matches(file_path),
             FileFilter::CatchAll => true,
             FileFilter::IsFile => file_path.full_path.is_file(),
             FileFilter::IsDir => file_path.full_path.is_dir(),
+            FileFilter::IsRegularFile => std::fs::symlink_metadata(&file_path.full_path)
+                .is_ok_and(|meta| meta.file_type().is_file()),
+            FileFilter::IsBrokenSymlink => {
+                file_path.full_path.is_symlink() && !file_path.full_path.exists()
+            }
+            FileFilter::HasSidecar { extension } => {
+                let sidecar = file_path
+                    .full_path
+                    .with_extension(extension.trim_start_matches('.'));
+                sidecar != file_path.full_path && sidecar.is_file()
+            }
             FileFilter::ImmediateParent(p) => file_path.path.parent().unwrap() == Path::new(p),
+            FileFilter::AncestorAt { depth, name } => {
+                match (*depth, file_path.path.ancestors().nth(*depth)) {
+                    (0, _) | (_, None) => false,
+                    (_, Some(ancestor)) => ancestor
+                        .file_name()
+                        .and_then(|s| s.to_str())
+                        .map(|actual| name.eq_ignore_ascii_case(actual))
+                        .unwrap_or(false),
+                }
+            }
             FileFilter::FileName(file_name) => {
                 if let Some(actual) = file_path.path.file_name().and_then(|s| s.to_str()) {
                     file_name.eq_ignore_ascii_case(actual)
@@ -203,6 +1336,186 @@ impl FileFilter {
     }
 }
 
+/// The built-in categories of `is_category` and their extensions.
+pub const CATEGORIES: &[(&str, &[&str])] = &[
+    (
+        "image",
+        &[
+            "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "heif", "avif",
+            "svg", "ico", "raw", "dng", "cr2", "cr3", "nef", "arw", "orf", "rw2", "raf",
+        ],
+    ),
+    (
+        "video",
+        &[
+            "mp4", "m4v", "mov", "avi", "mkv", "wmv", "flv", "webm", "mpg", "mpeg", "3gp", "mts",
+            "m2ts",
+        ],
+    ),
+    (
+        "audio",
+        &[
+            "mp3", "m4a", "aac", "flac", "wav", "ogg", "opus", "wma", "aiff", "mid", "midi",
+        ],
+    ),
+    (
+        "document",
+        &[
+            "pdf", "txt", "md", "rtf", "doc", "docx", "odt", "xls", "xlsx", "ods", "csv", "ppt",
+            "pptx", "odp", "epub",
+        ],
+    ),
+    (
+        "archive",
+        &[
+            "zip", "rar", "7z", "tar", "gz", "tgz", "bz2", "xz", "zst", "iso",
+        ],
+    ),
+];
+
+/// A category of `is_category`, given by name; its extensions are looked up when the config is
+/// loaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub struct Category {
+    pub name: String,
+    extensions: Vec<String>,
+}
+
+impl From<String> for Category {
+    fn from(name: String) -> Self {
+        Category {
+            name,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl From<Category> for String {
+    fn from(category: Category) -> Self {
+        category.name
+    }
+}
+
+/// The owner of files to match; user and group names are resolved to ids when the config is
+/// loaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "OwnerDef", into = "OwnerDef")]
+pub struct Owner {
+    def: OwnerDef,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct OwnerDef {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+}
+
+impl TryFrom<OwnerDef> for Owner {
+    type Error = String;
+
+    fn try_from(def: OwnerDef) -> Result<Self, Self::Error> {
+        if def.uid.is_some() && def.user.is_some() || def.gid.is_some() && def.group.is_some() {
+            return Err("owned_by takes either uid or user and either gid or group".to_owned());
+        }
+        if def.uid.is_none() && def.gid.is_none() && def.user.is_none() && def.group.is_none() {
+            return Err("owned_by needs a uid, gid, user or group".to_owned());
+        }
+
+        #[cfg(unix)]
+        let (uid, gid) = {
+            let uid = match &def.user {
+                Some(user) => Some(
+                    crate::util::user_id(user).ok_or_else(|| format!("Unknown user '{}'", user))?,
+                ),
+                None => def.uid,
+            };
+            let gid = match &def.group {
+                Some(group) => Some(
+                    crate::util::group_id(group)
+                        .ok_or_else(|| format!("Unknown group '{}'", group))?,
+                ),
+                None => def.gid,
+            };
+
+            (uid, gid)
+        };
+        #[cfg(not(unix))]
+        let (uid, gid) = (def.uid, def.gid);
+
+        Ok(Owner { def, uid, gid })
+    }
+}
+
+impl From<Owner> for OwnerDef {
+    fn from(owner: Owner) -> Self {
+        owner.def
+    }
+}
+
+impl Owner {
+    #[cfg(unix)]
+    fn matches(&self, file_path: &mut FilePath) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        file_path.metadata().is_some_and(|meta| {
+            self.uid.is_none_or(|uid| meta.uid() == uid)
+                && self.gid.is_none_or(|gid| meta.gid() == gid)
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn matches(&self, _file_path: &mut FilePath) -> bool {
+        static WARNING: std::sync::Once = std::sync::Once::new();
+        WARNING.call_once(|| {
+            eprintln!("WARNING: owned_by is only supported on Unix, no file matches it");
+        });
+
+        false
+    }
+}
+
+/// A file whose modification time is read once, when the config is loaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "PathBuf", into = "PathBuf")]
+pub struct ReferenceFile {
+    path: PathBuf,
+    modified: SystemTime,
+}
+
+impl TryFrom<PathBuf> for ReferenceFile {
+    type Error = String;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        let modified = std::fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .map_err(|e| {
+                format!(
+                    "Cannot read modification time of reference file {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+
+        Ok(ReferenceFile { path, modified })
+    }
+}
+
+impl From<ReferenceFile> for PathBuf {
+    fn from(reference: ReferenceFile) -> Self {
+        reference.path
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub enum Rule {
@@ -213,6 +1526,12 @@ pub enum Rule {
         target: String,
         #[serde(default)]
         skip_duplicates: bool,
+        /// Smaller files are skipped.
+        #[serde(default)]
+        min_size: Option<u64>,
+        /// Larger files are skipped.
+        #[serde(default)]
+        max_size: Option<u64>,
     },
     #[serde(rename = "copy_to")]
     CopyTo {
@@ -220,6 +1539,18 @@ pub enum Rule {
         path: Vec<PathElement>,
         #[serde(default)]
         skip_duplicates: bool,
+        #[serde(default)]
+        min_size: Option<u64>,
+        #[serde(default)]
+        max_size: Option<u64>,
+    },
+    /// Like `copy_to`, but the name of the target is derived from the file, e.g. from its folder.
+    #[serde(rename = "copy_to_matched_target")]
+    CopyToMatchedTarget {
+        target: Vec<PathElement>,
+        path: Vec<PathElement>,
+        #[serde(default)]
+        skip_duplicates: bool,
     },
     #[serde(rename = "traverse")]
     Traverse,
@@ -228,9 +1559,111 @@ pub enum Rule {
         target: String,
         log_file: Vec<PathElement>,
         full_path: bool,
+        #[serde(default)]
+        format: LogFormat,
+        /// Columns of `csv` and `json` logs.
+        #[serde(default = "LogColumn::defaults")]
+        columns: Vec<LogColumn>,
+        #[serde(default)]
+        mode: LogMode,
     },
 }
 
+impl Rule {
+    /// Whether a file of `size` bytes is within the `min_size` / `max_size` of the rule (both
+    /// inclusive); rules without them accept every size.
+    pub fn accepts_size(&self, size: u64) -> bool {
+        match self {
+            Rule::CopyExact {
+                min_size, max_size, ..
+            }
+            | Rule::CopyTo {
+                min_size, max_size, ..
+            } => min_size.is_none_or(|min| size >= min) && max_size.is_none_or(|max| size <= max),
+            _ => true,
+        }
+    }
+}
+
+/// Short description for `--verbose`, e.g. `copy_to target 'nas'`.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Ignore => write!(f, "ignore"),
+            Rule::CopyExact { target, .. } => write!(f, "copy_exact target '{}'", target),
+            Rule::CopyTo { target, .. } => write!(f, "copy_to target '{}'", target),
+            Rule::CopyToMatchedTarget { .. } => write!(f, "copy_to_matched_target"),
+            Rule::Traverse => write!(f, "traverse"),
+            Rule::LogFile { target, .. } => write!(f, "log_file target '{}'", target),
+        }
+    }
+}
+
+/// What happens to an existing log file when indexing starts.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum LogMode {
+    #[default]
+    #[serde(rename = "append")]
+    Append,
+    /// Truncate the log file the first time it's written to in a run.
+    #[serde(rename = "overwrite")]
+    Overwrite,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum LogFormat {
+    /// One path per line.
+    #[default]
+    #[serde(rename = "line")]
+    Line,
+    #[serde(rename = "csv")]
+    Csv,
+    /// One JSON object per line.
+    #[serde(rename = "json")]
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum LogColumn {
+    #[serde(rename = "path")]
+    Path,
+    #[serde(rename = "size")]
+    Size,
+    #[serde(rename = "modified")]
+    Modified,
+    #[serde(rename = "group")]
+    Group,
+    #[serde(rename = "source")]
+    Source,
+}
+
+impl LogColumn {
+    fn defaults() -> Vec<LogColumn> {
+        vec![
+            LogColumn::Path,
+            LogColumn::Size,
+            LogColumn::Modified,
+            LogColumn::Group,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogColumn::Path => "path",
+            LogColumn::Size => "size",
+            LogColumn::Modified => "modified",
+            LogColumn::Group => "group",
+            LogColumn::Source => "source",
+        }
+    }
+}
+
+/// Number of hex digits of the md5 hash used by [`PathElement::FileHash`].
+const SHORT_HASH_LEN: usize = 8;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub enum PathElement {
@@ -242,6 +1675,10 @@ pub enum PathElement {
     OriginalPath,
     #[serde(rename = "original_path_without_file_name")]
     OriginalPathWithoutFileName,
+    /// The original path below the given folder (relative to the source), e.g. for files matched
+    /// by `in_folder`.
+    #[serde(rename = "path_relative_to")]
+    PathRelativeTo(String),
     #[serde(rename = "direct_parent_folder")]
     DirectParentFolder,
     #[serde(rename = "file_name_with_extension")]
@@ -250,6 +1687,10 @@ pub enum PathElement {
     FileNameWithoutExtension,
     #[serde(rename = "file_extension")]
     FileExtension,
+    #[serde(rename = "file_hash")]
+    FileHash,
+    #[serde(rename = "counter")]
+    Counter,
     #[serde(rename = "img_date_time")]
     ImageDateTime(DateTimeFormatString),
     #[serde(rename = "access_time")]
@@ -258,20 +1699,54 @@ pub enum PathElement {
     CreatedTime(DateTimeFormatString),
     #[serde(rename = "modified_time")]
     ModifiedTime(DateTimeFormatString),
+    /// A label depending on how long ago the file was last modified.
+    #[serde(rename = "age_bucket")]
+    AgeBucket(AgeBuckets),
 }
 
+/// Stands in for `PathElement::Counter` until the whole index is built, see `assign_counters`.
+pub const COUNTER_PLACEHOLDER: &str = "\u{1}counter\u{1}";
+
 impl PathElement {
-    pub fn join_all(paths: &[PathElement], fp: &mut FilePath, base: PathBuf) -> Result<PathBuf> {
-        Ok(paths
-            .iter()
-            .map(|p| p.to_path(fp))
-            .collect::<Result<Vec<PathBuf>>>()?
-            .into_iter()
-            .fold(base, |mut buf, path| {
-                buf.push(path);
+    /// Joins the paths of all elements. Elements evaluating to an empty path or containing `.` or
+    /// `..` are reported, as errors if `strict` is set.
+    pub fn join_all(
+        paths: &[PathElement],
+        fp: &mut FilePath,
+        base: PathBuf,
+        strict: bool,
+    ) -> Result<PathBuf> {
+        let mut buf = base;
+        for element in paths {
+            let path = element.to_path(fp)?;
+
+            let problem = if path.as_os_str().is_empty() {
+                Some("an empty path")
+            } else if path
+                .components()
+                .any(|c| matches!(c, Component::CurDir | Component::ParentDir))
+            {
+                Some("'.' or '..'")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                let message = format!(
+                    "path element {:?} evaluates to {} for {}",
+                    element,
+                    problem,
+                    fp.full_path.display()
+                );
+                if strict {
+                    bail!(message);
+                }
+                eprintln!("WARNING: {}", message);
+            }
 
-                buf
-            }))
+            buf.push(path);
+        }
+
+        Ok(buf)
     }
 
     pub fn to_path(&self, fp: &mut FilePath) -> Result<PathBuf> {
@@ -284,37 +1759,60 @@ impl PathElement {
             PathElement::FileName(s) => s.into(),
             PathElement::OriginalPathWithoutFileName => fp.path.parent().unwrap().to_owned(),
             PathElement::OriginalPath => fp.path.clone(),
-            PathElement::DirectParentFolder => {
-                fp.path.parent().unwrap().file_name().unwrap().into()
-            }
+            PathElement::PathRelativeTo(folder) => fp
+                .path
+                .strip_prefix(fix_cross_path(folder))
+                .map_err(|_| anyhow!("{} is not in folder {}", fp.path.display(), folder))?
+                .to_owned(),
+            PathElement::DirectParentFolder => fp
+                .path
+                .parent()
+                .and_then(Path::file_name)
+                .ok_or_else(|| anyhow!("{} is not in a folder", fp.path.display()))?
+                .into(),
             PathElement::FileNameWithExtension => fp.path.file_name().unwrap().into(),
             PathElement::FileNameWithoutExtension => fp.path.file_stem().unwrap().into(),
             PathElement::FileExtension => fp.path.extension().unwrap().into(),
-            PathElement::ImageDateTime(fmt) => fmt
-                .fmt_chrono(
+            PathElement::FileHash => {
+                let digest = fp.digest().ok_or(anyhow!("Cannot hash file"))?;
+
+                format!("{:x}", digest)[..SHORT_HASH_LEN].into()
+            }
+            // formatted date / times may contain '/' to create nested directories
+            PathElement::ImageDateTime(fmt) => fix_cross_path(
+                &fmt.fmt_chrono(
                     &fp.img_metadata()
                         .ok_or(anyhow!("No image metadata"))?
                         .date_time
                         .ok_or(anyhow!("No image date/time"))?,
-                )
-                .into(),
-            PathElement::AccessTime(fmt) => fmt
-                .fmt_systime(fp.metadata().ok_or(anyhow!("No fs metadata"))?.accessed()?)
-                .into(),
-            PathElement::CreatedTime(fmt) => fmt
-                .fmt_systime(fp.metadata().ok_or(anyhow!("No fs metadata"))?.created()?)
-                .into(),
-            PathElement::ModifiedTime(fmt) => fmt
-                .fmt_systime(fp.metadata().ok_or(anyhow!("No fs metadata"))?.modified()?)
-                .into(),
+                ),
+            ),
+            PathElement::AccessTime(fmt) => fix_cross_path(
+                &fmt.fmt_systime(fp.metadata().ok_or(anyhow!("No fs metadata"))?.accessed()?),
+            ),
+            PathElement::CreatedTime(fmt) => fix_cross_path(
+                &fmt.fmt_systime(fp.metadata().ok_or(anyhow!("No fs metadata"))?.created()?),
+            ),
+            PathElement::ModifiedTime(fmt) => fix_cross_path(
+                &fmt.fmt_systime(fp.metadata().ok_or(anyhow!("No fs metadata"))?.modified()?),
+            ),
+            PathElement::AgeBucket(buckets) => fix_cross_path(buckets.label(
+                fp.metadata().ok_or(anyhow!("No fs metadata"))?.modified()?,
+                SystemTime::now(),
+            )),
+            PathElement::Counter => COUNTER_PLACEHOLDER.into(),
             PathElement::MergeStrings(vec) => vec
                 .iter()
-                .map(|x| {
-                    x.to_path(fp).map(|p| {
+                .map(|x| match x {
+                    // `Path::file_name` drops "." and "..", keep those as written
+                    PathElement::FileName(s) => Ok(Path::new(s)
+                        .file_name()
+                        .map_or_else(|| s.clone(), |f| f.to_string_lossy().into_owned())),
+                    x => x.to_path(fp).map(|p| {
                         p.file_name()
                             .map(|f| f.to_string_lossy().into_owned())
                             .unwrap_or_default()
-                    })
+                    }),
                 })
                 .collect::<Result<String>>()?
                 .into(),
@@ -324,7 +1822,14 @@ impl PathElement {
 
 #[cfg(test)]
 mod tests {
+    use serde::de::DeserializeOwned;
+
     use super::*;
+    use crate::util::TempDir;
+
+    fn parse<T: DeserializeOwned>(yaml: &str) -> T {
+        singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(yaml)).unwrap()
+    }
 
     #[test]
     fn test_root_path() {
@@ -340,6 +1845,1024 @@ mod tests {
         assert!(!InRootPath("bar".to_owned()).matches(&mut fp));
     }
 
+    #[test]
+    fn test_ancestor_at() {
+        let ancestor_at = |depth, name: &str| FileFilter::AncestorAt {
+            depth,
+            name: name.to_owned(),
+        };
+
+        let mut fp = FilePath::new("src", "Photos/2021/03/img.jpg");
+
+        assert!(ancestor_at(1, "03").matches(&mut fp));
+        assert!(ancestor_at(2, "2021").matches(&mut fp));
+        assert!(ancestor_at(3, "photos").matches(&mut fp));
+
+        assert!(!ancestor_at(1, "2021").matches(&mut fp));
+        assert!(!ancestor_at(0, "img.jpg").matches(&mut fp));
+        assert!(!ancestor_at(4, "src").matches(&mut fp));
+        assert!(!ancestor_at(100, "Photos").matches(&mut fp));
+    }
+
+    #[test]
+    fn test_full_path_matches_regex() {
+        let filter: FileFilter = parse(r#"full_path_matches_regex: "^/mnt/usb/""#);
+
+        assert!(filter.matches(&mut FilePath::new("/mnt/usb", "Photos/img.jpg")));
+        assert!(!filter.matches(&mut FilePath::new("/mnt/nas", "Photos/img.jpg")));
+
+        let relative: FileFilter = parse(r#"path_matches_regex: "^/mnt/usb/""#);
+        assert!(!relative.matches(&mut FilePath::new("/mnt/usb", "Photos/img.jpg")));
+    }
+
+    #[test]
+    fn test_text_encoding() {
+        use crate::file_path::detect_text_encoding;
+
+        assert_eq!(
+            Some("utf-8-bom"),
+            detect_text_encoding(b"\xef\xbb\xbfabc", false)
+        );
+        assert_eq!(
+            Some("utf-16le"),
+            detect_text_encoding(b"\xff\xfea\0", false)
+        );
+        assert_eq!(
+            Some("utf-32le"),
+            detect_text_encoding(b"\xff\xfe\0\0a\0\0\0", false)
+        );
+        assert_eq!(Some("utf-16be"), detect_text_encoding(b"\0a\0b", false));
+        assert_eq!(
+            Some("ascii"),
+            detect_text_encoding(b"plain\r\ntext\t", false)
+        );
+        assert_eq!(
+            Some("utf-8"),
+            detect_text_encoding("Caf\u{e9}".as_bytes(), false)
+        );
+        // an "é" cut off after its first byte
+        assert_eq!(Some("utf-8"), detect_text_encoding(b"Caf\xc3", true));
+        assert_eq!(Some("legacy"), detect_text_encoding(b"Caf\xc3", false));
+        assert_eq!(
+            Some("legacy"),
+            detect_text_encoding(b"Caf\xe9 cr\xe8me", false)
+        );
+        assert_eq!(
+            None,
+            detect_text_encoding(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", false)
+        );
+        assert_eq!(None, detect_text_encoding(b"", false));
+
+        let dir = TempDir::new("encoding");
+        std::fs::write(dir.join("ascii.txt"), "abc").unwrap();
+        std::fs::write(dir.join("latin1.txt"), b"Caf\xe9").unwrap();
+        let filter: FileFilter = parse("text_encoding: [UTF-8]");
+
+        assert!(filter.matches(&mut FilePath::new(&dir, "ascii.txt")));
+        assert!(!filter.matches(&mut FilePath::new(&dir, "latin1.txt")));
+        assert!(!filter.matches(&mut FilePath::new(&dir, "missing.txt")));
+        assert!(parse::<FileFilter>("text_encoding: [legacy]")
+            .matches(&mut FilePath::new(&dir, "latin1.txt")));
+    }
+
+    #[test]
+    fn test_content_type() {
+        let dir = TempDir::new("mime");
+        // a PNG signature behind the wrong extension
+        std::fs::write(dir.join("image.jpg"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        std::fs::write(dir.join("notes.txt"), "just text").unwrap();
+
+        let content_type =
+            |types: &[&str]| FileFilter::ContentType(types.iter().map(|t| t.to_string()).collect());
+        let mut png = FilePath::new(&dir, "image.jpg");
+        let mut text = FilePath::new(&dir, "notes.txt");
+
+        assert!(content_type(&["image/png"]).matches(&mut png));
+        assert!(content_type(&["IMAGE/*"]).matches(&mut png));
+        assert!(!content_type(&["image/jpeg"]).matches(&mut png));
+        assert!(!content_type(&["image/*", "text/plain"]).matches(&mut text));
+        assert!(!content_type(&["image/*"]).matches(&mut FilePath::new(&dir, "missing")));
+    }
+
+    #[test]
+    fn test_file_size() {
+        let dir = TempDir::new("size");
+        std::fs::write(dir.join("a.bin"), [0; 10]).unwrap();
+        let filter: FileFilter = parse("file_size:\n  min: 10\n  max: 10");
+        let file_size = |min, max| FileFilter::FileSize { min, max };
+
+        let mut fp = FilePath::new(&dir, "a.bin");
+        assert!(filter.matches(&mut fp));
+        assert!(file_size(None, None).matches(&mut fp));
+        assert!(file_size(Some(5), None).matches(&mut fp));
+        assert!(!file_size(Some(11), None).matches(&mut fp));
+        assert!(!file_size(None, Some(9)).matches(&mut fp));
+        assert!(!file_size(None, None).matches(&mut FilePath::new(&dir, "missing")));
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let dir_size = |min, max| FileFilter::DirSize { min, max };
+
+        let mut fp = FilePath::new(".", "src");
+        assert!(dir_size(Some(1), None).matches(&mut fp));
+        assert!(!dir_size(None, Some(1)).matches(&mut fp));
+
+        let mut fp = FilePath::new(".", "Cargo.toml");
+        assert!(!dir_size(None, None).matches(&mut fp));
+    }
+
+    #[test]
+    fn test_file_count() {
+        let dir = TempDir::new("count");
+        std::fs::create_dir_all(dir.join("cache/nested")).unwrap();
+        for name in ["a", "b", "nested/c"] {
+            std::fs::write(dir.join("cache").join(name), "").unwrap();
+        }
+        let filter: FileFilter = parse("file_count:\n  min: 3");
+        let file_count = |min, max| FileFilter::FileCount { min, max };
+
+        let mut fp = FilePath::new(&dir, "cache");
+        assert!(file_count(Some(3), Some(3)).matches(&mut fp));
+        assert!(!file_count(None, Some(2)).matches(&mut fp));
+        // only the direct entries count
+        assert!(!file_count(Some(4), None).matches(&mut fp));
+        assert!(filter.matches(&mut fp));
+        assert!(!file_count(None, None).matches(&mut FilePath::new(&dir, "cache/a")));
+        assert!(!file_count(None, None).matches(&mut FilePath::new(&dir, "missing")));
+    }
+
+    #[test]
+    fn test_source_filter_globs() {
+        let only = |names: &[&str]| {
+            SourceFilter::Only(names.iter().map(|name| name.to_string()).collect())
+        };
+
+        assert!(only(&["phone-*"]).includes("phone-2022"));
+        assert!(only(&["phone-*"]).includes("phone-"));
+        assert!(!only(&["phone-*"]).includes("my-phone-2022"));
+        assert!(only(&["*-202?"]).includes("phone-2023"));
+        assert!(!only(&["*-202?"]).includes("phone-20234"));
+        assert!(only(&["p*o*e"]).includes("phone"));
+        assert!(!only(&["p*o*e"]).includes("phones"));
+        assert!(only(&["home", "work"]).includes("work"));
+        assert!(!only(&["home"]).includes("homes"));
+        assert!(!SourceFilter::Except(vec!["phone-*".to_owned()]).includes("phone-2023"));
+        assert!(SourceFilter::Except(vec!["phone-*".to_owned()]).includes("home"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_types() {
+        let dir = TempDir::new("types");
+        std::fs::write(dir.join("file"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("file"), dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing"), dir.join("broken")).unwrap();
+        let _listener = std::os::unix::net::UnixListener::bind(dir.join("socket")).unwrap();
+
+        let matching = |filter: FileFilter| {
+            ["file", "link", "broken", "socket", "missing"]
+                .iter()
+                .copied()
+                .filter(|name| filter.matches(&mut FilePath::new(&dir, name)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["file", "link"], matching(FileFilter::IsFile));
+        assert_eq!(vec!["file"], matching(FileFilter::IsRegularFile));
+        assert_eq!(vec!["broken"], matching(FileFilter::IsBrokenSymlink));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_owned_by() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TempDir::new("owner");
+        std::fs::write(dir.join("file"), "").unwrap();
+        let meta = std::fs::metadata(dir.join("file")).unwrap();
+        let matches = |yaml: &str| {
+            parse::<FileFilter>(yaml).matches(&mut FilePath::new(&dir, "file"))
+                && !parse::<FileFilter>(yaml).matches(&mut FilePath::new(&dir, "missing"))
+        };
+
+        assert!(matches(&format!("owned_by:\n  uid: {}", meta.uid())));
+        assert!(matches(&format!(
+            "owned_by:\n  uid: {}\n  gid: {}",
+            meta.uid(),
+            meta.gid()
+        )));
+        assert!(!matches(&format!("owned_by:\n  uid: {}", meta.uid() + 1)));
+        assert!(!matches(&format!(
+            "owned_by:\n  uid: {}\n  gid: {}",
+            meta.uid(),
+            meta.gid() + 1
+        )));
+        assert_eq!(meta.uid() == 0, matches("owned_by:\n  user: root"));
+
+        let err = |yaml: &str| {
+            singleton_map_recursive::deserialize::<FileFilter, _>(
+                serde_yaml::Deserializer::from_str(yaml),
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert!(err("owned_by:\n  user: no-such-user-sortnbackup").contains("Unknown user"));
+        assert!(err("owned_by:\n  uid: 0\n  user: root").contains("either uid or user"));
+        assert!(err("owned_by: {}").contains("needs a uid"));
+    }
+
+    #[test]
+    fn test_destination() {
+        let dir = TempDir::new("dest");
+        for file in [
+            "home/Pictures/Holiday/beach.JPG",
+            "home/Documents/letter.pdf",
+            "home/.cache/blob",
+            "home/notes.txt",
+        ] {
+            std::fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let config = Config::from_reader(
+            format!(
+                r#"
+settings:
+  file_size_style: binary
+  lowercase_extension: true
+sources:
+  home:
+    path: "{0}/home"
+targets:
+  nas: "{0}/nas"
+  usb: "{0}/usb"
+file_groups:
+  hidden:
+    sources: all
+    filter:
+      file_name_matches_regex: "^\\."
+    rule: ignore
+  pictures:
+    sources: all
+    filter:
+      all:
+        - is_file
+        - in_folder: Pictures
+    rule:
+      copy_to:
+        target: usb
+        path:
+          - file_name: Photos
+          - direct_parent_folder
+          - file_name_with_extension
+  folders:
+    sources: all
+    filter: is_dir
+    rule: traverse
+  rest:
+    sources: all
+    filter: catch_all
+    rule:
+      copy_exact:
+        target: nas
+"#,
+                dir.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let destination = |file: &str| {
+            let mut fp = FilePath::new(dir.join("home"), file);
+            let (_, group) = config.file_group("home", &mut fp, None)?;
+            config
+                .destination(&group.rule, "home", &mut fp)
+                .map(|to| to.unwrap().strip_prefix(&dir).unwrap().to_owned())
+        };
+
+        assert_eq!(
+            Some(PathBuf::from("usb/Photos/Holiday/beach.jpg")),
+            destination("Pictures/Holiday/beach.JPG")
+        );
+        assert_eq!(
+            Some(PathBuf::from("nas/Documents/letter.pdf")),
+            destination("Documents/letter.pdf")
+        );
+        assert_eq!(
+            Some(PathBuf::from("nas/notes.txt")),
+            destination("notes.txt")
+        );
+        assert_eq!(None, destination(".cache"));
+        assert_eq!(None, destination("Documents"));
+
+        let mut namespaced = config.clone();
+        namespaced.settings.namespace_by_source = true;
+        assert_eq!(
+            dir.join("usb/home/Photos/Holiday/beach.jpg"),
+            namespaced
+                .namespaced("home", "usb", dir.join("usb/Photos/Holiday/beach.jpg"))
+                .unwrap()
+        );
+        assert!(namespaced
+            .namespaced("home", "tape", dir.join("x"))
+            .is_err());
+        assert_eq!(
+            dir.join("usb/Photos/beach.jpg"),
+            config
+                .namespaced("home", "usb", dir.join("usb/Photos/beach.jpg"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_has_sidecar() {
+        let dir = TempDir::new("sidecar");
+        for name in ["edited.cr2", "edited.xmp", "untouched.cr2", "notes.xmp"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let filter: FileFilter = parse("has_sidecar:\n  extension: xmp");
+
+        let matching = [
+            "edited.cr2",
+            "edited.xmp",
+            "untouched.cr2",
+            "notes.txt",
+            "missing.cr2",
+        ]
+        .iter()
+        .copied()
+        .filter(|name| filter.matches(&mut FilePath::new(&dir, name)))
+        .collect::<Vec<_>>();
+        assert_eq!(vec!["edited.cr2", "notes.txt"], matching);
+    }
+
+    #[test]
+    fn test_overlapping_sources() {
+        let sources: HashMap<String, Source> = parse(
+            r#"
+data:
+  path: "/nonexistent/data"
+  ignore_paths: ["music"]
+photos:
+  path: "/nonexistent/data/photos"
+music:
+  path: "/nonexistent/data/music/albums"
+photos_again:
+  path: "/nonexistent/data/photos"
+old:
+  path: "/nonexistent/data/old"
+  disabled: true
+other:
+  path: "/nonexistent/other"
+"#,
+        );
+        let config = Config {
+            sources,
+            ..Config::with_targets(HashMap::new())
+        };
+
+        assert_eq!(
+            vec![
+                ("data", "photos"),
+                ("data", "photos_again"),
+                ("photos", "photos_again")
+            ],
+            config.overlapping_sources()
+        );
+    }
+
+    #[test]
+    fn test_matched_target() {
+        let config = Config::with_targets(parse("canon: /backup/canon\nnikon: /backup/nikon"));
+        let elements = [PathElement::DirectParentFolder];
+
+        assert_eq!(
+            "canon",
+            config
+                .matched_target(&elements, &mut FilePath::new("src", "canon/a.jpg"))
+                .unwrap()
+        );
+        let err = config
+            .matched_target(&elements, &mut FilePath::new("src", "sony/b.jpg"))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Unknown target: 'sony'"),
+            "{:#}",
+            err
+        );
+        assert!(format!("{:#}", err).contains("b.jpg"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_categories() {
+        let config = |categories: &str, filter: &str| {
+            Config::from_reader(
+                format!(
+                    "settings:\n  file_size_style: binary\n  categories: {}\nsources: {{}}\ntargets: {{}}\nfile_groups:\n  media:\n    sources: all\n    filter: {}\n    rule: ignore\n",
+                    categories, filter
+                )
+                .as_bytes(),
+            )
+        };
+        let matches = |config: &Config, path: &str| {
+            config
+                .file_group("home", &mut FilePath::new("/nonexistent", path), None)
+                .is_some()
+        };
+
+        let config_ = config("{}", "{is_category: image}").unwrap();
+        assert!(matches(&config_, "a.JPG"));
+        assert!(matches(&config_, "b.cr2"));
+        assert!(!matches(&config_, "c.mp4"));
+        assert!(!matches(&config_, "image"));
+
+        let config_ = config(
+            "{image: [jpg], scans: [tiff]}",
+            "{any: [{is_category: image}, {not: {not: {is_category: scans}}}]}",
+        )
+        .unwrap();
+        assert!(matches(&config_, "a.jpg"));
+        assert!(!matches(&config_, "b.png"));
+        assert!(matches(&config_, "c.tiff"));
+
+        assert!(config("{}", "{is_category: spreadsheet}").is_err());
+    }
+
+    #[test]
+    fn test_extension_routes() {
+        let dir = TempDir::new("routes");
+        for file in ["a.JPG", "b.pdf", "Screenshot 1.jpg", "c.txt"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let config = Config::from_reader(
+            r#"
+settings:
+  file_size_style: binary
+  extension_routes:
+    jpg: photos
+    .pdf: docs
+sources: {}
+targets:
+  photos: /backup/photos
+  docs: /backup/docs
+  other: /backup/other
+file_groups:
+  screenshots:
+    sources: all
+    filter:
+      file_name_matches_regex: "^Screenshot"
+    rule:
+      copy_exact:
+        target: other
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let group = |path: &str| {
+            config
+                .file_group("home", &mut FilePath::new(&dir, path), None)
+                .map(|(name, group)| (name.to_owned(), group.rule.to_string()))
+        };
+        let route = |name: &str, rule: &str| Some((name.to_owned(), rule.to_owned()));
+        assert_eq!(
+            route("extension_routes.jpg", "copy_to target 'photos'"),
+            group("a.JPG")
+        );
+        assert_eq!(
+            route("extension_routes.pdf", "copy_to target 'docs'"),
+            group("b.pdf")
+        );
+        assert_eq!(
+            route("screenshots", "copy_exact target 'other'"),
+            group("Screenshot 1.jpg")
+        );
+        assert_eq!(None, group("c.txt"));
+
+        let mut fp = FilePath::new(&dir, "b.pdf");
+        assert_eq!(
+            Path::new("/backup/docs/b.pdf"),
+            match &config.file_group("home", &mut fp, None).unwrap().1.rule {
+                Rule::CopyTo { target, path, .. } =>
+                    config.target_path(target, path, &mut fp).unwrap(),
+                rule => panic!("unexpected rule {}", rule),
+            }
+        );
+    }
+
+    #[test]
+    fn test_normalize_filenames() {
+        let composed = Path::new("Caf\u{e9}/R\u{e9}sum\u{e9}.txt");
+        let decomposed = Path::new("Cafe\u{301}/Re\u{301}sume\u{301}.txt");
+
+        assert_eq!(composed, NormalizeFilenames::Nfc.apply(decomposed));
+        assert_eq!(decomposed, NormalizeFilenames::Nfd.apply(composed));
+        assert_eq!(decomposed, NormalizeFilenames::None.apply(decomposed));
+        assert_ne!(composed, decomposed);
+
+        let settings: Settings = parse("file_size_style: binary\nnormalize_filenames: nfc");
+        assert_eq!(NormalizeFilenames::Nfc, settings.normalize_filenames);
+    }
+
+    #[test]
+    fn test_disable_sources() {
+        let mut config: Config = parse(
+            r#"
+settings:
+  file_size_style: binary
+sources:
+  home:
+    path: /home
+  phone-2022:
+    path: /phone/2022
+  phone-2023:
+    path: /phone/2023
+    disabled: true
+  laptop:
+    path: /laptop
+targets: {}
+file_groups: {}
+"#,
+        );
+
+        let (disabled, unmatched) = config.disable_sources("phone-*, laptop,,work");
+
+        assert_eq!(vec!["laptop", "phone-2022"], disabled);
+        assert_eq!(vec!["work"], unmatched);
+        assert!(!config.sources["home"].disabled);
+        assert!(config.sources["phone-2022"].disabled);
+        assert!(config.sources["phone-2023"].disabled);
+        assert!(config.sources["laptop"].disabled);
+    }
+
+    #[test]
+    fn test_shorten_file_name() {
+        let long = "2023-05-01 Birthday party at the lake with everyone.jpg";
+        let shortened = shorten_file_name(&Path::new("Photos").join(long), 32).unwrap();
+        let hash = &format!("{:x}", md5::compute(long))[..SHORT_HASH_LEN];
+        assert_eq!(
+            Path::new("Photos").join(format!("2023-05-01 Birthday~{}.jpg", hash)),
+            shortened
+        );
+        assert_eq!(32, shortened.file_name().unwrap().len());
+        // deterministic, and names differing only after the cut stay different
+        assert_eq!(
+            Some(shortened),
+            shorten_file_name(&Path::new("Photos").join(long), 32)
+        );
+        assert_ne!(
+            shorten_file_name(Path::new(long), 32),
+            shorten_file_name(Path::new(&long.replace("everyone", "everybody")), 32)
+        );
+
+        assert_eq!(None, shorten_file_name(Path::new("Photos/short.jpg"), 32));
+        // not within a multi-byte character
+        let name = shorten_file_name(Path::new("ääääääääääää.txt"), 16).unwrap();
+        assert_eq!(15, name.to_str().unwrap().len());
+        assert!(name.to_str().unwrap().starts_with("ä~"));
+        // the extension is dropped if it doesn't fit
+        let name = shorten_file_name(Path::new("archive.verylongextension"), 16).unwrap();
+        assert_eq!(16, name.to_str().unwrap().len());
+        assert!(!name.to_str().unwrap().contains('.'));
+
+        let settings = |yaml: &str| {
+            singleton_map_recursive::deserialize::<Settings, _>(serde_yaml::Deserializer::from_str(
+                &format!("file_size_style: binary\n{}", yaml),
+            ))
+        };
+        assert_eq!(
+            Some(255),
+            settings("max_filename_length: 255")
+                .unwrap()
+                .max_filename_length
+        );
+        assert!(settings("max_filename_length: 8").is_err());
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        let settings = |yaml: &str| {
+            singleton_map_recursive::deserialize::<Settings, _>(serde_yaml::Deserializer::from_str(
+                &format!("file_size_style: binary\n{}", yaml),
+            ))
+        };
+        let sampled = |settings: &Settings| {
+            (0..1000)
+                .map(|i| PathBuf::from(format!("Photos/{}/IMG_{}.jpg", i % 7, i)))
+                .filter(|path| settings.is_sampled(path))
+                .collect::<Vec<_>>()
+        };
+
+        let tenth = settings("sample_rate: 0.1").unwrap();
+        let sample = sampled(&tenth);
+        assert!((60..140).contains(&sample.len()), "{}", sample.len());
+        assert_eq!(sample, sampled(&tenth));
+        assert_ne!(
+            sample,
+            sampled(&settings("sample_rate: 0.1\nsample_seed: 1").unwrap())
+        );
+
+        assert_eq!(1000, sampled(&Settings::default()).len());
+        assert_eq!(1000, sampled(&settings("sample_rate: 1").unwrap()).len());
+        assert!(sampled(&settings("sample_rate: 0").unwrap()).is_empty());
+        assert!(settings("sample_rate: 1.5").is_err());
+        assert!(settings("sample_rate: -0.1").is_err());
+    }
+
+    #[test]
+    fn test_tag_file_name() {
+        for (expected, path) in [
+            ("Photos/import_beach_2024.jpg", "Photos/beach.jpg"),
+            ("Photos/import_archive.tar_2024.gz", "Photos/archive.tar.gz"),
+            ("import_README_2024", "README"),
+            ("import_.profile_2024", ".profile"),
+        ] {
+            assert_eq!(
+                Path::new(expected),
+                tag_file_name(Path::new(path), "import_", "_2024")
+            );
+        }
+        assert_eq!(
+            Path::new("beach-copy.jpg"),
+            tag_file_name(Path::new("beach.jpg"), "", "-copy")
+        );
+        assert_eq!(
+            Path::new("beach.jpg"),
+            tag_file_name(Path::new("beach.jpg"), "", "")
+        );
+
+        let mut config: Config = parse(
+            r#"
+settings:
+  file_size_style: binary
+  filename_prefix: "import_"
+sources: {}
+targets:
+  nas: /backup
+file_groups: {}
+"#,
+        );
+        config.settings.filename_suffix = Some("_2024".to_owned());
+        assert_eq!(
+            Path::new("/backup/Photos/import_beach_2024.jpg"),
+            config
+                .target_path_of("nas", Path::new("Photos/beach.jpg"))
+                .unwrap()
+        );
+        let log_file = [PathElement::FileName("copied.log".to_owned())];
+        assert_eq!(
+            Path::new("/backup/copied.log"),
+            config
+                .log_file_path("nas", &log_file, &mut FilePath::new("/src", "a.jpg"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lowercase_extension() {
+        for (expected, path) in [
+            ("DCIM/IMG_0001.jpg", "DCIM/IMG_0001.JPG"),
+            ("DCIM/IMG_0002.jpg", "DCIM/IMG_0002.jpg"),
+            ("Clips/Movie.Final.mp4", "Clips/Movie.Final.Mp4"),
+            ("Folder.ABC/README", "Folder.ABC/README"),
+            (".PROFILE", ".PROFILE"),
+        ] {
+            assert_eq!(Path::new(expected), lowercase_extension(Path::new(path)));
+        }
+
+        let mut config: Config = parse(
+            r#"
+settings:
+  file_size_style: binary
+  lowercase_extension: true
+sources: {}
+targets:
+  nas: /backup
+file_groups: {}
+"#,
+        );
+        assert_eq!(
+            Path::new("/backup/Photos/A.jpg"),
+            config
+                .target_path_of("nas", Path::new("Photos/A.JPG"))
+                .unwrap()
+        );
+        config.settings.lowercase_extension = false;
+        assert_eq!(
+            Path::new("/backup/Photos/A.JPG"),
+            config
+                .target_path_of("nas", Path::new("Photos/A.JPG"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dest_mode() {
+        let dest_mode =
+            |yaml: &str| parse::<Settings>(&format!("file_size_style: binary\n{}", yaml)).dest_mode;
+
+        assert_eq!(None, dest_mode(""));
+        assert_eq!(Some(0o640), dest_mode("dest_mode: \"0640\""));
+        assert_eq!(Some(0o640), dest_mode("dest_mode: \"640\""));
+        assert_eq!(Some(0o640), dest_mode("dest_mode: 0o640"));
+        assert!(
+            serde_yaml::from_str::<Settings>("file_size_style: binary\ndest_mode: \"0980\"")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolve_relative_paths() {
+        let mut config: Config = parse(
+            r#"
+file_groups: {}
+sources:
+  relative:
+    path: "photos"
+  absolute:
+    path: "/home/me"
+targets:
+  relative: "../backup"
+  absolute: "/mnt/backup"
+settings:
+  file_size_style: binary
+"#,
+        );
+
+        config.resolve_relative_paths(Path::new("/etc/sortnbackup"));
+
+        assert_eq!(
+            Path::new("/etc/sortnbackup/photos"),
+            config.sources["relative"].path
+        );
+        assert_eq!(Path::new("/home/me"), config.sources["absolute"].path);
+        assert_eq!(
+            Path::new("/etc/sortnbackup/../backup"),
+            config.targets["relative"].path
+        );
+        assert_eq!(Path::new("/mnt/backup"), config.targets["absolute"].path);
+    }
+
+    #[test]
+    fn test_empty_path_element() {
+        let elements = [PathElement::OriginalPathWithoutFileName];
+        let mut root_file = FilePath::new("src", "main.rs");
+        let mut nested_file = FilePath::new(".", "src/main.rs");
+
+        let err = PathElement::join_all(&elements, &mut root_file, "/backup".into(), true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("OriginalPathWithoutFileName"), "{}", err);
+        assert!(err.contains("main.rs"), "{}", err);
+
+        assert_eq!(
+            Path::new("/backup"),
+            PathElement::join_all(&elements, &mut root_file, "/backup".into(), false).unwrap()
+        );
+        assert_eq!(
+            Path::new("/backup/src"),
+            PathElement::join_all(&elements, &mut nested_file, "/backup".into(), true).unwrap()
+        );
+
+        let dots = [PathElement::FileName("..".to_owned())];
+        assert!(PathElement::join_all(&dots, &mut nested_file, "/backup".into(), true).is_err());
+        assert!(PathElement::DirectParentFolder
+            .to_path(&mut root_file)
+            .is_err());
+    }
+
+    #[test]
+    fn test_ignore_file_names() {
+        let settings = Settings {
+            ignore_file_names: vec!["Thumbs.db".to_owned(), ".DS_Store".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(settings.is_ignored_file_name(OsStr::new("Thumbs.db")));
+        assert!(settings.is_ignored_file_name(OsStr::new(".DS_Store")));
+        assert!(!settings.is_ignored_file_name(OsStr::new("Thumbs.db.bak")));
+        assert_eq!(
+            cfg!(any(windows, target_os = "macos")),
+            settings.is_ignored_file_name(OsStr::new("thumbs.db"))
+        );
+    }
+
+    #[test]
+    fn test_skip_apple_double() {
+        let mut settings = Settings::default();
+        assert!(!settings.is_skipped_apple_double(OsStr::new("._IMG_0001.JPG")));
+
+        settings.skip_apple_double = true;
+        assert!(settings.is_skipped_apple_double(OsStr::new("._IMG_0001.JPG")));
+        assert!(settings.is_skipped_apple_double(OsStr::new("._.DS_Store")));
+        assert!(!settings.is_skipped_apple_double(OsStr::new("IMG_0001.JPG")));
+        assert!(!settings.is_skipped_apple_double(OsStr::new(".hidden")));
+        assert!(!settings.is_skipped_apple_double(OsStr::new("._")));
+    }
+
+    #[test]
+    fn test_target_forms() {
+        let targets: HashMap<String, Target> = parse(
+            r#"
+plain: "/mnt/backup"
+detailed:
+  path: "/mnt/nas"
+  verify: true
+  jobs: 2
+  deduplicate: true
+"#,
+        );
+
+        assert_eq!(Path::new("/mnt/backup"), targets["plain"].path);
+        assert!(!targets["plain"].verify);
+        assert_eq!(None, targets["plain"].jobs);
+        assert!(!targets["plain"].deduplicate);
+
+        assert_eq!(Path::new("/mnt/nas"), targets["detailed"].path);
+        assert!(targets["detailed"].verify);
+        assert_eq!(Some(2), targets["detailed"].jobs);
+        assert_eq!(None, targets["detailed"].buffer_size);
+        assert_eq!(None, targets["detailed"].remote);
+        assert!(targets["detailed"].deduplicate);
+    }
+
+    #[test]
+    fn test_target_bases() {
+        let config = |targets: &str| {
+            Config::from_reader(
+                format!(
+                    "settings:\n  file_size_style: binary\nsources: {{}}\nfile_groups: {{}}\ntargets:\n{}",
+                    targets
+                )
+                .as_bytes(),
+            )
+        };
+
+        let targets = config(
+            r#"
+  archive: "/mnt/archive"
+  archive-photos:
+    base: archive
+    subpath: photos
+    verify: true
+  archive-raw:
+    base: archive-photos
+    subpath: raw/2024
+  archive-again:
+    base: archive
+  server: "sftp://me@example.com/backup"
+  server-docs:
+    base: server
+    subpath: docs
+"#,
+        )
+        .unwrap()
+        .targets;
+        assert_eq!(
+            Path::new("/mnt/archive/photos"),
+            targets["archive-photos"].path
+        );
+        assert!(targets["archive-photos"].verify);
+        assert_eq!(
+            Path::new("/mnt/archive/photos/raw/2024"),
+            targets["archive-raw"].path
+        );
+        assert_eq!(Path::new("/mnt/archive"), targets["archive-again"].path);
+        assert_eq!(Path::new("/backup/docs"), targets["server-docs"].path);
+        assert_eq!(targets["server"].remote, targets["server-docs"].remote);
+
+        let cycle = config("  a:\n    base: b\n  b:\n    base: c\n  c:\n    base: a\n");
+        assert_eq!(
+            "targets form a cycle: a -> b -> c -> a",
+            cycle.unwrap_err().to_string()
+        );
+        assert!(config("  a:\n    base: missing\n").is_err());
+        assert!(config("  a: /x\n  b:\n    base: a\n    path: /y\n").is_err());
+        assert!(config("  a:\n    path: /x\n    subpath: y\n").is_err());
+    }
+
+    #[test]
+    fn test_sftp_targets() {
+        let targets: HashMap<String, Target> = parse(
+            r#"
+plain: "sftp://backup@nas.local/srv/backup"
+detailed:
+  path: "sftp://me@example.com:2222/home/me/backup"
+  identity_file: "/home/me/.ssh/id_ed25519"
+"#,
+        );
+
+        assert_eq!(Path::new("/srv/backup"), targets["plain"].path);
+        assert_eq!(
+            Some(Remote {
+                user: "backup".to_owned(),
+                host: "nas.local".to_owned(),
+                port: 22,
+                identity_file: None,
+            }),
+            targets["plain"].remote
+        );
+
+        let detailed = targets["detailed"].remote.as_ref().unwrap();
+        assert_eq!(Path::new("/home/me/backup"), targets["detailed"].path);
+        assert_eq!(2222, detailed.port);
+        assert_eq!(
+            Some(Path::new("/home/me/.ssh/id_ed25519")),
+            detailed.identity_file.as_deref()
+        );
+
+        for invalid in [
+            "sftp://nas.local/backup",
+            "sftp://me@nas.local",
+            "sftp://me@nas:x/b",
+        ] {
+            let yaml = format!("t: \"{}\"", invalid);
+            let result: Result<HashMap<String, Target>, _> =
+                singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(&yaml));
+            assert!(result.is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_modified_after_file() {
+        let dir = TempDir::new("marker");
+        let now = SystemTime::now();
+        for (name, age) in [("marker", 60), ("old.txt", 120), ("new.txt", 0)] {
+            std::fs::File::create(dir.join(name))
+                .unwrap()
+                .set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+
+        let filter: FileFilter = parse(&format!(
+            "modified_after_file: \"{}\"",
+            dir.join("marker").display()
+        ));
+        assert!(filter.matches(&mut FilePath::new(&dir, "new.txt")));
+        assert!(!filter.matches(&mut FilePath::new(&dir, "old.txt")));
+        assert!(!filter.matches(&mut FilePath::new(&dir, "marker")));
+        assert!(!filter.matches(&mut FilePath::new(&dir, "missing.txt")));
+
+        let missing: Result<FileFilter, _> =
+            singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(&format!(
+                "modified_after_file: \"{}\"",
+                dir.join("missing").display()
+            )));
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("Cannot read modification time of reference file"));
+    }
+
+    #[test]
+    fn test_hash_suffix() {
+        let dir = TempDir::new("hash");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        std::fs::write(dir.join("a/photo.jpg"), "first").unwrap();
+        std::fs::write(dir.join("b/photo.jpg"), "second").unwrap();
+
+        let element: PathElement = parse(
+            r#"
+merge_strings:
+  - file_name_without_extension
+  - file_name: "_"
+  - file_hash
+  - file_name: "."
+  - file_extension
+"#,
+        );
+        let name = |path: &str| {
+            element
+                .to_path(&mut FilePath::new(&dir, path))
+                .unwrap()
+                .display()
+                .to_string()
+        };
+
+        let a = name("a/photo.jpg");
+        let b = name("b/photo.jpg");
+
+        assert!(a.starts_with("photo_") && a.ends_with(".jpg"));
+        assert_eq!("photo_".len() + SHORT_HASH_LEN + ".jpg".len(), a.len());
+        assert_ne!(a, b);
+        assert_eq!(
+            a,
+            format!("photo_{}.jpg", &format!("{:x}", md5::compute("first"))[..8])
+        );
+    }
+
+    #[test]
+    fn test_nested_date_time() {
+        use std::convert::TryFrom;
+
+        let fmt = DateTimeFormatString::try_from("%Y/%m/%d".to_owned()).unwrap();
+        let mut fp = FilePath::new(".", "Cargo.toml");
+
+        let path = PathElement::ModifiedTime(fmt).to_path(&mut fp).unwrap();
+
+        assert_eq!(3, path.components().count());
+    }
+
     #[test]
     fn test_merge_strings() {
         use PathElement::{FileName, FileNameWithoutExtension, MergeStrings};
@@ -357,5 +2880,114 @@ mod tests {
         .to_string();
 
         assert_eq!("hello_bar_world", path);
+
+        let mut merged = |names: &[&str]| {
+            MergeStrings(
+                names
+                    .iter()
+                    .map(|name| FileName(name.to_string()))
+                    .collect(),
+            )
+            .to_path(&mut fp)
+            .unwrap()
+            .display()
+            .to_string()
+        };
+        assert_eq!("bar.jpg", merged(&["bar", ".", "jpg"]));
+        assert_eq!("a..b", merged(&["a", "..", "b"]));
+        // everything else as before, only the last component of a name is used
+        assert_eq!("a-b_c", merged(&["a", "-", "x/b", "_c/"]));
+    }
+
+    #[test]
+    fn test_yaml_tags() {
+        let tagged = r#"
+settings:
+  file_size_style: binary
+sources: {}
+targets: {}
+file_groups:
+  photos:
+    sources: !only [phone]
+    filter: !all
+      - is_file
+      - !has_extension [jpg]
+    rule: !copy_to
+      target: nas
+      path:
+        - !file_name Photos
+        - file_name_with_extension
+  rest:
+    sources: all
+    filter: catch_all
+    rule: ignore
+"#;
+        let mapped = r#"
+settings:
+  file_size_style: binary
+sources: {}
+targets: {}
+file_groups:
+  photos:
+    sources:
+      only: [phone]
+    filter:
+      all:
+        - is_file
+        - has_extension: [jpg]
+    rule:
+      copy_to:
+        target: nas
+        path:
+          - file_name: Photos
+          - file_name_with_extension
+  rest:
+    sources: all
+    filter: catch_all
+    rule: ignore
+"#;
+        let groups = |yaml: &str| {
+            let config = Config::from_reader(yaml.as_bytes()).unwrap();
+            format!("{:?}", config.file_groups)
+        };
+        let plain: Config = serde_yaml::from_str(tagged).unwrap();
+
+        assert_eq!(format!("{:?}", plain.file_groups), groups(tagged));
+        assert_eq!(groups(mapped), groups(tagged));
+        // mixing both forms in one file isn't supported, the error is about the documented one
+        let e = Config::from_reader(tagged.replace("!copy_to", "copy_to:").as_bytes()).unwrap_err();
+        assert!(
+            e.to_string().starts_with("file_groups.photos.sources"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_path_relative_to() {
+        use PathElement::PathRelativeTo;
+
+        let mut fp = FilePath::new("src", "Documents/Invoices/2023/march.pdf");
+
+        assert_eq!(
+            Path::new("2023/march.pdf"),
+            PathRelativeTo("Documents/Invoices".to_owned())
+                .to_path(&mut fp)
+                .unwrap()
+        );
+        assert_eq!(
+            Path::new("Invoices/2023/march.pdf"),
+            PathRelativeTo("Documents".to_owned())
+                .to_path(&mut fp)
+                .unwrap()
+        );
+        // only whole folder names at the start of the path
+        let e = PathRelativeTo("Docu".to_owned())
+            .to_path(&mut fp)
+            .unwrap_err();
+        assert!(format!("{:#}", e).contains("is not in folder Docu"));
+        assert!(PathRelativeTo("Invoices".to_owned())
+            .to_path(&mut fp)
+            .is_err());
     }
 }
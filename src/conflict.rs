@@ -0,0 +1,191 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, stdin, stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local};
+use indicatif::ProgressBar;
+use parking_lot::Mutex;
+
+use crate::util::file_digest;
+
+/// What to do with a file whose destination already exists.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Resolution {
+    Overwrite,
+    Skip,
+    /// Copy to this (free) path next to the destination instead.
+    Rename(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Answer {
+    Overwrite,
+    Skip,
+    Rename,
+    Diff,
+}
+
+/// Asks on the terminal what to do about each conflict (`--interactive`).
+///
+/// Files are copied in parallel; the lock makes sure only one question is asked at a time while
+/// the other copies wait.
+#[derive(Default)]
+pub struct ConflictPrompt {
+    /// The answer given for all remaining conflicts, if any.
+    for_all: Mutex<Option<Answer>>,
+}
+
+impl ConflictPrompt {
+    pub fn resolve(&self, from: &Path, to: &Path, pb: &ProgressBar) -> Result<Resolution> {
+        let mut for_all = self.for_all.lock();
+        let answer = match *for_all {
+            Some(answer) => answer,
+            None => {
+                let (answer, all) = pb.suspend(|| ask(from, to))?;
+                if all {
+                    *for_all = Some(answer);
+                }
+
+                answer
+            }
+        };
+
+        Ok(match answer {
+            Answer::Overwrite | Answer::Diff => Resolution::Overwrite,
+            Answer::Skip => Resolution::Skip,
+            // reserved while holding the lock, so no other conflict gets the same name
+            Answer::Rename => Resolution::Rename(reserve_free_path(to)?),
+        })
+    }
+}
+
+fn ask(from: &Path, to: &Path) -> Result<(Answer, bool)> {
+    println!(
+        "{} already exists (copying {}).",
+        to.display(),
+        from.display()
+    );
+    loop {
+        print!("[o]verwrite, [s]kip, [r]ename, [d]iff? (uppercase: for all conflicts) ");
+        stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin().read_line(&mut line)? == 0 {
+            bail!("stdin closed while asking about {}", to.display());
+        }
+        match parse_answer(line.trim()) {
+            Some((Answer::Diff, _)) => print_diff(from, to),
+            Some(answer) => return Ok(answer),
+            None => println!("Please answer o, s, r or d."),
+        }
+    }
+}
+
+/// The answer and whether it applies to all remaining conflicts.
+fn parse_answer(s: &str) -> Option<(Answer, bool)> {
+    let answer = match s.to_lowercase().as_str() {
+        "o" | "overwrite" => Answer::Overwrite,
+        "s" | "skip" => Answer::Skip,
+        "r" | "rename" => Answer::Rename,
+        "d" | "diff" => Answer::Diff,
+        _ => return None,
+    };
+
+    Some((answer, s.chars().all(char::is_uppercase)))
+}
+
+fn print_diff(from: &Path, to: &Path) {
+    let (from_meta, to_meta) = match (std::fs::metadata(from), std::fs::metadata(to)) {
+        (Ok(from_meta), Ok(to_meta)) => (from_meta, to_meta),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("  cannot compare: {}", e);
+            return;
+        }
+    };
+
+    for (label, meta) in [("source", &from_meta), ("existing", &to_meta)] {
+        let modified = meta
+            .modified()
+            .map(|t| {
+                DateTime::<Local>::from(t)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| "unknown".to_owned());
+        println!("  {:<8} {} bytes, modified {}", label, meta.len(), modified);
+    }
+
+    let content = if from_meta.len() != to_meta.len() {
+        "differs"
+    } else {
+        match (file_digest(from), file_digest(to)) {
+            (Ok(a), Ok(b)) if a == b => "identical",
+            (Ok(_), Ok(_)) => "differs",
+            _ => "cannot be compared",
+        }
+    };
+    println!("  content  {}", content);
+}
+
+/// Creates the first free `name (n).ext` next to `to`.
+fn reserve_free_path(to: &Path) -> io::Result<PathBuf> {
+    let stem = to.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = to
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    for n in 1.. {
+        let candidate = to.with_file_name(format!("{} ({}){}", stem, n, extension));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(Some((Answer::Overwrite, false)), parse_answer("o"));
+        assert_eq!(Some((Answer::Skip, true)), parse_answer("S"));
+        assert_eq!(Some((Answer::Rename, false)), parse_answer("rename"));
+        assert_eq!(Some((Answer::Diff, false)), parse_answer("d"));
+        assert_eq!(None, parse_answer(""));
+        assert_eq!(None, parse_answer("x"));
+    }
+
+    #[test]
+    fn test_reserve_free_path() {
+        let dir = TempDir::new("conflict");
+        std::fs::write(dir.join("photo.jpg"), "a").unwrap();
+
+        assert_eq!(
+            dir.join("photo (1).jpg"),
+            reserve_free_path(&dir.join("photo.jpg")).unwrap()
+        );
+        assert_eq!(
+            dir.join("photo (2).jpg"),
+            reserve_free_path(&dir.join("photo.jpg")).unwrap()
+        );
+        std::fs::write(dir.join("notes"), "b").unwrap();
+        assert_eq!(
+            dir.join("notes (1)"),
+            reserve_free_path(&dir.join("notes")).unwrap()
+        );
+    }
+}
@@ -0,0 +1,587 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    config::{Reflink, Settings, Target},
+    manifest::CopyStatus,
+    util::{file_digest, reader_digest},
+};
+
+/// Buffer size for resumed copies to targets without a `buffer_size`.
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copies a single file, honoring the settings of the target it is copied to.
+///
+/// With `staging`, the file is copied to its part file (see [`part_path`]) and only renamed to
+/// `to` once it is complete.
+pub fn copy_file(
+    from: &Path,
+    to: &Path,
+    settings: &Settings,
+    target: &Target,
+) -> Result<CopyStatus> {
+    if settings.skip_identical && is_identical(from, to) {
+        return Ok(CopyStatus::SkippedIdentical);
+    }
+
+    let dest = staged_path(to, target);
+    let cloned = match settings.reflink {
+        Reflink::Never => false,
+        Reflink::Auto => clone_file(from, to, &dest).is_ok(),
+        Reflink::Always => {
+            clone_file(from, to, &dest).context("cannot clone file (reflink is always)")?;
+            true
+        }
+    };
+    match target.buffer_size {
+        _ if cloned => {}
+        Some(buffer_size) => copy_buffered(from, &dest, buffer_size)?,
+        None if settings.use_copy_file_range => copy_in_kernel(from, &dest)?,
+        None => {
+            std::fs::copy(from, &dest)?;
+        }
+    }
+
+    complete_copy(from, &dest, to, settings, target)
+}
+
+/// Appended to the names of partial copies; no other program writes files ending in it, so they
+/// can be removed without touching files of the user.
+const PART_SUFFIX: &str = ".sortnbackup-part";
+
+/// Clones `from` for a copy to `to` whose content is written to `dest`. The clone is made in the
+/// part file of `to` and only then renamed to `dest`, so a failed clone leaves an existing `dest`
+/// untouched.
+fn clone_file(from: &Path, to: &Path, dest: &Path) -> io::Result<()> {
+    let part = part_path(to);
+    if let Err(e) = reflink(from, &part) {
+        let _ = std::fs::remove_file(&part);
+        return Err(e);
+    }
+    if part != dest {
+        std::fs::rename(&part, dest)?;
+    }
+
+    Ok(())
+}
+
+/// Where a copy to `to` is written while it isn't complete: a hidden file named after it, e.g.
+/// `.photo.jpg.sortnbackup-part`.
+pub fn part_path(to: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(to.file_name().unwrap_or_default());
+    name.push(PART_SUFFIX);
+
+    to.with_file_name(name)
+}
+
+/// Whether `name` is the name of a part file (see [`part_path`]).
+pub fn is_part_file(name: &str) -> bool {
+    name.len() > PART_SUFFIX.len() + 1 && name.starts_with('.') && name.ends_with(PART_SUFFIX)
+}
+
+/// Where the content of a copy to `to` is written: its part file with `staging`.
+pub fn staged_path(to: &Path, target: &Target) -> PathBuf {
+    if target.staging {
+        part_path(to)
+    } else {
+        to.to_owned()
+    }
+}
+
+/// Finishes a copy whose content has been written to `dest`, moving it to `to` if they differ.
+fn complete_copy(
+    from: &Path,
+    dest: &Path,
+    to: &Path,
+    settings: &Settings,
+    target: &Target,
+) -> Result<CopyStatus> {
+    finish_copy(from, dest, settings)?;
+
+    let status = if target.verify {
+        verify_copy(from, dest)?;
+        CopyStatus::Verified
+    } else {
+        CopyStatus::Copied
+    };
+    if settings.fsync {
+        sync_file(dest).with_context(|| format!("cannot flush {} to disk", dest.display()))?;
+    }
+    if dest != to {
+        std::fs::rename(dest, to)
+            .with_context(|| format!("cannot rename {} into place", dest.display()))?;
+    }
+    if settings.fsync && settings.fsync_dirs {
+        if let Some(dir) = to.parent() {
+            sync_dir(dir).with_context(|| format!("cannot flush {} to disk", dir.display()))?;
+        }
+    }
+
+    Ok(status)
+}
+
+/// Waits until the content and metadata of the file have been written to the disk.
+fn sync_file(path: &Path) -> io::Result<()> {
+    // Windows needs write access to flush, as for setting the modification time
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .or_else(|_| File::open(path))?
+        .sync_all()
+}
+
+/// Waits until the entries of the directory (e.g. a renamed copy) have been written to the disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for flushing, their entries are written with the files.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `to` has the same size and modification time as `from`.
+pub fn is_identical(from: &Path, to: &Path) -> bool {
+    let (from, to) = match (std::fs::metadata(from), std::fs::metadata(to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return false,
+    };
+
+    match (from.modified(), to.modified()) {
+        (Ok(from_modified), Ok(to_modified)) => {
+            to.is_file() && from.len() == to.len() && from_modified == to_modified
+        }
+        _ => false,
+    }
+}
+
+/// Applies the settings concerning the copy's metadata once its content has been written.
+fn finish_copy(from: &Path, to: &Path, settings: &Settings) -> io::Result<()> {
+    if settings.skip_identical {
+        let modified = std::fs::metadata(from)?.modified()?;
+        // the copy may be read-only; owning it suffices on Unix, Windows needs write access
+        File::open(to)
+            .and_then(|file| file.set_modified(modified))
+            .or_else(|_| {
+                OpenOptions::new()
+                    .write(true)
+                    .open(to)?
+                    .set_modified(modified)
+            })?;
+    }
+
+    set_mode(to, settings)
+}
+
+/// Continues a copy of `from` to `to` (or its part file with `staging`) that was interrupted
+/// after `offset` bytes, appending the rest. Anything written after `offset` is discarded.
+///
+/// If the partial copy is shorter than `offset`, `offset` is not shorter than `from`, or (unless
+/// `verify_resume` is turned off) its first `offset` bytes differ from those of `from`, it is
+/// copied again from scratch.
+pub fn resume_copy(
+    from: &Path,
+    to: &Path,
+    offset: u64,
+    settings: &Settings,
+    target: &Target,
+) -> Result<CopyStatus> {
+    let dest = staged_path(to, target);
+    let written = std::fs::metadata(&dest).map(|m| m.len()).unwrap_or(0);
+    let len = std::fs::metadata(from)?.len();
+
+    if offset == 0
+        || written < offset
+        || offset >= len
+        || (settings.verify_resume && !same_prefix(&dest, from, offset)?)
+    {
+        return copy_file(from, to, settings, target);
+    }
+
+    let buffer_size = target.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE).max(1);
+    let mut reader = File::open(from)?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::with_capacity(buffer_size, reader);
+    let file = OpenOptions::new().write(true).open(&dest)?;
+    file.set_len(offset)?;
+    let mut writer = BufWriter::with_capacity(buffer_size, file);
+    writer.seek(SeekFrom::Start(offset))?;
+
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    copy_permissions(from, &dest)?;
+
+    complete_copy(from, &dest, to, settings, target)
+}
+
+/// Whether the first `len` bytes of `a` and `b` are the same.
+fn same_prefix(a: &Path, b: &Path, len: u64) -> Result<bool> {
+    let digest = |path: &Path| {
+        reader_digest(File::open(path)?.take(len))
+            .with_context(|| format!("cannot hash {}", path.display()))
+    };
+
+    Ok(digest(a)? == digest(b)?)
+}
+
+fn copy_buffered(from: &Path, to: &Path, buffer_size: usize) -> io::Result<()> {
+    let buffer_size = buffer_size.max(1);
+    let mut reader = BufReader::with_capacity(buffer_size, File::open(from)?);
+    let mut writer = BufWriter::with_capacity(buffer_size, File::create(to)?);
+
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+
+    copy_permissions(from, to)
+}
+
+/// Copies the file without moving its content through user space, using `copy_file_range`.
+/// Falls back to a regular copy if the file system doesn't support it (e.g. across devices).
+#[cfg(target_os = "linux")]
+fn copy_in_kernel(from: &Path, to: &Path) -> io::Result<()> {
+    use std::{os::unix::io::AsRawFd, ptr};
+
+    let mut reader = File::open(from)?;
+    let mut writer = File::create(to)?;
+    let mut remaining = reader.metadata()?.len();
+    let mut copied_any = false;
+
+    while remaining > 0 {
+        let len = remaining.min(isize::MAX as u64) as usize;
+        // SAFETY: both file descriptors are valid for the duration of the call and null offsets
+        // make the kernel use (and advance) the file offsets.
+        let n = unsafe {
+            libc::copy_file_range(
+                reader.as_raw_fd(),
+                ptr::null_mut(),
+                writer.as_raw_fd(),
+                ptr::null_mut(),
+                len,
+                0,
+            )
+        };
+
+        match n {
+            -1 => {
+                let e = io::Error::last_os_error();
+                let unsupported = matches!(
+                    e.raw_os_error(),
+                    Some(libc::EXDEV | libc::ENOSYS | libc::EINVAL | libc::EOPNOTSUPP)
+                );
+                if copied_any || !unsupported {
+                    return Err(e);
+                }
+
+                io::copy(&mut reader, &mut writer)?;
+                break;
+            }
+            0 => break,
+            n => {
+                remaining -= n as u64;
+                copied_any = true;
+            }
+        }
+    }
+
+    copy_permissions(from, to)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_in_kernel(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::copy(from, to).map(drop)
+}
+
+/// Clones `from` to `to` with the `FICLONE` ioctl. Fails (e.g. across file systems or on file
+/// systems without copy-on-write) without copying anything.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let reader = File::open(from)?;
+    let writer = File::create(to)?;
+    // SAFETY: both file descriptors are valid for the duration of the call.
+    if unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    copy_permissions(from, to)
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cloning files is only supported on Linux",
+    ))
+}
+
+fn copy_permissions(from: &Path, to: &Path) -> io::Result<()> {
+    let permissions = std::fs::metadata(from)?.permissions();
+
+    std::fs::set_permissions(to, permissions)
+}
+
+/// Applies `dest_mode`; not supported (and ignored) on other platforms than Unix.
+#[cfg(unix)]
+fn set_mode(to: &Path, settings: &Settings) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match settings.dest_mode {
+        Some(mode) => std::fs::set_permissions(to, std::fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_to: &Path, _settings: &Settings) -> io::Result<()> {
+    Ok(())
+}
+
+fn verify_copy(from: &Path, to: &Path) -> Result<()> {
+    let expected = file_digest(from).with_context(|| format!("cannot hash {}", from.display()))?;
+    let actual = file_digest(to).with_context(|| format!("cannot hash {}", to.display()))?;
+
+    if expected != actual {
+        bail!("verification failed, copy differs from source");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_copy_modes() {
+        let dir = TempDir::new("copy");
+        let from = dir.join("from.bin");
+        let content = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&from, &content).unwrap();
+
+        let settings = |use_copy_file_range| Settings {
+            use_copy_file_range,
+            ..Default::default()
+        };
+        let buffered = Target {
+            buffer_size: Some(4096),
+            verify: true,
+            ..Default::default()
+        };
+
+        let cases = [
+            ("plain", settings(false), Target::default()),
+            ("in_kernel", settings(true), Target::default()),
+            ("buffered", settings(false), buffered),
+        ];
+        for (name, settings, target) in &cases {
+            let to = dir.join(name);
+            let status = copy_file(&from, &to, settings, target).unwrap();
+            let expected = if target.verify {
+                CopyStatus::Verified
+            } else {
+                CopyStatus::Copied
+            };
+
+            assert_eq!(expected, status, "{}", name);
+
+            assert_eq!(content, std::fs::read(&to).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_reflink() {
+        let dir = TempDir::new("reflink");
+        let from = dir.join("from.bin");
+        std::fs::write(&from, "content").unwrap();
+        let settings = |reflink| Settings {
+            reflink,
+            ..Default::default()
+        };
+
+        // falls back to copying where the temporary directory doesn't support cloning
+        let to = dir.join("auto.bin");
+        copy_file(&from, &to, &settings(Reflink::Auto), &Target::default()).unwrap();
+        assert_eq!("content", std::fs::read_to_string(&to).unwrap());
+
+        let to = dir.join("always.bin");
+        std::fs::write(&to, "old backup").unwrap();
+        match copy_file(&from, &to, &settings(Reflink::Always), &Target::default()) {
+            Ok(_) => assert_eq!("content", std::fs::read_to_string(&to).unwrap()),
+            Err(e) => {
+                assert!(e.to_string().contains("cannot clone file"));
+                // a failed clone doesn't touch the old backup
+                assert_eq!("old backup", std::fs::read_to_string(&to).unwrap());
+            }
+        }
+        assert!(!part_path(&to).exists());
+    }
+
+    #[test]
+    fn test_fsync() {
+        let dir = TempDir::new("fsync");
+        let from = dir.join("from.txt");
+        std::fs::write(&from, "content").unwrap();
+        let settings = Settings {
+            fsync: true,
+            fsync_dirs: true,
+            dest_mode: Some(0o444),
+            ..Default::default()
+        };
+        let target = Target {
+            staging: true,
+            ..Default::default()
+        };
+
+        let to = dir.join("to.txt");
+        copy_file(&from, &to, &settings, &target).unwrap();
+        // read-only copies (dest_mode) are flushed as well
+        assert_eq!("content", std::fs::read_to_string(&to).unwrap());
+        assert!(!part_path(&to).exists());
+    }
+
+    #[test]
+    fn test_skip_identical() {
+        let dir = TempDir::new("identical");
+        let from = dir.join("from.txt");
+        let to = dir.join("to.txt");
+        std::fs::write(&from, "content").unwrap();
+
+        let settings = Settings::default();
+        let target = Target::default();
+        let copy = || copy_file(&from, &to, &settings, &target).unwrap();
+
+        assert_eq!(CopyStatus::Copied, copy());
+        assert_eq!(CopyStatus::SkippedIdentical, copy());
+
+        // same size, but modified later
+        std::fs::write(&from, "CONTENT").unwrap();
+        let modified = std::fs::metadata(&to).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(CopyStatus::Copied, copy());
+        assert_eq!("CONTENT", std::fs::read_to_string(&to).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dest_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("mode");
+        let from = dir.join("from.txt");
+        let to = dir.join("to.txt");
+        std::fs::write(&from, "content").unwrap();
+        std::fs::set_permissions(&from, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let settings = Settings {
+            dest_mode: Some(0o640),
+            ..Default::default()
+        };
+        copy_file(&from, &to, &settings, &Target::default()).unwrap();
+
+        let mode = std::fs::metadata(&to).unwrap().permissions().mode();
+        assert_eq!(0o640, mode & 0o7777);
+    }
+
+    #[test]
+    fn test_resume_copy() {
+        let dir = TempDir::new("resume");
+        let from = dir.join("from.bin");
+        let to = dir.join("to.bin");
+        let content = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&from, &content).unwrap();
+
+        let settings = Settings::default();
+        let target = Target::default();
+
+        // interrupted copy
+        std::fs::write(&to, &content[..30_000]).unwrap();
+        resume_copy(&from, &to, 30_000, &settings, &target).unwrap();
+        assert_eq!(content, std::fs::read(&to).unwrap());
+
+        // written further than recorded
+        std::fs::write(&to, &content[..50_000]).unwrap();
+        resume_copy(&from, &to, 30_000, &settings, &target).unwrap();
+        assert_eq!(content, std::fs::read(&to).unwrap());
+
+        // partial file with different content (e.g. an older backup) is copied again
+        std::fs::write(&to, vec![0xff; 30_000]).unwrap();
+        resume_copy(&from, &to, 30_000, &settings, &target).unwrap();
+        assert_eq!(content, std::fs::read(&to).unwrap());
+
+        // shorter than recorded
+        std::fs::write(&to, &content[..10_000]).unwrap();
+        resume_copy(&from, &to, 30_000, &settings, &target).unwrap();
+        assert_eq!(content, std::fs::read(&to).unwrap());
+    }
+
+    #[test]
+    fn test_part_path() {
+        assert_eq!(
+            Path::new("/backup/.photo.jpg.sortnbackup-part"),
+            part_path(Path::new("/backup/photo.jpg"))
+        );
+        // the part file of `a` isn't the backup of a file named `a.part`
+        assert_ne!(
+            Path::new("/backup/a.part"),
+            part_path(Path::new("/backup/a"))
+        );
+
+        assert!(is_part_file(".photo.jpg.sortnbackup-part"));
+        assert!(!is_part_file("photo.jpg.part"));
+        assert!(!is_part_file(".sortnbackup-part"));
+    }
+
+    #[test]
+    fn test_staging() {
+        let dir = TempDir::new("staging");
+        let from = dir.join("from.bin");
+        let to = dir.join("to.bin");
+        let part = dir.join(".to.bin.sortnbackup-part");
+        let content = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&from, &content).unwrap();
+        std::fs::write(&to, "old version").unwrap();
+
+        let settings = Settings::default();
+        let target = Target {
+            staging: true,
+            verify: true,
+            ..Default::default()
+        };
+
+        // the old version stays in place until the copy is complete
+        std::fs::write(&part, &content[..30_000]).unwrap();
+        assert_eq!("old version", std::fs::read_to_string(&to).unwrap());
+        assert_eq!(
+            CopyStatus::Verified,
+            resume_copy(&from, &to, 30_000, &settings, &target).unwrap()
+        );
+        assert_eq!(content, std::fs::read(&to).unwrap());
+        assert!(!part.exists());
+
+        std::fs::write(&from, "new").unwrap();
+        copy_file(&from, &to, &settings, &target).unwrap();
+        assert_eq!("new", std::fs::read_to_string(&to).unwrap());
+        assert!(!part.exists());
+    }
+}
@@ -1,8 +1,12 @@
-use std::{convert::TryFrom, time::SystemTime};
+use std::{
+    convert::TryFrom,
+    fmt::Display,
+    time::{Duration, SystemTime},
+};
 
 use chrono::{
     format::{Item, StrftimeItems},
-    Local,
+    DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone,
 };
 use serde::{Deserialize, Serialize};
 
@@ -31,12 +35,42 @@ impl DateTimeFormatString {
     */
 
     pub fn fmt_systime(&self, dt: SystemTime) -> String {
-        self.fmt_chrono(&dt.into())
+        let dt: DateTime<Local> = dt.into();
+
+        self.fmt_chrono(&dt)
     }
 
-    pub fn fmt_chrono(&self, dt: &chrono::DateTime<Local>) -> String {
-        dt.format(&self.0).to_string()
+    pub fn fmt_chrono<Tz>(&self, dt: &DateTime<Tz>) -> String
+    where
+        Tz: TimeZone,
+        Tz::Offset: Display,
+    {
+        let quarter = (dt.month0() / 3 + 1).to_string();
+
+        dt.format(&expand_quarter(&self.0, &quarter)).to_string()
+    }
+}
+
+/// Replaces `%q` (which chrono doesn't know) with the quarter, leaving `%%q` alone.
+fn expand_quarter(s: &str, quarter: &str) -> String {
+    let mut expanded = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('q')) => {
+                chars.next();
+                expanded.push_str(quarter);
+            }
+            ('%', Some(next)) => {
+                chars.next();
+                expanded.push('%');
+                expanded.push(next);
+            }
+            _ => expanded.push(c),
+        }
     }
+
+    expanded
 }
 
 impl TryFrom<String> for DateTimeFormatString {
@@ -52,5 +86,184 @@ impl TryFrom<String> for DateTimeFormatString {
 }
 
 fn is_valid_format_str(s: &str) -> bool {
-    StrftimeItems::new(s).all(|x| x != Item::Error)
+    StrftimeItems::new(&expand_quarter(s, "1")).all(|x| x != Item::Error)
+}
+
+/// Labels for how old a file is: the first label for files younger than the first threshold, the
+/// second one for files younger than the second threshold, ... and the last one for all older files.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "AgeBucketsDef", into = "AgeBucketsDef")]
+pub struct AgeBuckets {
+    thresholds: Vec<Duration>,
+    def: AgeBucketsDef,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgeBucketsDef {
+    thresholds: Vec<String>,
+    labels: Vec<String>,
+}
+
+impl AgeBuckets {
+    /// The label of a file modified at `modified`; files from the future count as new.
+    pub fn label(&self, modified: SystemTime, now: SystemTime) -> &str {
+        let age = now.duration_since(modified).unwrap_or_default();
+        let bucket = self.thresholds.iter().take_while(|&&t| age >= t).count();
+
+        &self.def.labels[bucket]
+    }
+}
+
+impl TryFrom<AgeBucketsDef> for AgeBuckets {
+    type Error = String;
+
+    fn try_from(def: AgeBucketsDef) -> Result<Self, Self::Error> {
+        let thresholds = def
+            .thresholds
+            .iter()
+            .map(|s| {
+                parse_duration(s)
+                    .ok_or_else(|| format!("Invalid age threshold '{}', expected e.g. 365d", s))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if thresholds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("Age thresholds must be in ascending order".to_owned());
+        }
+        if def.labels.len() != thresholds.len() + 1 {
+            return Err(format!(
+                "Expected one label more than thresholds, got {} thresholds and {} labels",
+                thresholds.len(),
+                def.labels.len()
+            ));
+        }
+
+        Ok(AgeBuckets { thresholds, def })
+    }
+}
+
+impl From<AgeBuckets> for AgeBucketsDef {
+    fn from(buckets: AgeBuckets) -> Self {
+        buckets.def
+    }
+}
+
+/// Parses a point in time given as a date ("2024-01-31", local midnight), a date / time
+/// ("2024-01-31T12:00:00" in local time or RFC 3339) or a duration before `now`
+/// ("90s", "30m", "12h", "7d", "2w").
+pub fn parse_since(s: &str, now: SystemTime) -> Result<SystemTime, String> {
+    if let Some(duration) = parse_duration(s) {
+        return now
+            .checked_sub(duration)
+            .ok_or_else(|| format!("Duration too long: '{}'", s));
+    }
+
+    let local = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_hms(0, 0, 0))
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"));
+    if let Ok(local) = local {
+        return Local
+            .from_local_datetime(&local)
+            .earliest()
+            .map(Into::into)
+            .ok_or_else(|| format!("Invalid local date / time: '{}'", s));
+    }
+
+    DateTime::parse_from_rfc3339(s)
+        .map(Into::into)
+        .map_err(|_| format!("Invalid date / time or duration: '{}'", s))
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount: u64 = s[..s.len() - 1].parse().ok()?;
+
+    Some(Duration::from_secs(amount.checked_mul(unit)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_since() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        assert_eq!(Ok(now - 7 * day), parse_since("7d", now));
+        assert_eq!(Ok(now - 2 * 7 * day), parse_since("2w", now));
+        assert_eq!(Ok(now - Duration::from_secs(90)), parse_since("90s", now));
+
+        let date: DateTime<Local> = parse_since("2024-01-31", now).unwrap().into();
+        assert_eq!("2024-01-31 00:00:00", date.format("%F %T").to_string());
+        let date: DateTime<Local> = parse_since("2024-01-31T12:30:00", now).unwrap().into();
+        assert_eq!("2024-01-31 12:30:00", date.format("%F %T").to_string());
+        assert_eq!(
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(1706704200)),
+            parse_since("2024-01-31T12:30:00Z", now)
+        );
+
+        assert!(parse_since("yesterday", now).is_err());
+        assert!(parse_since("7", now).is_err());
+        assert!(parse_since("d", now).is_err());
+    }
+
+    #[test]
+    fn test_age_buckets() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let buckets = AgeBuckets::try_from(AgeBucketsDef {
+            thresholds: vec!["30d".to_owned(), "365d".to_owned()],
+            labels: vec!["recent".to_owned(), "year".to_owned(), "old".to_owned()],
+        })
+        .unwrap();
+
+        assert_eq!("recent", buckets.label(now, now));
+        assert_eq!("recent", buckets.label(now + day, now));
+        assert_eq!("year", buckets.label(now - 30 * day, now));
+        assert_eq!("year", buckets.label(now - 364 * day, now));
+        assert_eq!("old", buckets.label(now - 365 * day, now));
+
+        let invalid = |thresholds: &[&str], labels: &[&str]| {
+            AgeBuckets::try_from(AgeBucketsDef {
+                thresholds: thresholds.iter().map(|&s| s.to_owned()).collect(),
+                labels: labels.iter().map(|&s| s.to_owned()).collect(),
+            })
+            .is_err()
+        };
+        assert!(invalid(&["1y"], &["new", "old"]));
+        assert!(invalid(&["365d", "30d"], &["a", "b", "c"]));
+        assert!(invalid(&["30d"], &["new"]));
+    }
+
+    #[test]
+    fn test_partitions() {
+        let format = |s: &str, date: &str| {
+            let dt = Local
+                .from_local_datetime(
+                    &NaiveDate::parse_from_str(date, "%F")
+                        .unwrap()
+                        .and_hms(12, 0, 0),
+                )
+                .unwrap();
+            DateTimeFormatString::try_from(s.to_owned())
+                .unwrap()
+                .fmt_chrono(&dt)
+        };
+
+        assert_eq!("2023/Q3", format("%Y/Q%q", "2023-09-30"));
+        assert_eq!("2023/Q4", format("%Y/Q%q", "2023-10-01"));
+        assert_eq!("2024-Q1 %q", format("%Y-Q%q %%q", "2024-01-01"));
+        // ISO weeks belong to the ISO year, which can differ around new year
+        assert_eq!("2023-W27", format("%G-W%V", "2023-07-05"));
+        assert_eq!("2020-W53", format("%G-W%V", "2021-01-01"));
+        assert!(DateTimeFormatString::try_from("%Y/%Q".to_owned()).is_err());
+    }
 }
@@ -0,0 +1,215 @@
+use std::{fmt::Write, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    config::{Config, Rule, COUNTER_PLACEHOLDER},
+    file_path::FilePath,
+    util::is_root_path_of,
+};
+
+/// Describes how the config handles a single file (`--test-file`): the decision of every file
+/// group in order, the rule used and where the file would end up. Nothing is indexed or copied.
+///
+/// Without `source`, the enabled source with the longest path containing the file is used.
+pub fn explain(config: &Config, source: Option<&str>, file: &Path) -> Result<String> {
+    let file = file
+        .canonicalize()
+        .map_err(|e| anyhow!("cannot find {}: {}", file.display(), e))?;
+    let contains = |path: &Path| {
+        path.canonicalize()
+            .is_ok_and(|path| is_root_path_of(&file, &path))
+    };
+
+    let (src_name, src) = match source {
+        Some(name) => {
+            let src = config
+                .sources
+                .get(name)
+                .ok_or_else(|| anyhow!("Unknown source: '{}'", name))?;
+            if !contains(&src.path) {
+                bail!("{} is not in source '{}'", file.display(), name);
+            }
+
+            (name, src)
+        }
+        None => config
+            .sources
+            .iter()
+            .filter(|(_, src)| !src.disabled && contains(&src.path))
+            .max_by_key(|(_, src)| src.path.canonicalize().map(|p| p.as_os_str().len()).ok())
+            .map(|(name, src)| (name.as_str(), src))
+            .ok_or_else(|| anyhow!("{} is not in any enabled source", file.display()))?,
+    };
+    let sub_path = file.strip_prefix(src.path.canonicalize()?)?.to_owned();
+    let mut fp = FilePath::new(&src.path, &sub_path);
+
+    let mut out = String::new();
+    writeln!(out, "File: {} (source '{}')", sub_path.display(), src_name)?;
+
+    let ignored_name = sub_path
+        .iter()
+        .find(|name| config.settings.is_ignored_file_name(name));
+    if let Some(name) = ignored_name {
+        writeln!(
+            out,
+            "Note: {:?} is in ignore_file_names, so this file is not indexed",
+            name
+        )?;
+    }
+    let apple_double = sub_path
+        .file_name()
+        .filter(|name| file.is_file() && config.settings.is_skipped_apple_double(name));
+    if let Some(name) = apple_double {
+        writeln!(
+            out,
+            "Note: {:?} is an AppleDouble file and skip_apple_double is set, so it is not indexed",
+            name
+        )?;
+    }
+    if let Some(path) = src
+        .ignore_paths
+        .iter()
+        .find(|ignored| sub_path.starts_with(ignored))
+    {
+        writeln!(
+            out,
+            "Note: {} is in the source's ignore_paths, so this file is not indexed",
+            path.display()
+        )?;
+    }
+
+    writeln!(out, "File groups:")?;
+    let mut chosen = None;
+    for (name, group) in config.file_groups.iter() {
+        let decision = if !group.sources.includes(src_name) {
+            "not for this source"
+        } else if !group.filter.matches(&mut fp) {
+            "no match"
+        } else if chosen.is_some() {
+            "match (not used, an earlier group matched)"
+        } else {
+            chosen = Some((name, &group.rule));
+            "match"
+        };
+        writeln!(out, "  {}: {}", name, decision)?;
+    }
+
+    let rule = match chosen {
+        Some((name, rule)) => {
+            writeln!(out, "Rule: {} (group '{}')", rule, name)?;
+            rule
+        }
+        None if fp.full_path.is_dir() => {
+            writeln!(out, "Rule: traverse (no group matched a folder)")?;
+            &Rule::Traverse
+        }
+        None => {
+            writeln!(out, "Rule: ignore (no group matched)")?;
+            &Rule::Ignore
+        }
+    };
+
+    if let Some(size) = fp.metadata().map(|meta| meta.len()) {
+        if !rule.accepts_size(size) {
+            writeln!(
+                out,
+                "Note: its size ({} bytes) is outside the min_size / max_size of the rule, so it is skipped",
+                size
+            )?;
+        }
+    }
+    match config.destination(rule, src_name, &mut fp) {
+        Some(Ok(to)) => {
+            let label = match rule {
+                Rule::LogFile { .. } => "Log file",
+                _ => "Destination",
+            };
+            // counters are only assigned once all files are known
+            let to = to
+                .display()
+                .to_string()
+                .replace(COUNTER_PLACEHOLDER, "{counter}");
+            writeln!(out, "{}: {}", label, to)?;
+        }
+        Some(Err(e)) => writeln!(out, "Destination: error: {:#}", e)?,
+        None => {}
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_explain() {
+        let dir = TempDir::new("explain");
+        std::fs::create_dir_all(dir.join("home/Pictures")).unwrap();
+        std::fs::write(dir.join("home/Pictures/a.jpg"), "").unwrap();
+        std::fs::write(dir.join("home/notes.txt"), "").unwrap();
+        let yaml = format!(
+            r#"
+settings:
+  file_size_style: binary
+sources:
+  home:
+    path: "{0}/home"
+targets:
+  nas: "{0}/nas"
+file_groups:
+  documents:
+    sources: all
+    filter:
+      has_extension: [txt]
+    rule:
+      copy_exact:
+        target: nas
+  photos:
+    sources:
+      except: [home]
+    filter:
+      has_extension: [jpg]
+    rule: ignore
+  images:
+    sources: all
+    filter:
+      has_extension: [jpg, png]
+    rule:
+      copy_to:
+        target: nas
+        path:
+          - file_name: Images
+          - file_name_with_extension
+  everything:
+    sources: all
+    filter: catch_all
+    rule: ignore
+"#,
+            dir.display()
+        );
+        let config = Config::from_reader(yaml.as_bytes()).unwrap();
+
+        let out = explain(&config, None, &dir.join("home/Pictures/a.jpg")).unwrap();
+        assert_eq!(
+            format!(
+                "File: Pictures/a.jpg (source 'home')
+File groups:
+  documents: no match
+  photos: not for this source
+  images: match
+  everything: match (not used, an earlier group matched)
+Rule: copy_to target 'nas' (group 'images')
+Destination: {}/nas/Images/a.jpg
+",
+                dir.display()
+            ),
+            out
+        );
+
+        assert!(explain(&config, Some("work"), &dir.join("home/notes.txt")).is_err());
+        assert!(explain(&config, None, &dir).is_err());
+    }
+}
@@ -1,6 +1,16 @@
-use std::{fs::Metadata, path::PathBuf, sync::Arc};
+use std::{
+    fs::{File, Metadata},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::img::ImageMetadata;
+use md5::Digest;
+
+use crate::{
+    img::ImageMetadata,
+    util::{dir_size, file_digest},
+};
 
 enum Lazy<T> {
     Some(T),
@@ -43,11 +53,16 @@ where
 }
 
 pub struct FilePath {
+    #[allow(dead_code)]
     pub source_path: PathBuf,
     pub path: PathBuf,
     pub full_path: PathBuf,
     metadata: Lazy<Arc<Metadata>>,
     img_metadata: Lazy<ImageMetadata>,
+    digest: Lazy<Digest>,
+    dir_size: Lazy<u64>,
+    content_type: Lazy<&'static str>,
+    text_encoding: Lazy<&'static str>,
 }
 
 impl FilePath {
@@ -58,6 +73,10 @@ impl FilePath {
     fn new_internal(source_path: PathBuf, path: PathBuf) -> Self {
         let full_path = source_path.join(&path);
         let full_path2 = full_path.clone();
+        let full_path3 = full_path.clone();
+        let full_path4 = full_path.clone();
+        let full_path5 = full_path.clone();
+        let full_path6 = full_path.clone();
 
         FilePath {
             source_path,
@@ -65,6 +84,10 @@ impl FilePath {
             full_path: full_path.clone(),
             metadata: Lazy::new(move || std::fs::metadata(&full_path).ok().map(Arc::new)),
             img_metadata: Lazy::new(move || ImageMetadata::for_path(&full_path2)),
+            digest: Lazy::new(move || file_digest(&full_path3).ok()),
+            dir_size: Lazy::new(move || dir_size(&full_path4).ok()),
+            content_type: Lazy::new(move || content_type(&full_path5)),
+            text_encoding: Lazy::new(move || text_encoding(&full_path6)),
         }
     }
 
@@ -75,4 +98,100 @@ impl FilePath {
     pub fn img_metadata(&mut self) -> Option<ImageMetadata> {
         self.img_metadata.get()
     }
+
+    /// The md5 hash of the file's content.
+    pub fn digest(&mut self) -> Option<Digest> {
+        self.digest.get()
+    }
+
+    /// The recursive size of the directory; `None` for files.
+    pub fn dir_size(&mut self) -> Option<u64> {
+        self.dir_size.get()
+    }
+
+    /// The MIME type detected from the file's first bytes, e.g. "image/heif".
+    pub fn content_type(&mut self) -> Option<&'static str> {
+        self.content_type.get()
+    }
+
+    /// The encoding of a text file detected from its first bytes, see [`detect_text_encoding`].
+    pub fn text_encoding(&mut self) -> Option<&'static str> {
+        self.text_encoding.get()
+    }
+}
+
+/// Magic numbers of all types known to `infer` are within the first bytes.
+const CONTENT_TYPE_PREFIX_LEN: u64 = 8192;
+
+fn content_type(path: &Path) -> Option<&'static str> {
+    let mut prefix = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(CONTENT_TYPE_PREFIX_LEN)
+        .read_to_end(&mut prefix)
+        .ok()?;
+
+    infer::get(&prefix).map(|t| t.mime_type())
+}
+
+/// Enough text to tell the encodings apart, while reading only a block of big files.
+const TEXT_ENCODING_PREFIX_LEN: u64 = 4096;
+
+fn text_encoding(path: &Path) -> Option<&'static str> {
+    let file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut prefix = Vec::new();
+    file.take(TEXT_ENCODING_PREFIX_LEN)
+        .read_to_end(&mut prefix)
+        .ok()?;
+
+    detect_text_encoding(&prefix, len > TEXT_ENCODING_PREFIX_LEN)
+}
+
+/// The encoding of text starting with `prefix` (`truncated` if the file continues): by its byte
+/// order mark `utf-8-bom`, `utf-16le`, `utf-16be`, `utf-32le` or `utf-32be`, otherwise `ascii`,
+/// `utf-8`, `utf-16le` / `utf-16be` (if every other byte is zero) or `legacy` (8-bit text that
+/// isn't UTF-8, e.g. Windows-1252). `None` for empty and binary files.
+pub fn detect_text_encoding(prefix: &[u8], truncated: bool) -> Option<&'static str> {
+    // UTF-32 LE starts like UTF-16 LE, so longer marks first
+    const BOMS: &[(&[u8], &str)] = &[
+        (&[0xff, 0xfe, 0, 0], "utf-32le"),
+        (&[0, 0, 0xfe, 0xff], "utf-32be"),
+        (&[0xef, 0xbb, 0xbf], "utf-8-bom"),
+        (&[0xff, 0xfe], "utf-16le"),
+        (&[0xfe, 0xff], "utf-16be"),
+    ];
+    if let Some((_, encoding)) = BOMS.iter().find(|(bom, _)| prefix.starts_with(bom)) {
+        return Some(encoding);
+    }
+    if prefix.is_empty() {
+        return None;
+    }
+
+    if prefix.contains(&0) {
+        // mostly ASCII text in UTF-16 has a zero in the high byte of every character
+        let zeros = |offset: usize| prefix.iter().skip(offset).step_by(2).all(|&b| b == 0);
+        return match (zeros(0), zeros(1)) {
+            (false, true) if prefix.len() >= 2 => Some("utf-16le"),
+            (true, false) if prefix.len() >= 2 => Some("utf-16be"),
+            _ => None,
+        };
+    }
+    // control characters other than whitespace and escape are a sign of binary data
+    if prefix
+        .iter()
+        .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+    {
+        return None;
+    }
+
+    if prefix.is_ascii() {
+        return Some("ascii");
+    }
+    match std::str::from_utf8(prefix) {
+        Ok(_) => Some("utf-8"),
+        // a character cut off at the end of the prefix
+        Err(e) if truncated && e.error_len().is_none() => Some("utf-8"),
+        Err(_) => Some("legacy"),
+    }
 }
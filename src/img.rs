@@ -1,10 +1,17 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
 
-use chrono::{Local, TimeZone};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone, Timelike};
 use exif::{Exif, Field, In, Tag, Value};
 use immeta::Dimensions;
 use serde::{Deserialize, Serialize};
 
+use crate::metadata_cache;
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct ImageDimensions {
     pub width: u32,
@@ -19,6 +26,20 @@ impl ImageDimensions {
     pub fn ensure_max(&self, max: u32) -> bool {
         self.width <= max && self.height <= max
     }
+
+    /// Whether the width lies within `min..=max`; a missing bound doesn't constrain it.
+    pub fn ensure_width(&self, min: Option<u32>, max: Option<u32>) -> bool {
+        within(self.width, min, max)
+    }
+
+    /// Whether the height lies within `min..=max`; a missing bound doesn't constrain it.
+    pub fn ensure_height(&self, min: Option<u32>, max: Option<u32>) -> bool {
+        within(self.height, min, max)
+    }
+}
+
+fn within(value: u32, min: Option<u32>, max: Option<u32>) -> bool {
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
 }
 
 impl From<immeta::Dimensions> for ImageDimensions {
@@ -30,12 +51,18 @@ impl From<immeta::Dimensions> for ImageDimensions {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ImageMetadata {
-    pub date_time: Option<chrono::DateTime<Local>>,
+    pub date_time: Option<DateTime<FixedOffset>>,
+    #[allow(dead_code)]
     pub camera_make: Option<String>,
+    #[allow(dead_code)]
     pub camera_model: Option<String>,
     pub dimensions: ImageDimensions,
+    /// EXIF orientation, 1 (upright) to 8; 6 and 8 are portrait shots of a landscape sensor.
+    pub orientation: Option<u16>,
+    /// Whether the flash fired (from the EXIF flash tag).
+    pub flash_fired: Option<bool>,
 }
 
 impl ImageMetadata {
@@ -45,61 +72,258 @@ impl ImageMetadata {
             date_time: None,
             camera_make: None,
             camera_model: None,
+            orientation: None,
+            flash_fired: None,
         })
     }
 
+    /// With `cache_dir`, files that haven't changed since they were last read aren't read again.
     pub fn for_path(path: &Path) -> Option<Self> {
-        Self::for_path_inner(path).ok()
+        metadata_cache::get_or_read(path, || Self::for_path_inner(path).ok())
+    }
+
+    /// Dimensions of HEIF images (HEIC, AVIF); immeta doesn't support them.
+    fn from_heif(path: &Path) -> anyhow::Result<Self> {
+        Ok(ImageMetadata {
+            dimensions: heif_dimensions(&mut File::open(path)?)
+                .ok_or_else(|| anyhow::anyhow!("no HEIF image dimensions"))?,
+            date_time: None,
+            camera_make: None,
+            camera_model: None,
+            orientation: None,
+            flash_fired: None,
+        })
+    }
+
+    fn from_container(path: &Path) -> anyhow::Result<Self> {
+        Self::from_heif(path).or_else(|_| Self::from_immeta(path))
     }
 
     fn for_path_inner(path: &Path) -> anyhow::Result<Self> {
         let file = File::open(path)?;
         let exif = match exif::Reader::new().read_from_container(&mut BufReader::new(&file)) {
             Ok(x) => x,
-            Err(exif::Error::NotFound(_)) => return Self::from_immeta(path),
+            Err(exif::Error::NotFound(_)) => return Self::from_container(path),
             Err(e) => return Err(e.into()),
         };
 
         let dimensions = match get_exif_dimensions(&exif) {
             Some(d) => d,
-            None => Self::from_immeta(path).map(|m| m.dimensions)?,
+            None => Self::from_container(path).map(|m| m.dimensions)?,
         };
 
         Ok(ImageMetadata {
             dimensions,
-            date_time: get_date_time(
-                exif.get_field(Tag::DateTime, In::PRIMARY)
-                    .or(exif.get_field(Tag::DateTimeOriginal, In::PRIMARY))
-                    .or(exif.get_field(Tag::DateTimeDigitized, In::PRIMARY)),
-            ),
+            date_time: get_exif_date_time(&exif),
             camera_make: get_string(exif.get_field(Tag::Make, In::PRIMARY)),
             camera_model: get_string(exif.get_field(Tag::Model, In::PRIMARY)),
+            orientation: get_u16(exif.get_field(Tag::Orientation, In::PRIMARY)),
+            flash_fired: get_u16(exif.get_field(Tag::Flash, In::PRIMARY))
+                .map(|flash| flash & 1 == 1),
         })
     }
 }
 
 fn get_exif_dimensions(exif: &Exif) -> Option<ImageDimensions> {
-    let width = exif
-        .get_field(Tag::ImageWidth, In::PRIMARY)?
-        .value
-        .get_uint(0)?;
-    let height = exif
-        .get_field(Tag::ImageLength, In::PRIMARY)?
-        .value
-        .get_uint(0)?;
+    let get = |tag| exif.get_field(tag, In::PRIMARY)?.value.get_uint(0);
+
+    // phones (e.g. in HEIC) often only write the dimensions of the compressed image
+    let (width, height) = match (get(Tag::ImageWidth), get(Tag::ImageLength)) {
+        (Some(width), Some(height)) => (width, height),
+        _ => (get(Tag::PixelXDimension)?, get(Tag::PixelYDimension)?),
+    };
 
     Some(ImageDimensions { width, height })
 }
 
-fn get_date_time(value: Option<&Field>) -> Option<chrono::DateTime<Local>> {
-    Local
-        .datetime_from_str(get_str(value)?, "%Y:%m:%d %H:%M:%S")
-        .ok()
+/// Brands (in the `ftyp` box) of HEIF files, including AVIF.
+const HEIF_BRANDS: [&[u8; 4]; 10] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+/// Reads the largest image spatial extents (`ispe`) property of a HEIF file. Besides the
+/// primary image, there are thumbnails and the tiles of grid images, which are all smaller.
+fn heif_dimensions(reader: &mut (impl Read + Seek)) -> Option<ImageDimensions> {
+    let (kind, ftyp) = read_box(reader)?;
+    if &kind != b"ftyp"
+        || !ftyp
+            .chunks(4)
+            .any(|brand| HEIF_BRANDS.iter().any(|b| b[..] == *brand))
+    {
+        return None;
+    }
+
+    let meta = loop {
+        let (kind, content) = read_box(reader)?;
+        if &kind == b"meta" {
+            break content;
+        }
+    };
+
+    // `meta` and `ispe` are full boxes, starting with version and flags
+    let iprp = find_box(meta.get(4..)?, b"iprp")?;
+    let ipco = find_box(iprp, b"ipco")?;
+    boxes(ipco)
+        .filter(|(kind, _)| kind == b"ispe")
+        .filter_map(|(_, ispe)| {
+            Some(ImageDimensions {
+                width: u32::from_be_bytes(ispe.get(4..8)?.try_into().ok()?),
+                height: u32::from_be_bytes(ispe.get(8..12)?.try_into().ok()?),
+            })
+        })
+        .max_by_key(|d| u64::from(d.width) * u64::from(d.height))
+}
+
+/// Boxes larger than this aren't read into memory (only `ftyp` and `meta` are read at all).
+const MAX_HEIF_BOX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads the next top-level box, skipping the content of boxes other than `ftyp` and `meta`.
+fn read_box(reader: &mut (impl Read + Seek)) -> Option<([u8; 4], Vec<u8>)> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).ok()?;
+    let kind: [u8; 4] = header[4..].try_into().ok()?;
+    let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().ok()?) {
+        1 => {
+            let mut size = [0; 8];
+            reader.read_exact(&mut size).ok()?;
+            (u64::from_be_bytes(size), 16)
+        }
+        size => (u64::from(size), 8),
+    };
+    let len = size.checked_sub(header_len)?;
+
+    if &kind == b"ftyp" || &kind == b"meta" {
+        if len > MAX_HEIF_BOX_SIZE {
+            return None;
+        }
+        let mut content = vec![0; len as usize];
+        reader.read_exact(&mut content).ok()?;
+
+        Some((kind, content))
+    } else {
+        reader.seek(SeekFrom::Current(len.try_into().ok()?)).ok()?;
+
+        Some((kind, Vec::new()))
+    }
+}
+
+/// The boxes in `data` as `(type, content)` (small boxes only, see `read_box`).
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let kind = data.get(4..8)?.try_into().ok()?;
+        let content = data.get(8..size)?;
+        data = &data[size..];
+
+        Some((kind, content))
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(k, _)| k == kind)
+        .map(|(_, content)| content)
+}
+
+/// Date / time tags in order of preference, together with their sub-second and offset companions.
+const DATE_TIME_TAGS: [(Tag, Tag, Tag); 3] = [
+    (Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime),
+    (
+        Tag::DateTimeOriginal,
+        Tag::SubSecTimeOriginal,
+        Tag::OffsetTimeOriginal,
+    ),
+    (
+        Tag::DateTimeDigitized,
+        Tag::SubSecTimeDigitized,
+        Tag::OffsetTimeDigitized,
+    ),
+];
+
+fn get_exif_date_time(exif: &Exif) -> Option<DateTime<FixedOffset>> {
+    let (date_time, sub_sec, offset) =
+        DATE_TIME_TAGS
+            .iter()
+            .find_map(|&(date_time, sub_sec, offset)| {
+                Some((
+                    exif.get_field(date_time, In::PRIMARY)?,
+                    exif.get_field(sub_sec, In::PRIMARY),
+                    exif.get_field(offset, In::PRIMARY),
+                ))
+            })?;
+
+    get_date_time(Some(date_time), sub_sec, offset)
+}
+
+fn get_date_time(
+    value: Option<&Field>,
+    sub_sec: Option<&Field>,
+    offset: Option<&Field>,
+) -> Option<DateTime<FixedOffset>> {
+    parse_date_time(get_str(value)?, get_str(sub_sec), get_str(offset))
+}
+
+/// Parses an EXIF date/time string, treating malformed values as "no date / time"
+/// so the file falls through to rules that don't require it.
+///
+/// Sub-second and offset information is optional; a malformed companion value is ignored and
+/// without an offset the date / time is interpreted in the local time zone.
+fn parse_date_time(
+    s: &str,
+    sub_sec: Option<&str>,
+    offset: Option<&str>,
+) -> Option<DateTime<FixedOffset>> {
+    let naive = match NaiveDateTime::parse_from_str(s, "%Y:%m:%d %H:%M:%S") {
+        Ok(dt) => dt,
+        Err(e) => {
+            eprintln!("Ignoring malformed EXIF date/time '{}': {}", s, e);
+
+            return None;
+        }
+    };
+    let naive = sub_sec
+        .and_then(parse_sub_sec)
+        .and_then(|nanos| naive.with_nanosecond(nanos))
+        .unwrap_or(naive);
+
+    match offset.and_then(parse_offset) {
+        Some(offset) => offset.from_local_datetime(&naive).single(),
+        None => Local
+            .from_local_datetime(&naive)
+            .single()
+            .map(|dt| dt.with_timezone(dt.offset())),
+    }
+}
+
+/// Parses the digits of a `SubSecTime*` tag (e.g. "042" = 42ms) into nanoseconds.
+fn parse_sub_sec(s: &str) -> Option<u32> {
+    let digits = s.trim_end_matches([' ', '\0']);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let digits = &digits[..digits.len().min(9)];
+    let nanos: u32 = digits.parse().ok()?;
+
+    Some(nanos * 10u32.pow(9 - digits.len() as u32))
+}
+
+/// Parses an `OffsetTime*` tag in the form "+HH:MM" / "-HH:MM".
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let (hours, minutes) = s[1..].split_once(':')?;
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+
+    FixedOffset::east_opt(sign * seconds)
 }
 
 fn get_str(value: Option<&Field>) -> Option<&str> {
     match &value.as_ref()?.value {
-        Value::Ascii(v) => std::str::from_utf8(v.get(0)?).ok(),
+        Value::Ascii(v) => std::str::from_utf8(v.first()?).ok(),
         _ => None,
     }
 }
@@ -107,3 +331,117 @@ fn get_str(value: Option<&Field>) -> Option<&str> {
 fn get_string(value: Option<&Field>) -> Option<String> {
     get_str(value).map(ToOwned::to_owned)
 }
+
+fn get_u16(value: Option<&Field>) -> Option<u16> {
+    value?.value.get_uint(0)?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dimensions() {
+        let landscape = ImageDimensions {
+            width: 1920,
+            height: 1080,
+        };
+
+        assert!(landscape.ensure_min(1080));
+        assert!(!landscape.ensure_min(1920));
+        assert!(landscape.ensure_width(Some(1920), None));
+        assert!(!landscape.ensure_height(Some(1920), None));
+        assert!(landscape.ensure_height(None, Some(1080)));
+        assert!(!landscape.ensure_width(None, Some(1080)));
+        assert!(landscape.ensure_width(None, None));
+    }
+
+    #[test]
+    fn test_heif_dimensions() {
+        fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+            let mut b = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(kind);
+            b.extend_from_slice(content);
+            b
+        }
+        let ispe = |width: u32, height: u32| {
+            let mut content = vec![0; 4];
+            content.extend_from_slice(&width.to_be_bytes());
+            content.extend_from_slice(&height.to_be_bytes());
+            mp4_box(b"ispe", &content)
+        };
+
+        let ipco = [ispe(512, 512), ispe(4032, 3024), ispe(320, 240)].concat();
+        let mut meta = vec![0; 4];
+        meta.extend(mp4_box(b"hdlr", &[0; 20]));
+        meta.extend(mp4_box(b"iprp", &mp4_box(b"ipco", &ipco)));
+        let file = [
+            mp4_box(b"ftyp", b"heic\0\0\0\0mif1heic"),
+            mp4_box(b"free", &[0; 10]),
+            mp4_box(b"meta", &meta),
+            mp4_box(b"mdat", &[0; 100]),
+        ]
+        .concat();
+
+        assert_eq!(
+            Some(ImageDimensions {
+                width: 4032,
+                height: 3024
+            }),
+            heif_dimensions(&mut std::io::Cursor::new(&file))
+        );
+
+        let mp4 = [
+            mp4_box(b"ftyp", b"isom\0\0\0\0isom"),
+            mp4_box(b"meta", &meta),
+        ]
+        .concat();
+        assert_eq!(None, heif_dimensions(&mut std::io::Cursor::new(&mp4)));
+        assert_eq!(
+            None,
+            heif_dimensions(&mut std::io::Cursor::new(&file[..60]))
+        );
+    }
+
+    #[test]
+    fn test_get_u16() {
+        let field = |value| Field {
+            tag: Tag::Flash,
+            ifd_num: In::PRIMARY,
+            value,
+        };
+
+        assert_eq!(Some(0x19), get_u16(Some(&field(Value::Short(vec![0x19])))));
+        assert_eq!(None, get_u16(Some(&field(Value::Short(vec![])))));
+        assert_eq!(None, get_u16(Some(&field(Value::Long(vec![70_000])))));
+        assert_eq!(None, get_u16(None));
+    }
+
+    #[test]
+    fn test_malformed_date_time() {
+        let field = Field {
+            tag: Tag::DateTime,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"2021:13:45 99:00:00".to_vec()]),
+        };
+
+        assert_eq!(None, get_date_time(Some(&field), None, None));
+        assert_eq!(None, parse_date_time("    :  :     :  :  ", None, None));
+        assert!(parse_date_time("2021:03:14 15:09:26", None, None).is_some());
+    }
+
+    #[test]
+    fn test_sub_sec_and_offset() {
+        let dt = parse_date_time("2021:03:14 15:09:26", Some("042"), Some("+02:00")).unwrap();
+
+        assert_eq!("2021-03-14T15:09:26.042+02:00", dt.to_rfc3339());
+
+        let first = parse_date_time("2021:03:14 15:09:26", Some("1"), Some("-05:30")).unwrap();
+        let second = parse_date_time("2021:03:14 15:09:26", Some("2"), Some("-05:30")).unwrap();
+        assert!(first < second);
+
+        // malformed companion tags are ignored
+        let dt = parse_date_time("2021:03:14 15:09:26", Some("ab"), Some("+0200")).unwrap();
+        assert_eq!(0, dt.nanosecond());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use walkdir::WalkDir;
+
+use crate::util::file_digest;
+
+/// Completeness of a target after copying: the files expected from the index compared with the
+/// files actually found below the target's path.
+#[derive(Debug, Default)]
+pub struct TargetReport {
+    pub expected_files: usize,
+    pub expected_bytes: u64,
+    pub present_files: usize,
+    pub present_bytes: u64,
+    pub missing: BTreeSet<PathBuf>,
+    /// Expected files that are present, but of another size.
+    pub mismatched: BTreeSet<PathBuf>,
+    /// Files below the target's path that are not part of the index, e.g. from earlier runs.
+    pub unexpected: BTreeSet<PathBuf>,
+}
+
+/// Walks `root` and compares it with the `expected` destinations and their sizes.
+pub fn check_target(root: &Path, expected: &HashMap<PathBuf, u64>) -> TargetReport {
+    let mut report = TargetReport {
+        expected_files: expected.len(),
+        expected_bytes: expected.values().sum(),
+        missing: expected.keys().cloned().collect(),
+        ..Default::default()
+    };
+
+    for entry in WalkDir::new(root).into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.into_path();
+        if report.missing.remove(&path) {
+            let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            report.present_files += 1;
+            report.present_bytes += size;
+            if size != expected[&path] {
+                report.mismatched.insert(path);
+            }
+        } else {
+            report.unexpected.insert(path);
+        }
+    }
+
+    report
+}
+
+/// Files (by path relative to the roots) in only one of two trees or of different sizes in both,
+/// e.g. of two mirrors.
+#[derive(Debug, Default)]
+pub struct TreeDiff {
+    pub only_in_a: BTreeSet<PathBuf>,
+    pub only_in_b: BTreeSet<PathBuf>,
+    pub different_size: BTreeSet<PathBuf>,
+}
+
+/// Walks both trees and compares the files in them by relative path and size.
+pub fn compare_trees(a: &Path, b: &Path) -> TreeDiff {
+    let files = |root: &Path| {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| {
+                let size = entry.metadata().ok()?.len();
+                let path = entry.path().strip_prefix(root).ok()?.to_owned();
+
+                Some((path, size))
+            })
+            .collect::<HashMap<_, _>>()
+    };
+    let (a, mut b) = rayon::join(|| files(a), || files(b));
+
+    let mut diff = TreeDiff::default();
+    for (path, size) in a {
+        match b.remove(&path) {
+            Some(other) if other != size => {
+                diff.different_size.insert(path);
+            }
+            Some(_) => {}
+            None => {
+                diff.only_in_a.insert(path);
+            }
+        }
+    }
+    diff.only_in_b = b.into_keys().collect();
+
+    diff
+}
+
+/// The destinations of `(from, to)` pairs whose content differs from their original (by md5
+/// hash), or that cannot be read.
+pub fn differing_content(files: Vec<(&Path, &Path)>) -> BTreeSet<PathBuf> {
+    files
+        .into_par_iter()
+        .filter(|(from, to)| match (file_digest(from), file_digest(to)) {
+            (Ok(expected), Ok(actual)) => expected != actual,
+            _ => true,
+        })
+        .map(|(_, to)| to.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_check_target() {
+        let root = TempDir::new("integrity");
+        std::fs::create_dir_all(root.join("Docs")).unwrap();
+        std::fs::write(root.join("Docs/a.txt"), "abc").unwrap();
+        std::fs::write(root.join("old.txt"), "old").unwrap();
+        std::fs::write(root.join("c.txt"), "c").unwrap();
+
+        let expected = vec![
+            (root.join("Docs/a.txt"), 3),
+            (root.join("b.jpg"), 5),
+            (root.join("c.txt"), 2),
+        ]
+        .into_iter()
+        .collect();
+
+        let report = check_target(&root, &expected);
+
+        assert_eq!(3, report.expected_files);
+        assert_eq!(10, report.expected_bytes);
+        assert_eq!(2, report.present_files);
+        assert_eq!(4, report.present_bytes);
+        assert_eq!(
+            vec![&root.join("c.txt")],
+            report.mismatched.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![&root.join("b.jpg")],
+            report.missing.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![&root.join("old.txt")],
+            report.unexpected.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_compare_trees() {
+        let root = TempDir::new("mirrors");
+        for (file, content) in [
+            ("a/Photos/1.jpg", "1"),
+            ("b/Photos/1.jpg", "1"),
+            ("a/Photos/2.jpg", "2"),
+            ("b/notes.txt", "new"),
+            ("a/notes.txt", "old notes"),
+            ("b/Docs/letter.pdf", "pdf"),
+        ] {
+            std::fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            std::fs::write(root.join(file), content).unwrap();
+        }
+        std::fs::create_dir_all(root.join("a/Empty")).unwrap();
+
+        let diff = compare_trees(&root.join("a"), &root.join("b"));
+
+        let paths = |paths: &BTreeSet<PathBuf>| paths.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(vec![PathBuf::from("Photos/2.jpg")], paths(&diff.only_in_a));
+        assert_eq!(
+            vec![PathBuf::from("Docs/letter.pdf")],
+            paths(&diff.only_in_b)
+        );
+        assert_eq!(
+            vec![PathBuf::from("notes.txt")],
+            paths(&diff.different_size)
+        );
+    }
+
+    #[test]
+    fn test_differing_content() {
+        let root = TempDir::new("content");
+        for (name, content) in [
+            ("a", "abc"),
+            ("a.copy", "abc"),
+            ("b", "abc"),
+            ("b.copy", "abd"),
+        ] {
+            std::fs::write(root.join(name), content).unwrap();
+        }
+        let (a, a_copy) = (root.join("a"), root.join("a.copy"));
+        let (b, b_copy) = (root.join("b"), root.join("b.copy"));
+        let missing = root.join("missing");
+
+        let differing = differing_content(vec![(&a, &a_copy), (&b, &b_copy), (&a, &missing)]);
+
+        assert_eq!(
+            vec![&b_copy, &missing],
+            differing.iter().collect::<Vec<_>>()
+        );
+    }
+}
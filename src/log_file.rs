@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+
+use crate::config::{LogColumn, LogFormat, LogMode};
+
+/// One entry of a log file written by the `log_file` rule.
+pub struct LogEntry<'a> {
+    pub path: String,
+    pub size: Option<u64>,
+    pub modified: Option<String>,
+    pub group: &'a str,
+    pub source: &'a str,
+}
+
+impl LogEntry<'_> {
+    fn value(&self, column: LogColumn) -> serde_json::Value {
+        match column {
+            LogColumn::Path => self.path.clone().into(),
+            LogColumn::Size => self.size.into(),
+            LogColumn::Modified => self.modified.clone().into(),
+            LogColumn::Group => self.group.into(),
+            LogColumn::Source => self.source.into(),
+        }
+    }
+
+    fn format(&self, format: LogFormat, columns: &[LogColumn]) -> String {
+        match format {
+            LogFormat::Line => self.path.clone(),
+            LogFormat::Csv => columns
+                .iter()
+                .map(|&column| match self.value(column) {
+                    serde_json::Value::String(s) => csv_field(&s),
+                    serde_json::Value::Null => String::new(),
+                    value => value.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            LogFormat::Json => serde_json::Value::Object(
+                columns
+                    .iter()
+                    .map(|&column| (column.name().to_owned(), self.value(column)))
+                    .collect(),
+            )
+            .to_string(),
+        }
+    }
+}
+
+/// Quotes `s` for use as a CSV field if necessary.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Log files opened during indexing, shared between sources.
+///
+/// Sources are indexed in parallel, so each file is guarded by its own lock and every entry is
+/// written with a single call while holding it; entries of different sources never interleave.
+#[derive(Default)]
+pub struct LogFiles {
+    files: Mutex<HashMap<PathBuf, Arc<Mutex<File>>>>,
+}
+
+impl LogFiles {
+    pub fn write(
+        &self,
+        path: &Path,
+        format: LogFormat,
+        columns: &[LogColumn],
+        mode: LogMode,
+        entry: &LogEntry,
+    ) -> Result<()> {
+        let file = self.open(path, format, columns, mode)?;
+        let line = format!("{}\n", entry.format(format, columns));
+
+        let result = file.lock().write_all(line.as_bytes());
+
+        result.with_context(|| format!("Failed to write to log file {}", path.display()))
+    }
+
+    fn open(
+        &self,
+        path: &Path,
+        format: LogFormat,
+        columns: &[LogColumn],
+        mode: LogMode,
+    ) -> Result<Arc<Mutex<File>>> {
+        let mut files = self.files.lock();
+        if let Some(file) = files.get(path) {
+            return Ok(file.clone());
+        }
+
+        // files are opened once per run, so truncating here only discards previous runs
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(mode == LogMode::Append)
+            .write(true)
+            .truncate(mode == LogMode::Overwrite)
+            .open(path)
+            .with_context(|| format!("Failed to open log file at {}", path.display()))?;
+
+        let is_empty = file.metadata().map(|m| m.len() == 0).unwrap_or(false);
+        if format == LogFormat::Csv && is_empty {
+            let header = columns
+                .iter()
+                .map(|column| column.name())
+                .collect::<Vec<_>>()
+                .join(",");
+            writeln!(file, "{}", header)
+                .with_context(|| format!("Failed to write to log file {}", path.display()))?;
+        }
+
+        let file = Arc::new(Mutex::new(file));
+        files.insert(path.to_owned(), file.clone());
+
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_format() {
+        let entry = LogEntry {
+            path: "Documents/a, \"b\".txt".to_owned(),
+            size: Some(42),
+            modified: None,
+            group: "docs",
+            source: "home",
+        };
+        let columns = [
+            LogColumn::Path,
+            LogColumn::Size,
+            LogColumn::Modified,
+            LogColumn::Group,
+        ];
+
+        assert_eq!(
+            "Documents/a, \"b\".txt",
+            entry.format(LogFormat::Line, &columns)
+        );
+        assert_eq!(
+            "\"Documents/a, \"\"b\"\".txt\",42,,docs",
+            entry.format(LogFormat::Csv, &columns)
+        );
+        assert_eq!(
+            r#"{"group":"docs","modified":null,"path":"Documents/a, \"b\".txt","size":42}"#,
+            entry.format(LogFormat::Json, &columns)
+        );
+    }
+
+    #[test]
+    fn test_modes() {
+        let dir = TempDir::new("log");
+        let path = dir.join("log.csv");
+        std::fs::write(&path, "old\n").unwrap();
+        let entry = LogEntry {
+            path: "a.txt".to_owned(),
+            size: None,
+            modified: None,
+            group: "g",
+            source: "s",
+        };
+        let write = |mode| {
+            let log_files = LogFiles::default();
+            for _ in 0..2 {
+                log_files
+                    .write(&path, LogFormat::Line, &[], mode, &entry)
+                    .unwrap();
+            }
+        };
+
+        write(LogMode::Append);
+        assert_eq!(
+            "old\na.txt\na.txt\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        write(LogMode::Overwrite);
+        assert_eq!("a.txt\na.txt\n", std::fs::read_to_string(&path).unwrap());
+    }
+}
@@ -1,36 +1,74 @@
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
-    io::{stdin, stdout, Write},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{self, stderr, stdin, stdout, IsTerminal, Read, Write},
     path::{Path, PathBuf},
-    sync::atomic::{AtomicU32, Ordering},
-    time::Duration,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::{DateTime, Local};
 use fakemap::FakeMap;
 use humansize::FileSize;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use parking_lot::{Condvar, Mutex};
 use pathdiff::diff_paths;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::{
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
 use crate::{
-    cli::cli_options,
-    config::{Config, Rule, Source},
+    cli::{cli_options, ExportFormat, Options},
+    collision::Collision,
+    config::{
+        CaseCollisions, Config, DuplicateSource, Rule, Settings, Source, Target,
+        COUNTER_PLACEHOLDER,
+    },
+    conflict::{ConflictPrompt, Resolution},
+    copy::{copy_file, is_identical, part_path, resume_copy, staged_path},
     file_path::FilePath,
-    util::find_disk,
+    integrity::{check_target, compare_trees, differing_content},
+    log_file::{csv_field, LogEntry, LogFiles},
+    manifest::{CopyStatus, Manifest, ManifestEntry, TargetStats},
+    profile::Profile,
+    progress_stream::ProgressStream,
+    sftp::Remotes,
+    state::{Removed, State},
+    target_contents::TargetContents,
+    util::{check_writable, file_digest, is_root_path_of, Semaphore},
 };
 use md5::Digest;
 use std::collections::hash_map::Entry;
 
+mod benchmark;
+mod clean;
 mod cli;
+mod collision;
 mod config;
+mod conflict;
+mod copy;
 mod date_time;
+mod explain;
 mod file_path;
 mod img;
+mod integrity;
+mod log_file;
+mod manifest;
+mod metadata_cache;
+mod plan;
+mod profile;
+mod progress_stream;
+mod sftp;
+mod state;
+mod summary;
+mod target_contents;
+mod tree;
 mod util;
 
 #[derive(Default)]
@@ -38,9 +76,152 @@ struct Context {
     copy_instructions: FakeMap<PathBuf, CopyInstruction>,
     files_added_with_duplicate_filter: HashMap<Digest, PathBuf>,
     file_size_per_target: HashMap<String, u64>,
+    file_size_per_extension: HashMap<String, u64>,
+    unreadable: Vec<UnreadableFile>,
+    inaccessible: Vec<InaccessiblePath>,
+    outside_size_range: Vec<OutsideSizeRange>,
+    modified: HashMap<PathBuf, SystemTime>,
 }
 
 impl Context {
+    /// Adds a copy instruction for `fp` unless it's not part of the `sample_rate` sample, a skipped
+    /// duplicate or can't be read.
+    pub fn add_copy_instruction(
+        &mut self,
+        settings: &Settings,
+        mut fp: FilePath,
+        target: &str,
+        to: PathBuf,
+        skip_duplicates: bool,
+    ) -> Result<()> {
+        if !settings.is_sampled(&fp.path) {
+            return Ok(());
+        }
+
+        if settings.verify_readable {
+            if let Err(e) = File::open(&fp.full_path) {
+                self.unreadable.push(UnreadableFile {
+                    path: fp.full_path,
+                    error: e.kind().to_string(),
+                });
+
+                return Ok(());
+            }
+        }
+
+        if skip_duplicates && self.check_duplicate(&fp.full_path)? {
+            return Ok(());
+        }
+
+        // copying a file onto itself would truncate it
+        if let (Ok(from), Ok(dest)) = (fp.full_path.canonicalize(), to.canonicalize()) {
+            if from == dest {
+                let message = format!(
+                    "destination of {} is the file itself, skipping it",
+                    fp.full_path.display()
+                );
+                if settings.strict {
+                    bail!(message);
+                }
+                eprintln!("WARNING: {}", message);
+
+                return Ok(());
+            }
+        }
+
+        let metadata = fp.metadata();
+        if let Some(modified) = metadata.as_ref().and_then(|m| m.modified().ok()) {
+            self.modified.insert(fp.full_path.clone(), modified);
+        }
+
+        let instr = CopyInstruction {
+            to,
+            file_size: metadata.map(|m| m.len()).unwrap_or(0),
+            target: target.to_owned(),
+        };
+        self.add_size(&fp.full_path, &instr);
+        self.copy_instructions.insert(fp.full_path, instr);
+
+        Ok(())
+    }
+
+    /// Records and skips `fp` if its size is outside the `min_size` / `max_size` of `rule`.
+    fn skip_outside_size_range(&mut self, rule: &Rule, fp: &mut FilePath) -> bool {
+        match fp.metadata().map(|meta| meta.len()) {
+            Some(size) if !rule.accepts_size(size) => {
+                self.outside_size_range.push(OutsideSizeRange {
+                    path: fp.full_path.clone(),
+                    size,
+                });
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn add_size(&mut self, from: &Path, instr: &CopyInstruction) {
+        *self
+            .file_size_per_target
+            .entry(instr.target.clone())
+            .or_default() += instr.file_size;
+        *self
+            .file_size_per_extension
+            .entry(extension_key(from))
+            .or_default() += instr.file_size;
+    }
+
+    /// Drops all but the `n` most recently modified files from the copy instructions.
+    pub fn keep_newest(&mut self, n: usize) {
+        let mut by_age = self
+            .copy_instructions
+            .keys()
+            .map(|from| (self.modified.get(from).copied(), from))
+            .collect::<Vec<_>>();
+        // newest first, files without modification time last
+        by_age.sort_by(|a, b| b.cmp(a));
+        let keep = by_age
+            .into_iter()
+            .take(n)
+            .map(|(_, from)| from.clone())
+            .collect::<HashSet<_>>();
+
+        self.retain(|from, _| Ok(keep.contains(from)))
+            .expect("filter doesn't fail");
+    }
+
+    /// Drops the files whose content is already on their (deduplicated) target and returns how
+    /// many were dropped.
+    pub fn skip_present(&mut self, contents: &TargetContents) -> Result<usize> {
+        let mut skipped = 0;
+        self.retain(|from, instr| {
+            let present = contents.contains(&instr.target, from, instr.file_size)?;
+            skipped += present as usize;
+
+            Ok(!present)
+        })?;
+
+        Ok(skipped)
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&Path, &CopyInstruction) -> Result<bool>) -> Result<()> {
+        let mut instructions = Vec::new();
+        for (from, instr) in std::mem::take(&mut self.copy_instructions) {
+            if f(&from, &instr)? {
+                instructions.push((from, instr));
+            }
+        }
+
+        self.file_size_per_target.clear();
+        self.file_size_per_extension.clear();
+        for (from, instr) in &instructions {
+            self.add_size(from, instr);
+        }
+        self.copy_instructions = instructions.into_iter().collect();
+
+        Ok(())
+    }
+
     pub fn check_duplicate(&mut self, path: &Path) -> Result<bool> {
         let bytes = std::fs::read(path)
             .with_context(|| format!("could not read file {}", path.display()))?;
@@ -60,6 +241,28 @@ impl Context {
 struct SourceIndex {
     copy_instructions: FakeMap<PathBuf, CopyInstruction>,
     file_size_per_target: HashMap<String, u64>,
+    #[serde(default)]
+    file_size_per_extension: HashMap<String, u64>,
+    #[serde(default)]
+    unreadable: Vec<UnreadableFile>,
+    #[serde(default)]
+    inaccessible: Vec<InaccessiblePath>,
+    #[serde(default)]
+    outside_size_range: Vec<OutsideSizeRange>,
+}
+
+impl SourceIndex {
+    /// Drops the copy instruction of `from`, keeping the sizes in sync.
+    fn remove(&mut self, from: &Path) {
+        if let Some(instr) = self.copy_instructions.remove(from) {
+            if let Some(size) = self.file_size_per_target.get_mut(&instr.target) {
+                *size -= instr.file_size;
+            }
+            if let Some(size) = self.file_size_per_extension.get_mut(&extension_key(from)) {
+                *size -= instr.file_size;
+            }
+        }
+    }
 }
 
 impl From<Context> for SourceIndex {
@@ -67,304 +270,2105 @@ impl From<Context> for SourceIndex {
         SourceIndex {
             copy_instructions: c.copy_instructions,
             file_size_per_target: c.file_size_per_target,
+            file_size_per_extension: c.file_size_per_extension,
+            unreadable: c.unreadable,
+            inaccessible: c.inaccessible,
+            outside_size_range: c.outside_size_range,
         }
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct CopyInstruction {
-    to: PathBuf,
-    file_size: u64,
+/// The lowercase extension used for the per-file-type breakdown.
+fn extension_key(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "(no extension)".to_owned())
 }
 
-type Index = HashMap<String, SourceIndex>;
+/// A file that would have been copied, but couldn't be opened during indexing.
+#[derive(Deserialize, Serialize)]
+struct UnreadableFile {
+    path: PathBuf,
+    error: String,
+}
 
-type Progress = HashMap<String, AtomicU32>;
+/// A file skipped because its size is outside the `min_size` / `max_size` of its rule.
+#[derive(Deserialize, Serialize)]
+struct OutsideSizeRange {
+    path: PathBuf,
+    size: u64,
+}
 
-fn read_config() -> Result<Config> {
-    serde_yaml::from_reader(
-        File::open("config.yaml").with_context(|| format!("cannot open config.yaml"))?,
-    )
-    .with_context(|| format!("cannot parse config.yaml"))
+/// A file or directory that couldn't be read while walking a source, so neither it nor anything
+/// below it is part of the index.
+#[derive(Deserialize, Serialize)]
+struct InaccessiblePath {
+    path: PathBuf,
+    kind: WalkErrorKind,
+    error: String,
 }
 
-fn read_index() -> Result<Index> {
-    serde_yaml::from_reader(
-        File::open("index.yaml").with_context(|| format!("cannot open index.yaml"))?,
-    )
-    .with_context(|| format!("Cannot parse index.yaml"))
+impl InaccessiblePath {
+    fn new(dir_path: &Path, e: &walkdir::Error) -> Self {
+        let kind = if e.loop_ancestor().is_some() {
+            WalkErrorKind::Loop
+        } else {
+            match e.io_error().map(std::io::Error::kind) {
+                Some(std::io::ErrorKind::PermissionDenied) => WalkErrorKind::PermissionDenied,
+                Some(std::io::ErrorKind::NotFound) => WalkErrorKind::NotFound,
+                _ => WalkErrorKind::Other,
+            }
+        };
+
+        InaccessiblePath {
+            path: e.path().unwrap_or(dir_path).to_owned(),
+            kind,
+            error: e.to_string(),
+        }
+    }
 }
 
-fn read_progress() -> Result<Progress> {
-    serde_yaml::from_reader(
-        File::open("progress.yaml").with_context(|| format!("cannot open progress.yaml"))?,
-    )
-    .with_context(|| format!("Cannot parse progress.yaml"))
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+enum WalkErrorKind {
+    #[serde(rename = "permission_denied")]
+    PermissionDenied,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "loop")]
+    Loop,
+    #[serde(rename = "other")]
+    Other,
 }
 
-fn app() -> Result<()> {
-    let options = cli_options();
+impl std::fmt::Display for WalkErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WalkErrorKind::PermissionDenied => "permission denied",
+            WalkErrorKind::NotFound => "not found",
+            WalkErrorKind::Loop => "file system loop",
+            WalkErrorKind::Other => "error",
+        })
+    }
+}
 
-    let config: Config = read_config()?;
+#[derive(Deserialize, Serialize)]
+struct CopyInstruction {
+    to: PathBuf,
+    file_size: u64,
+    #[serde(default)]
+    target: String,
+}
 
-    let index = if options.continue_ {
-        read_index()
-            .with_context(|| format!("cannot continue backup because index cannot be read"))?
-    } else {
-        build_index(&config).with_context(|| format!("failed to build index"))?
-    };
+impl CopyInstruction {
+    fn manifest_entry(
+        &self,
+        from: &Path,
+        status: CopyStatus,
+        error: Option<String>,
+    ) -> ManifestEntry {
+        ManifestEntry {
+            from: from.to_owned(),
+            to: self.to.clone(),
+            target: self.target.clone(),
+            file_size: self.file_size,
+            status,
+            error,
+        }
+    }
+}
 
-    let progress = if options.continue_ {
-        read_progress()?
-    } else {
-        index
-            .keys()
-            .map(|source| (source.clone(), AtomicU32::new(0)))
-            .collect()
-    };
+type Index = HashMap<String, SourceIndex>;
 
-    let fmt_size = |size: u64| {
-        size.file_size(config.settings.file_size_style.to_file_size_opts())
-            .unwrap()
-    };
+/// Replaces the placeholders left by `PathElement::Counter` with sequence numbers.
+///
+/// Sources are indexed in parallel, so numbers are only assigned once the whole index has been
+/// built, in order of source name and original path: an unchanged tree always gets the same
+/// numbers. They are zero-padded to the width of the largest one.
+fn assign_counters(index: &mut Index) {
+    let mut instructions = index
+        .iter_mut()
+        .flat_map(|(source, source_index)| {
+            source_index
+                .copy_instructions
+                .iter_mut()
+                .map(move |(from, instr)| (source.as_str(), &*from, instr))
+        })
+        .filter(|(_, _, instr)| {
+            instr
+                .to
+                .iter()
+                .any(|c| c.to_str().is_some_and(|c| c.contains(COUNTER_PLACEHOLDER)))
+        })
+        .collect::<Vec<_>>();
+    instructions.sort_by(|(a_source, a_from, _), (b_source, b_from, _)| {
+        (a_source, a_from).cmp(&(b_source, b_from))
+    });
 
-    println!("Summary:");
-    println!();
+    let width = instructions.len().to_string().len();
+    for (i, (_, _, instr)) in instructions.into_iter().enumerate() {
+        let number = format!("{:0width$}", i + 1, width = width);
+        instr.to = instr
+            .to
+            .iter()
+            .map(|c| match c.to_str() {
+                Some(c) => c.replace(COUNTER_PLACEHOLDER, &number).into(),
+                None => c.to_owned(),
+            })
+            .collect();
+    }
+}
 
-    for (source, context) in &index {
-        if config
-            .sources
-            .get(source)
-            .map(|s| s.disabled)
-            .unwrap_or(false)
+/// Keeps only one of the files with the same destination if they are from different sources,
+/// chosen by `policy`; ties go to the source whose name sorts first. Returns how many files were
+/// dropped.
+fn resolve_duplicate_sources(index: &mut Index, policy: DuplicateSource) -> usize {
+    let collisions = collision::find(index.iter().flat_map(|(source, context)| {
+        context
+            .copy_instructions
+            .iter()
+            .map(move |(from, instr)| (source.as_str(), from.as_path(), instr.to.as_path()))
+    }));
+
+    let mut dropped = 0;
+    for collision in collisions {
+        let (first_source, _) = &collision.from[0];
+        if collision
+            .from
+            .iter()
+            .all(|(source, _)| source == first_source)
         {
             continue;
         }
 
-        println!("Source '{}'", source);
-        let total: u64 = context.file_size_per_target.values().cloned().sum();
-        println!("  Data to copy [all targets]: {}", fmt_size(total));
-        if total > 0 {
-            println!("  Data per target:");
-            for (target, size) in context.file_size_per_target.iter() {
-                println!("    To target '{}': {}", target, fmt_size(*size));
+        // sorted by source, and `min_by_key` keeps the first of equal ones
+        let (winner, _) = collision
+            .from
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (source, from))| match policy {
+                DuplicateSource::Newest => Reverse(
+                    std::fs::metadata(from)
+                        .and_then(|meta| meta.modified())
+                        .ok()
+                        .map(|modified| {
+                            modified
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_nanos()
+                        }),
+                ),
+                DuplicateSource::Largest => Reverse(
+                    index[source]
+                        .copy_instructions
+                        .get(from)
+                        .map(|instr| u128::from(instr.file_size)),
+                ),
+                DuplicateSource::First => Reverse(None),
+            })
+            .unwrap();
+        for (i, (source, from)) in collision.from.iter().enumerate() {
+            if i != winner {
+                index.get_mut(source).unwrap().remove(from);
+                dropped += 1;
             }
         }
     }
 
-    println!();
-
-    index
-        .values()
-        .flat_map(|context| context.file_size_per_target.iter())
-        .fold(HashMap::new(), |mut map, (target, size)| {
-            *map.entry(target.clone()).or_default() += *size;
-
-            map
-        })
-        .iter()
-        .for_each(|(target, size)| {
+    dropped
+}
 
-            if let Some(disk_info) = config.targets.get(target).and_then(|target| find_disk(target)) {
-                println!("Target '{}' [{}] (free space: {})", target, fmt_size(*size), fmt_size(disk_info.available));
+/// Handles destinations that differ only in case according to `case_collisions`. With
+/// `rename`, all but the first of each group (in path order) get the first free
+/// `name (n).ext` that doesn't collide with any destination either.
+fn resolve_case_collisions(index: &mut Index, policy: CaseCollisions) -> Result<()> {
+    if policy == CaseCollisions::Ignore {
+        return Ok(());
+    }
 
-                if disk_info.available < *size {
-                    eprintln!("WARNING: Free disk space on target's disk ({}) is less than data to copy (would need {} more)", disk_info.mount_point.display(), fmt_size(*size - disk_info.available));
-                    if options.continue_ {
-                        println!("Note: Continue option is enabled, space may be sufficient due to already copied files");
-                    } else if disk_info.capacity >= *size {
-                        println!("Note: Disk has enough (total) capacity (might want to free up space)");
-                    }
+    let collisions = collision::find_case_insensitive(
+        index
+            .values()
+            .flat_map(|context| context.copy_instructions.values())
+            .map(|instr| instr.to.as_path()),
+    );
+    let list = |variants: &[PathBuf]| {
+        variants
+            .iter()
+            .map(|to| to.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match policy {
+        CaseCollisions::Ignore => {}
+        CaseCollisions::Warn => {
+            for variants in &collisions {
+                eprintln!(
+                    "WARNING: destinations differ only in case and overwrite each other on case-insensitive targets (e.g. macOS, Windows): {}",
+                    list(variants)
+                );
+            }
+        }
+        CaseCollisions::Error => {
+            if let Some(variants) = collisions.first() {
+                bail!(
+                    "destinations differ only in case and would overwrite each other on case-insensitive targets: {} ({} such collisions, case_collisions is error)",
+                    list(variants),
+                    collisions.len()
+                );
+            }
+        }
+        CaseCollisions::Rename => {
+            let mut taken = index
+                .values()
+                .flat_map(|context| context.copy_instructions.values())
+                .map(|instr| collision::case_key(&instr.to))
+                .collect::<HashSet<_>>();
+            let mut renamed = HashMap::new();
+            for variants in collisions {
+                for to in variants.into_iter().skip(1) {
+                    let stem = to.file_stem().unwrap_or_default().to_string_lossy();
+                    let extension = to
+                        .extension()
+                        .map(|ext| format!(".{}", ext.to_string_lossy()))
+                        .unwrap_or_default();
+                    let new = (1..)
+                        .map(|n| to.with_file_name(format!("{} ({}){}", stem, n, extension)))
+                        .find(|new| taken.insert(collision::case_key(new)))
+                        .unwrap();
+                    println!(
+                        "Renamed {} to {} (differs only in case from another destination)",
+                        to.display(),
+                        new.file_name().unwrap().to_string_lossy()
+                    );
+                    renamed.insert(to, new);
                 }
-            } else {
-                println!("Target '{}' [{}] (free space unknown)", target, fmt_size(*size));
             }
-        });
 
-    let total = index
-        .values()
-        .flat_map(|context| context.copy_instructions.values().map(|ci| ci.file_size))
-        .sum();
+            for instr in index
+                .values_mut()
+                .flat_map(|context| context.copy_instructions.values_mut())
+            {
+                if let Some(new) = renamed.get(&instr.to) {
+                    instr.to = new.clone();
+                }
+            }
+        }
+    }
 
-    println!();
-    if options.continue_ {
-        let remaining = index
-            .iter()
-            .flat_map(|(src, context)| {
-                context
-                    .copy_instructions
-                    .values()
-                    .skip(progress[src].load(Ordering::SeqCst) as usize)
-                    .map(|ci| ci.file_size)
-            })
-            .sum();
+    Ok(())
+}
 
-        println!(
-            "Total data to copy (remaining): {} of {}",
-            fmt_size(remaining),
-            fmt_size(total)
-        );
-    } else {
-        println!("Total data to copy: {}", fmt_size(total));
+/// Orders the copy instructions of each source by descending priority of their target, keeping
+/// the order of instructions of the same priority. `copy_files` copies one priority at a time,
+/// which is then a contiguous part of every source, so `progress` still counts completed files
+/// from the start.
+fn order_by_priority(index: &mut Index, config: &Config) {
+    let priority = |target: &str| config.targets.get(target).map_or(0, |t| t.priority);
+    for source_index in index.values_mut() {
+        let mut instructions = std::mem::take(&mut source_index.copy_instructions)
+            .into_iter()
+            .collect::<Vec<_>>();
+        instructions.sort_by_key(|(_, instr)| Reverse(priority(&instr.target)));
+        source_index.copy_instructions = instructions.into_iter().collect();
     }
+}
 
-    if !options.yes {
-        print!("Continue? [y/N] ");
-        stdout().flush().unwrap();
+/// Written after a successful run, for `--since last`.
+#[derive(Deserialize, Serialize)]
+struct LastRun {
+    started: String,
+}
 
-        let mut buf = String::new();
-        stdin().read_line(&mut buf).unwrap();
+type Progress = HashMap<String, AtomicU32>;
 
-        let input = buf.trim();
-        let is_yes = input.starts_with("Y") || input.starts_with("y");
+/// The sources all files of which have been copied, with their number of files. Saved to
+/// `completed.yaml` as soon as a source is done, so `--continue` can skip it outright.
+type Completed = BTreeMap<String, usize>;
+
+/// Reads the config from `path`, or from stdin if it is "-", and the md5 hash of its text.
+///
+/// Relative paths in a config file are relative to its directory, those read from stdin to the
+/// working directory.
+fn read_config(path: &str) -> Result<(Config, String)> {
+    if path == "-" {
+        let mut text = Vec::new();
+        stdin()
+            .lock()
+            .read_to_end(&mut text)
+            .context("cannot read config from stdin")?;
+        let config =
+            Config::from_reader(text.as_slice()).context("cannot parse config from stdin")?;
+
+        return Ok((config, format!("{:x}", md5::compute(&text))));
+    }
 
-        if !is_yes {
-            println!("Cancelled");
-            return Ok(());
-        }
+    let text = std::fs::read(path).with_context(|| format!("cannot open {}", path))?;
+    let mut config =
+        Config::from_reader(text.as_slice()).with_context(|| format!("cannot parse {}", path))?;
+    if let Some(dir) = Path::new(path).parent() {
+        config.resolve_relative_paths(dir);
     }
 
-    copy_files(&index, progress, total)?;
+    Ok((config, format!("{:x}", md5::compute(&text))))
+}
 
-    Ok(())
+/// Writes `value` to a temporary file next to `path` and renames it to `path`, so a crash while
+/// writing never leaves a partial file behind (the rename replaces it at once).
+fn write_yaml_atomically(path: &str, value: &impl Serialize) -> Result<()> {
+    let temp = format!("{}.tmp", path);
+    let file = File::create(&temp).with_context(|| format!("cannot create {}", temp))?;
+    let mut writer = io::BufWriter::new(file);
+    serde_yaml::to_writer(&mut writer, value).with_context(|| format!("cannot write {}", temp))?;
+    writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("cannot write {}", temp))?;
+
+    std::fs::rename(&temp, path).with_context(|| format!("cannot replace {}", path))
 }
 
-fn build_index(config: &Config) -> Result<Index> {
-    println!("Building indices...");
+/// Comma-separated names of sources to disable in addition to those disabled in the config.
+const DISABLE_SOURCES_VAR: &str = "SNB_DISABLE";
 
-    let multi_progress_bar = MultiProgress::new();
-    let sty = ProgressStyle::default_spinner()
-        // For more spinners check out the cli-spinners project:
-        // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-        .tick_strings(&[
-            "▹▹▹▹▹",
-            "▸▹▹▹▹",
-            "▹▸▹▹▹",
-            "▹▹▸▹▹",
-            "▹▹▹▸▹",
-            "▹▹▹▹▸",
-            "▪▪▪▪▪",
-        ])
-        .template("{spinner:.blue} {msg}")
-        .unwrap();
+/// Saved for `--continue`.
+fn save_index(index: &Index) -> Result<()> {
+    write_yaml_atomically("index.yaml", index)
+}
 
-    let index = config
-        .sources
-        .par_iter()
-        .map(|(name, source)| {
-            if source.disabled {
-                return Ok((name.to_owned(), Default::default()));
-            }
+fn read_index() -> Result<Index> {
+    serde_yaml::from_reader(File::open("index.yaml").context("cannot open index.yaml")?)
+        .context("Cannot parse index.yaml")
+}
+
+/// Version of `progress.yaml`; increase it whenever its meaning changes.
+const PROGRESS_VERSION: u32 = 1;
+
+/// How far copying got, saved to `progress.yaml`, together with the md5 hashes of the config and
+/// `index.yaml` it belongs to, so `--continue` never counts files of another index.
+#[derive(Deserialize, Serialize)]
+struct SavedProgress {
+    version: u32,
+    config: String,
+    index: String,
+    sources: Progress,
+    #[serde(default)]
+    in_flight: InFlight,
+    /// The copies in progress when the continued run was interrupted, by source.
+    #[serde(skip)]
+    interrupted: BTreeMap<String, InFlightFile>,
+}
 
-            println!("Building index for source '{}'...", name);
+impl SavedProgress {
+    fn save(&self) -> Result<()> {
+        for file in self.in_flight.0.lock().values_mut() {
+            file.update_offset();
+        }
 
-            let pb = multi_progress_bar.add(ProgressBar::new_spinner());
-            pb.enable_steady_tick(Duration::from_secs(120));
-            pb.set_style(sty.clone());
-            pb.set_message(format!("{}...", name));
+        write_yaml_atomically("progress.yaml", self)
+    }
+}
 
-            let mut context = Default::default();
+/// The file each source is copying to a local target, by source.
+#[derive(Default)]
+struct InFlight(Mutex<BTreeMap<String, InFlightFile>>);
 
-            walk_dir(&config, name, source, &source.path, &mut context, &pb)?;
+impl Serialize for InFlight {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.lock().serialize(serializer)
+    }
+}
 
-            pb.finish_with_message(format!("{}... Done", name));
+impl<'de> Deserialize<'de> for InFlight {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        BTreeMap::deserialize(deserializer).map(|files| InFlight(Mutex::new(files)))
+    }
+}
 
-            println!("Building index for source '{}'... Done", name);
+/// A copy in progress when progress was saved, which `--continue` resumes at `offset`.
+#[derive(Clone, Deserialize, Serialize)]
+struct InFlightFile {
+    from: PathBuf,
+    to: PathBuf,
+    /// Where the copy is written to, the part file with `staging`.
+    dest: PathBuf,
+    /// Size and modification time of `from` when the copy started; if either changed since, the
+    /// partial copy is of another version.
+    len: u64,
+    modified: Option<SystemTime>,
+    started: SystemTime,
+    /// Bytes of `dest` written by this copy, as of the last save.
+    offset: u64,
+}
 
-            Ok((name.to_owned(), context.into()))
+impl InFlightFile {
+    fn new(from: &Path, to: &Path, target: &Target) -> Option<Self> {
+        let metadata = std::fs::metadata(from).ok()?;
+
+        Some(InFlightFile {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            dest: staged_path(to, target),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            started: SystemTime::now(),
+            offset: 0,
         })
-        .collect::<Result<Index>>()?;
-
-    serde_yaml::to_writer(
-        File::create("index.yaml").with_context(|| format!("cannot create index.yaml"))?,
-        &index,
-    )?;
+    }
 
-    multi_progress_bar.clear().unwrap();
+    /// Takes the length of `dest` as the offset, unless it wasn't written since the copy started
+    /// (e.g. the previous backup of the file, not truncated yet).
+    fn update_offset(&mut self) {
+        self.offset = std::fs::metadata(&self.dest)
+            .ok()
+            .filter(|meta| {
+                meta.modified()
+                    .is_ok_and(|modified| modified >= self.started)
+            })
+            .map_or(0, |meta| meta.len());
+    }
 
-    println!("Building indices... Done (saved to index.yaml)");
+    /// The offset to resume a copy of `from` to `to` at, if this is an interrupted copy of the
+    /// same version of the file.
+    fn resume_offset(&self, from: &Path, to: &Path) -> Option<u64> {
+        let unchanged = || {
+            std::fs::metadata(from)
+                .is_ok_and(|meta| meta.len() == self.len && meta.modified().ok() == self.modified)
+        };
 
-    Ok(index)
+        (self.from == from && self.to == to && self.offset > 0 && unchanged())
+            .then_some(self.offset)
+    }
 }
 
-fn copy_files(index: &Index, progress: Progress, total_size: u64) -> Result<()> {
-    println!("Copying files...");
-
-    let mutex = Mutex::new(());
-    let finished = Condvar::new();
-    let finished = &finished;
-    let progress = &progress;
+fn read_progress() -> Result<SavedProgress> {
+    // written by versions before `SavedProgress`, without hashes to check
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ProgressFile {
+        Saved(SavedProgress),
+        Unversioned(Progress),
+    }
 
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{wide_bar.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-    let pb = &pb;
+    let file = File::open("progress.yaml").context("cannot open progress.yaml")?;
+    match serde_yaml::from_reader(file).context("Cannot parse progress.yaml")? {
+        ProgressFile::Saved(saved) if saved.version != PROGRESS_VERSION => bail!(
+            "unsupported progress.yaml version {} (this version of sortnbackup reads version {})",
+            saved.version,
+            PROGRESS_VERSION
+        ),
+        ProgressFile::Saved(saved) => Ok(saved),
+        ProgressFile::Unversioned(sources) => Ok(SavedProgress {
+            version: PROGRESS_VERSION,
+            config: String::new(),
+            index: String::new(),
+            sources,
+            in_flight: Default::default(),
+            interrupted: BTreeMap::new(),
+        }),
+    }
+}
 
-    rayon::scope(|scope| {
-        scope.spawn(move |_scope| {
-            while finished
-                .wait_for(&mut mutex.lock(), Duration::from_secs(15))
-                .timed_out()
-            {
-                if let Ok(file) = File::create("progress.yaml") {
-                    let _ = serde_yaml::to_writer(file, &progress);
-                }
-            }
-        });
+/// Checks that `saved` belongs to the index and config it continues with; a changed config is
+/// only an error with `strict`, the index determines what is copied.
+fn check_progress(saved: &SavedProgress, config: &str, index: &str, strict: bool) -> Result<()> {
+    if saved.index.is_empty() {
+        eprintln!("WARNING: progress.yaml is from an older version, cannot check that it belongs to index.yaml");
+        return Ok(());
+    }
+    if saved.index != index {
+        bail!("progress.yaml doesn't belong to index.yaml (it counts the files of another index), run without --continue to start over");
+    }
+    if saved.config != config {
+        let message = "the config changed since the backup was started, index.yaml was built with the old one (run without --continue to rebuild it)";
+        if strict {
+            bail!(message);
+        }
+        eprintln!("WARNING: {}", message);
+    }
 
-        index.par_iter().for_each(move |(source, context)| {
-            let src_progress: &AtomicU32 = &progress[source];
+    Ok(())
+}
 
-            for (from, instr) in context
-                .copy_instructions
-                .iter()
-                .skip(src_progress.load(Ordering::SeqCst) as usize)
-            {
-                let instr: &CopyInstruction = instr;
-                let to = &instr.to;
-                let _ = std::fs::create_dir_all(to.parent().unwrap());
-                if let Err(e) = std::fs::copy(from, to) {
-                    eprintln!(
-                        "Failed to copy {} to {}: {}",
-                        from.display(),
-                        to.display(),
-                        e
-                    );
-                }
-                src_progress.fetch_add(1, Ordering::SeqCst);
-                pb.inc(instr.file_size);
-            }
-        });
+fn read_completed() -> Result<Completed> {
+    match File::open("completed.yaml") {
+        Ok(file) => serde_yaml::from_reader(file).context("Cannot parse completed.yaml"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Completed::new()),
+        Err(e) => Err(e).context("cannot open completed.yaml"),
+    }
+}
 
-        finished.notify_all();
+/// Keeps the sources that are done, i.e. still have the number of files in the index they had when
+/// they were completed, and marks all their files as copied in `progress`.
+fn reconcile_completed(index: &Index, mut completed: Completed, progress: &Progress) -> Completed {
+    completed.retain(|source, files| match index.get(source) {
+        Some(context) if context.copy_instructions.keys().count() == *files => {
+            progress[source].store(*files as u32, Ordering::SeqCst);
+            true
+        }
+        _ => {
+            eprintln!(
+                "WARNING: Ignoring completion of source '{}' which doesn't match index.yaml",
+                source
+            );
+            false
+        }
     });
 
-    let _ = std::fs::remove_file("progress.yaml");
-    let _ = std::fs::remove_file("index.yaml");
+    completed
+}
+
+/// Drops progress entries of sources that are not part of the index (e.g. because the config
+/// changed between runs) and starts sources without progress from the beginning.
+fn reconcile_progress(index: &Index, mut progress: Progress) -> Progress {
+    progress.retain(|source, _| {
+        let known = index.contains_key(source);
+        if !known {
+            eprintln!(
+                "WARNING: Ignoring progress of source '{}' which is not part of index.yaml",
+                source
+            );
+        }
 
-    pb.finish_with_message("copied");
+        known
+    });
 
-    println!("Copying files... Done");
+    for source in index.keys() {
+        progress
+            .entry(source.clone())
+            .or_insert_with(|| AtomicU32::new(0));
+    }
 
-    Ok(())
+    progress
 }
 
-fn walk_dir(
+/// At most this many files not copied yet are checked for changes when continuing.
+const STALENESS_SAMPLE: usize = 100;
+
+/// Checks an evenly spread sample of the files not copied yet for changes since the index was
+/// built at `indexed_at`: a file that is gone, has another size or was modified since. Returns the
+/// number of files checked and changed.
+fn sample_changed_files(
+    index: &Index,
+    progress: &Progress,
+    indexed_at: SystemTime,
+) -> (usize, usize) {
+    let remaining = || {
+        index.iter().flat_map(|(source, context)| {
+            let already_copied = progress[source].load(Ordering::SeqCst) as usize;
+            context.copy_instructions.iter().skip(already_copied)
+        })
+    };
+    let step = (remaining().count() / STALENESS_SAMPLE).max(1);
+
+    remaining().step_by(step).take(STALENESS_SAMPLE).fold(
+        (0, 0),
+        |(checked, changed), (from, instr)| {
+            let is_changed = match std::fs::metadata(from) {
+                Ok(meta) => {
+                    meta.len() != instr.file_size
+                        || meta.modified().is_ok_and(|modified| modified > indexed_at)
+                }
+                Err(_) => true,
+            };
+
+            (checked + 1, changed + is_changed as usize)
+        },
+    )
+}
+
+/// Outcome of a run, reported as the process exit code (1 is used for errors aborting the run).
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Success = 0,
+    CopyFailed = 2,
+    OutOfSpace = 3,
+    Cancelled = 4,
+    VerifyFailed = 5,
+}
+
+impl Outcome {
+    fn of(manifest: &Manifest) -> Self {
+        if !manifest.out_of_space.is_empty() {
+            Outcome::OutOfSpace
+        } else if manifest
+            .entries()
+            .any(|entry| entry.status == CopyStatus::Failed)
+        {
+            Outcome::CopyFailed
+        } else {
+            Outcome::Success
+        }
+    }
+}
+
+/// The files that failed in the last run, as an index to copy them again (`--retry-failed`).
+/// Their targets have to be part of `config`.
+fn read_failed(config: &Config) -> Result<Index> {
+    let manifest = Manifest::load(Path::new("manifest.yaml"))?;
+    failed_index(&manifest, config)
+}
+
+fn failed_index(manifest: &Manifest, config: &Config) -> Result<Index> {
+    let mut contexts = BTreeMap::<&str, Context>::new();
+    for (source, entry) in manifest.failed() {
+        if !config.targets.contains_key(&entry.target) {
+            bail!(
+                "{} is copied to target '{}', which is not in the config",
+                entry.from.display(),
+                entry.target
+            );
+        }
+
+        let context = contexts.entry(source).or_default();
+        let instr = CopyInstruction {
+            to: entry.to.clone(),
+            file_size: entry.file_size,
+            target: entry.target.clone(),
+        };
+        context.add_size(&entry.from, &instr);
+        context.copy_instructions.insert(entry.from.clone(), instr);
+    }
+
+    Ok(contexts
+        .into_iter()
+        .map(|(source, context)| (source.to_owned(), context.into()))
+        .collect())
+}
+
+fn read_last_run() -> Result<LastRun> {
+    serde_yaml::from_reader(File::open("last_run.yaml").context("cannot open last_run.yaml")?)
+        .context("Cannot parse last_run.yaml")
+}
+
+/// Parses the argument of `--since`, where "last" refers to the start of the last successful run.
+fn parse_since(since: &str, now: SystemTime) -> Result<SystemTime> {
+    if since == "last" {
+        let last_run = read_last_run().context("cannot determine the last run")?;
+
+        return Ok(DateTime::parse_from_rfc3339(&last_run.started)
+            .context("invalid start time in last_run.yaml")?
+            .into());
+    }
+
+    date_time::parse_since(since, now).map_err(|e| anyhow!(e))
+}
+
+/// Reads the index for `--continue`, loads it from a plan or the failures of the last run or builds
+/// it from the sources. Returns `None` if there are no failures to retry.
+fn read_or_build_index(
+    config: &Config,
+    options: &Options,
+    started: SystemTime,
+) -> Result<Option<Index>> {
+    let index = if options.continue_ {
+        read_index().context("cannot continue backup because index cannot be read")?
+    } else if let Some(path) = &options.run_plan {
+        let mut index = plan::load(path, config)?;
+        order_by_priority(&mut index, config);
+        save_index(&index)?;
+        println!("Using plan {}", path.display());
+
+        index
+    } else if options.retry_failed {
+        let mut index = read_failed(config).context("cannot read the failures of the last run")?;
+        let files = index
+            .values()
+            .map(|context| context.copy_instructions.keys().count())
+            .sum::<usize>();
+        if files == 0 {
+            println!("No failed files in manifest.yaml");
+
+            return Ok(None);
+        }
+        order_by_priority(&mut index, config);
+        save_index(&index)?;
+        println!("Retrying {} failed files of manifest.yaml", files);
+
+        index
+    } else {
+        let since = options
+            .since
+            .as_deref()
+            .map(|since| parse_since(since, started))
+            .transpose()
+            .context("invalid --since")?;
+
+        let profile = options.profile.then(|| Profile::new(config));
+        let index = build_index(
+            config,
+            since,
+            options.verbose,
+            show_progress(options),
+            profile.as_ref(),
+        )
+        .context("failed to build index")?;
+        if let Some(profile) = profile {
+            println!();
+            println!("{}", profile.report());
+        }
+
+        index
+    };
+
+    Ok(Some(index))
+}
+
+/// Reads what earlier runs copied for `--continue` (checking that it belongs to the same config and
+/// index), or starts from scratch: the progress per source, the copies that were interrupted and
+/// the completed sources.
+fn load_progress(
+    config: &Config,
+    index: &Index,
+    options: &Options,
+    config_digest: &str,
+    index_digest: &str,
+) -> Result<(Progress, BTreeMap<String, InFlightFile>, Completed)> {
+    let (progress, interrupted) = if options.continue_ {
+        let saved = read_progress()?;
+        check_progress(&saved, config_digest, index_digest, config.settings.strict)?;
+
+        (
+            reconcile_progress(index, saved.sources),
+            saved.in_flight.0.into_inner(),
+        )
+    } else {
+        let progress = index
+            .keys()
+            .map(|source| (source.clone(), AtomicU32::new(0)))
+            .collect();
+
+        (progress, BTreeMap::new())
+    };
+    if options.continue_ {
+        if let Ok(indexed_at) = std::fs::metadata("index.yaml").and_then(|meta| meta.modified()) {
+            let (checked, changed) = sample_changed_files(index, &progress, indexed_at);
+            if changed > 0 {
+                eprintln!(
+                    "WARNING: {} of {} checked files changed since index.yaml was built, the \
+                     index may be outdated (run without --continue to rebuild it)",
+                    changed, checked
+                );
+            }
+        }
+    }
+    let completed = if options.continue_ {
+        reconcile_completed(index, read_completed()?, &progress)
+    } else {
+        let _ = std::fs::remove_file("completed.yaml");
+        Completed::new()
+    };
+
+    Ok((progress, interrupted, completed))
+}
+
+fn app() -> Result<Outcome> {
+    let started = SystemTime::now();
+    let options = cli_options();
+
+    if options.config == "-" && !options.yes {
+        // the confirmation prompt reads from stdin as well
+        bail!("--config - requires --yes");
+    }
+    if options.config == "-" && options.interactive {
+        bail!("--config - cannot be used with --interactive");
+    }
+    let (mut config, config_digest) = read_config(&options.config)?;
+    #[cfg(not(unix))]
+    if config.settings.dest_mode.is_some() {
+        eprintln!("WARNING: dest_mode is only supported on Unix and will be ignored");
+    }
+    if let Some(prefix) = &options.target_prefix {
+        config.relocate_targets(prefix);
+    }
+    if let Ok(patterns) = std::env::var(DISABLE_SOURCES_VAR) {
+        let (disabled, unmatched) = config.disable_sources(&patterns);
+        if !disabled.is_empty() {
+            println!(
+                "Disabled sources ({}): {}",
+                DISABLE_SOURCES_VAR,
+                disabled.join(", ")
+            );
+        }
+        for pattern in unmatched {
+            eprintln!(
+                "WARNING: '{}' in {} doesn't match any source",
+                pattern, DISABLE_SOURCES_VAR
+            );
+        }
+    }
+    let remotes = Remotes::new(&config);
+
+    if options.benchmark {
+        let fmt_size = |size: u64| {
+            size.file_size(config.settings.file_size_style.to_file_size_opts())
+                .unwrap()
+        };
+        benchmark::run(&config, &remotes, benchmark::FILE_SETS, &fmt_size)?;
+
+        return Ok(Outcome::Success);
+    }
+    if options.clean {
+        clean::run(&config, Path::new(""), options.dry_run)?;
+
+        return Ok(Outcome::Success);
+    }
+    if let Some((a, b)) = &options.compare_targets {
+        print_target_diff(&config, &remotes, a, b)?;
+
+        return Ok(Outcome::Success);
+    }
+    if let Some(file) = &options.test_file {
+        print!(
+            "{}",
+            explain::explain(&config, options.source.as_deref(), file)?
+        );
+
+        return Ok(Outcome::Success);
+    }
+
+    let index = match read_or_build_index(&config, &options, started)? {
+        Some(index) => index,
+        None => return Ok(Outcome::Success),
+    };
+
+    if let Some(path) = &options.export_index {
+        export_index(&index, path, options.export_format)?;
+        println!("Exported index to {}", path.display());
+    }
+
+    let index_digest = format!(
+        "{:x}",
+        file_digest(Path::new("index.yaml")).context("cannot hash index.yaml")?
+    );
+    let (progress, interrupted, completed) =
+        load_progress(&config, &index, &options, &config_digest, &index_digest)?;
+
+    let fmt_size = |size: u64| {
+        size.file_size(config.settings.file_size_style.to_file_size_opts())
+            .unwrap()
+    };
+
+    summary::print_sources(&config, &index, &fmt_size);
+
+    let has_inaccessible = index
+        .values()
+        .any(|context| !context.inaccessible.is_empty());
+    if config.settings.fail_on_inaccessible && has_inaccessible {
+        bail!("some paths of the sources cannot be read (fail_on_inaccessible is set)");
+    }
+
+    summary::print_targets(&config, &index, &remotes, options.continue_, &fmt_size);
+    summary::print_file_types(&index, &fmt_size);
+
+    let mut state = State::load(Path::new("state.yaml"))?;
+    let removed = if options.continue_ {
+        Vec::new()
+    } else {
+        // an unmounted source would look like all of its files had been removed
+        let sources = index
+            .keys()
+            .filter(|source| {
+                config
+                    .sources
+                    .get(*source)
+                    .is_some_and(|s| !s.disabled && s.path.exists())
+            })
+            .map(String::as_str);
+
+        state.removed(sources)
+    };
+    if config.settings.sync_deletions && !removed.is_empty() {
+        println!();
+        println!(
+            "Files removed from sources (copies will be deleted): {}",
+            removed.len()
+        );
+        for removed in &removed {
+            println!("  {}", removed.backed_up.to.display());
+        }
+    }
+
+    let total = index
+        .values()
+        .flat_map(|context| context.copy_instructions.values().map(|ci| ci.file_size))
+        .sum();
+
+    summary::print_total(
+        &index,
+        &progress,
+        &completed,
+        total,
+        options.continue_,
+        &fmt_size,
+    );
+
+    let collisions = collision::find(index.iter().flat_map(|(source, context)| {
+        context
+            .copy_instructions
+            .iter()
+            .map(move |(from, instr)| (source.as_str(), from.as_path(), instr.to.as_path()))
+    }));
+    if !collisions.is_empty() && !options.dry_run {
+        eprintln!(
+            "WARNING: {} destinations would be written by more than one file (see --dry-run)",
+            collisions.len()
+        );
+    }
+
+    if options.tree {
+        println!();
+        print_destination_tree(&config, &index);
+
+        return Ok(Outcome::Success);
+    }
+
+    if let Some(path) = &options.save_plan {
+        plan::save(&index, path)?;
+        println!();
+        println!("Saved plan to {} (copy it with --run-plan)", path.display());
+
+        return Ok(Outcome::Success);
+    }
+
+    if options.dry_run {
+        println!();
+        print_collisions(&config, &index, &remotes, &collisions);
+
+        return Ok(Outcome::Success);
+    }
+
+    if options.verify {
+        let complete =
+            print_integrity_report(&config, &index, &remotes, &fmt_size, options.verify_content);
+
+        return Ok(if complete {
+            Outcome::Success
+        } else {
+            Outcome::VerifyFailed
+        });
+    }
+
+    if config.settings.preflight_targets && !preflight_targets(&config, &index, &remotes) {
+        if !options.force {
+            bail!("some targets are unreachable or not writable (use --force to copy anyway)");
+        }
+
+        eprintln!(
+            "WARNING: Some targets are unreachable or not writable, continuing because of --force"
+        );
+    }
+
+    if !options.continue_ && !state.is_empty() {
+        let files = index.iter().flat_map(|(source, context)| {
+            context
+                .copy_instructions
+                .iter()
+                .map(move |(from, instr)| (source.as_str(), from.as_path(), instr.file_size))
+        });
+        let changes = state.changes(files, removed.len());
+
+        println!(
+            "Changes since last run: {} new, {} modified, {} deleted",
+            changes.new, changes.modified, changes.deleted
+        );
+    }
+
+    if !options.yes {
+        print!("Continue? [y/N] ");
+        stdout().flush().unwrap();
+
+        let mut buf = String::new();
+        stdin().read_line(&mut buf).unwrap();
+
+        let input = buf.trim();
+        let is_yes = input.starts_with("Y") || input.starts_with("y");
+
+        if !is_yes {
+            println!("Cancelled");
+            return Ok(Outcome::Cancelled);
+        }
+    }
+
+    let manifest = copy_files(
+        &config,
+        &index,
+        &remotes,
+        SavedProgress {
+            version: PROGRESS_VERSION,
+            config: config_digest,
+            index: index_digest,
+            sources: progress,
+            in_flight: Default::default(),
+            interrupted,
+        },
+        completed,
+        total,
+        &options,
+    )?;
+    manifest.save(Path::new("manifest.yaml"))?;
+
+    summary::print_results(&manifest, &fmt_size);
+    println!("Saved manifest to manifest.yaml");
+
+    if let Some(reason) = &manifest.stopped {
+        // the files not attempted are neither backed up nor removed; deletions wait for a
+        // complete run
+        state.update(&manifest, &[]);
+        state.save(Path::new("state.yaml"))?;
+
+        bail!("{}", reason);
+    }
+
+    if config.settings.integrity_report {
+        print_integrity_report(&config, &index, &remotes, &fmt_size, false);
+    }
+
+    // without `sync_deletions`, removed files are forgotten (and their copies kept)
+    let forget = if config.settings.sync_deletions {
+        let deleted = delete_removed(&config, &index, &remotes, removed);
+        println!("Files deleted at targets: {}", deleted.len());
+
+        deleted
+    } else {
+        removed
+    };
+    state.update(&manifest, &forget);
+    state.save(Path::new("state.yaml"))?;
+
+    let outcome = Outcome::of(&manifest);
+    // a retry only copies some files, so it doesn't count for `--since last`
+    if outcome == Outcome::Success && !options.retry_failed {
+        let last_run = LastRun {
+            started: DateTime::<Local>::from(started).to_rfc3339(),
+        };
+        serde_yaml::to_writer(
+            File::create("last_run.yaml").context("cannot create last_run.yaml")?,
+            &last_run,
+        )?;
+    }
+
+    Ok(outcome)
+}
+
+/// Checks that every target files will be copied to exists and is writable, reporting those that
+/// aren't. Missing target folders aren't created, as they usually are the mount point of a drive
+/// that isn't mounted (and copying would fill the file system below it instead).
+fn preflight_targets(config: &Config, index: &Index, remotes: &Remotes) -> bool {
+    let targets = index
+        .values()
+        .flat_map(|context| context.file_size_per_target.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut all_writable = true;
+    for name in targets {
+        let pool = remotes.get(name);
+        let exists = config.target(name).and_then(|target| match pool {
+            Some(pool) => pool.is_dir(&target.path),
+            None => Ok(target.path.is_dir()),
+        });
+        let result = match exists {
+            Ok(true) => config.target(name).and_then(|target| match pool {
+                Some(pool) => pool.check_writable(&target.path),
+                None => Ok(check_writable(&target.path)?),
+            }),
+            Ok(false) => {
+                eprintln!(
+                    "Target '{}' is unreachable: {} doesn't exist (is the drive mounted?)",
+                    name,
+                    config.targets[name].path.display()
+                );
+                all_writable = false;
+                continue;
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Target '{}' is not writable: {:#}", name, e);
+            all_writable = false;
+        }
+    }
+
+    all_writable
+}
+
+/// One row of `--export-index`.
+#[derive(Serialize)]
+struct ExportedInstruction<'a> {
+    source: &'a str,
+    from: &'a Path,
+    to: &'a Path,
+    size: u64,
+    target: &'a str,
+}
+
+/// Writes one row per copy instruction, ordered by source and original path.
+fn export_index(index: &Index, path: &Path, format: ExportFormat) -> Result<()> {
+    let mut rows = index
+        .iter()
+        .flat_map(|(source, context)| {
+            context
+                .copy_instructions
+                .iter()
+                .map(move |(from, instr)| ExportedInstruction {
+                    source,
+                    from,
+                    to: &instr.to,
+                    size: instr.file_size,
+                    target: &instr.target,
+                })
+        })
+        .collect::<Vec<_>>();
+    rows.sort_unstable_by_key(|row| (row.source, row.from));
+
+    let mut out = String::new();
+    match format {
+        ExportFormat::Csv => {
+            out.push_str("source,from,to,size,target\n");
+            for row in &rows {
+                out.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    csv_field(row.source),
+                    csv_field(&row.from.to_string_lossy()),
+                    csv_field(&row.to.to_string_lossy()),
+                    row.size,
+                    csv_field(row.target)
+                ));
+            }
+        }
+        ExportFormat::Json => {
+            out = serde_json::to_string_pretty(&rows)?;
+            out.push('\n');
+        }
+    }
+
+    std::fs::write(path, out)
+        .with_context(|| format!("cannot write exported index to {}", path.display()))
+}
+
+/// Lists destinations that several files would be copied to and existing files on the targets
+/// that would be overwritten.
+fn print_collisions(config: &Config, index: &Index, remotes: &Remotes, collisions: &[Collision]) {
+    println!("Destination collisions: {}", collisions.len());
+    for collision in collisions {
+        println!("  {}", collision.to.display());
+        for (source, from) in &collision.from {
+            println!("    from '{}': {}", source, from.display());
+        }
+    }
+
+    let mut overwritten = index
+        .values()
+        .flat_map(|context| context.copy_instructions.iter())
+        // SFTP targets aren't checked
+        .filter(|(_, instr)| remotes.get(&instr.target).is_none())
+        .filter(|(from, instr)| {
+            instr.to.exists() && !(config.settings.skip_identical && is_identical(from, &instr.to))
+        })
+        .map(|(from, instr)| (&instr.to, from))
+        .collect::<Vec<_>>();
+    overwritten.sort_unstable();
+
+    println!();
+    println!(
+        "Existing files that would be overwritten: {}",
+        overwritten.len()
+    );
+    for (to, from) in overwritten {
+        println!("  {} (by {})", to.display(), from.display());
+    }
+}
+
+fn print_destination_tree(config: &Config, index: &Index) {
+    let mut paths_per_target: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+    for instr in index
+        .values()
+        .flat_map(|context| context.copy_instructions.values())
+    {
+        paths_per_target
+            .entry(&instr.target)
+            .or_default()
+            .push(&instr.to);
+    }
+
+    for (target, paths) in paths_per_target {
+        let root = config
+            .targets
+            .get(target)
+            .map(|t| t.path.as_path())
+            .unwrap_or_else(|| Path::new(""));
+
+        println!("Target '{}'", target);
+        println!("{}", tree::render(root, paths));
+    }
+}
+
+/// Whether to draw progress bars; not when they're disabled or stderr isn't a terminal (e.g. a log
+/// file), where they would only leave escape codes.
+fn show_progress(options: &Options) -> bool {
+    !options.no_progress && stderr().is_terminal()
+}
+
+fn build_index(
+    config: &Config,
+    since: Option<SystemTime>,
+    verbose: bool,
+    show_progress: bool,
+    profile: Option<&Profile>,
+) -> Result<Index> {
+    println!("Building indices...");
+
+    let multi_progress_bar = if show_progress {
+        MultiProgress::new()
+    } else {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    };
+    let sty = ProgressStyle::default_spinner()
+        // For more spinners check out the cli-spinners project:
+        // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
+        .tick_strings(&[
+            "▹▹▹▹▹",
+            "▸▹▹▹▹",
+            "▹▸▹▹▹",
+            "▹▹▸▹▹",
+            "▹▹▹▸▹",
+            "▹▹▹▹▸",
+            "▪▪▪▪▪",
+        ])
+        .template("{spinner:.blue} {msg}")
+        .unwrap();
+
+    for (outer, inner) in config.overlapping_sources() {
+        let message = format!(
+            "source '{}' lies within source '{}', its files would be copied twice",
+            inner, outer
+        );
+        if config.settings.strict {
+            bail!(message);
+        }
+        eprintln!("WARNING: {}", message);
+    }
+
+    if let Some(dir) = &config.settings.cache_dir {
+        metadata_cache::init(dir)?;
+    }
+    if let Some(rate) = config.settings.sample_rate {
+        println!(
+            "Only copying a sample of {}% of the files (sample_rate)",
+            (rate * 10000.0).round() / 100.0
+        );
+    }
+    let log_files = LogFiles::default();
+    let target_contents = TargetContents::scan(config)?;
+
+    let index_source = |(name, source): (&String, &Source)| -> Result<(String, SourceIndex)> {
+        if source.disabled {
+            return Ok((name.to_owned(), Default::default()));
+        }
+
+        println!("Building index for source '{}'...", name);
+
+        let pb = multi_progress_bar.add(ProgressBar::new_spinner());
+        pb.enable_steady_tick(Duration::from_secs(120));
+        pb.set_style(sty.clone());
+        pb.set_message(format!("{}...", name));
+
+        let mut context = Default::default();
+        let walk = Walk {
+            config,
+            src_name: name,
+            src: source,
+            log_files: &log_files,
+            pb: &pb,
+            since,
+            verbose,
+            profile,
+        };
+
+        walk_dir(&walk, &source.path, &mut context)?;
+
+        let skipped = context.skip_present(&target_contents)?;
+        if skipped > 0 {
+            println!(
+                "Skipping {} files of source '{}' that are already on their target",
+                skipped, name
+            );
+        }
+        if let Some(n) = source.keep_newest {
+            context.keep_newest(n);
+        }
+
+        pb.finish_with_message(format!("{}... Done", name));
+
+        println!("Building index for source '{}'... Done", name);
+
+        Ok((name.to_owned(), context.into()))
+    };
+
+    // sequential sources are indexed one after another, alongside the others
+    let (sequential, parallel): (Vec<_>, Vec<_>) = config
+        .sources
+        .iter()
+        .partition(|(_, source)| source.sequential);
+    let build = || -> Result<Index> {
+        let (sequential, parallel) = rayon::join(
+            || {
+                sequential
+                    .into_iter()
+                    .map(index_source)
+                    .collect::<Result<Vec<_>>>()
+            },
+            || {
+                parallel
+                    .into_par_iter()
+                    .map(index_source)
+                    .collect::<Result<Vec<_>>>()
+            },
+        );
+
+        Ok(sequential?.into_iter().chain(parallel?).collect())
+    };
+    let mut index = match config.settings.index_jobs {
+        Some(jobs) => ThreadPoolBuilder::new()
+            .num_threads(jobs.max(1))
+            .build()?
+            .install(build)?,
+        None => build()?,
+    };
+    assign_counters(&mut index);
+    if let Some(policy) = config.settings.on_duplicate_source {
+        let dropped = resolve_duplicate_sources(&mut index, policy);
+        if dropped > 0 {
+            println!(
+                "Not copying {} files with the same destination as a file of another source (on_duplicate_source)",
+                dropped
+            );
+        }
+    }
+    resolve_case_collisions(&mut index, config.settings.case_collisions)?;
+    order_by_priority(&mut index, config);
+    save_index(&index)?;
+    metadata_cache::save()?;
+
+    multi_progress_bar.clear().unwrap();
+
+    println!("Building indices... Done (saved to index.yaml)");
+
+    Ok(index)
+}
+
+/// Copies all files of the index. The copies in progress when an earlier run was interrupted
+/// (`saved.interrupted`, empty unless resuming) are continued instead of copied again. The files
+/// of `completed` sources are skipped without looking at them.
+fn copy_files(
+    config: &Config,
+    index: &Index,
+    remotes: &Remotes,
+    saved: SavedProgress,
+    completed: Completed,
+    total_size: u64,
+    options: &Options,
+) -> Result<Manifest> {
+    println!("Copying files...");
+    let progress = &saved.sources;
+    let interrupted = &saved.interrupted;
+
+    let conflict_prompt = options.interactive.then(ConflictPrompt::default);
+    let conflict_prompt = conflict_prompt.as_ref();
+    let progress_stream = options
+        .progress_json
+        .as_deref()
+        .map(|path| {
+            let mut target_bytes_total = HashMap::new();
+            for (source, context) in index.iter().filter(|(s, _)| !completed.contains_key(*s)) {
+                let already_copied = progress[source].load(Ordering::SeqCst) as usize;
+                for instr in context.copy_instructions.values().skip(already_copied) {
+                    *target_bytes_total.entry(instr.target.clone()).or_default() += instr.file_size;
+                }
+            }
+
+            ProgressStream::create(path, target_bytes_total)
+        })
+        .transpose()?;
+    let progress_stream = progress_stream.as_ref();
+
+    let stale_parts = remove_stale_parts(config, index, remotes, interrupted);
+    if stale_parts > 0 {
+        println!("Removed {} stale part files", stale_parts);
+    }
+
+    // one priority after the other, highest first; a level is needed even without instructions, so
+    // that sources without files are marked as completed
+    let priority = |target: &str| config.targets.get(target).map_or(0, |t| t.priority);
+    let priority = &priority;
+    let mut levels = index
+        .values()
+        .flat_map(|context| context.copy_instructions.values())
+        .map(|instr| priority(&instr.target))
+        .collect::<Vec<_>>();
+    levels.sort_unstable_by_key(|&level| Reverse(level));
+    levels.dedup();
+    if levels.is_empty() {
+        levels.push(0);
+    }
+
+    let done = Mutex::new(false);
+    let finished = Condvar::new();
+    let done = &done;
+    let finished = &finished;
+    let completed = Mutex::new(completed);
+    let completed = &completed;
+
+    let show_progress = show_progress(options);
+    let pb = if show_progress {
+        ProgressBar::new(total_size)
+    } else {
+        ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden())
+    };
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{wide_bar.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+    let pb = &pb;
+
+    let copy_run = CopyRun::new(
+        config,
+        remotes,
+        &saved,
+        conflict_prompt,
+        progress_stream,
+        pb,
+        options,
+    );
+    let run = &copy_run;
+    let saved = &saved;
+
+    let sources = rayon::scope(|scope| {
+        scope.spawn(move |_scope| {
+            let mut done = done.lock();
+            while !*done {
+                if finished
+                    .wait_for(&mut done, Duration::from_secs(15))
+                    .timed_out()
+                {
+                    let _ = saved.save();
+                    // instead of the progress bar
+                    if !show_progress {
+                        let size_opts = config.settings.file_size_style.to_file_size_opts();
+                        println!(
+                            "Copying files... {} of {} ({:.0}%)",
+                            pb.position().file_size(size_opts).unwrap(),
+                            total_size.file_size(size_opts).unwrap(),
+                            100.0 * pb.position() as f64 / total_size.max(1) as f64
+                        );
+                    }
+                }
+            }
+        });
+
+        let mut sources = BTreeMap::<String, Vec<ManifestEntry>>::new();
+        for (phase, &level) in levels.iter().enumerate() {
+            if run.cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let phase_entries = index
+                .par_iter()
+                .map(move |(source, context)| {
+                    let src_progress: &AtomicU32 = &progress[source];
+                    let already_copied = src_progress.load(Ordering::SeqCst) as usize;
+                    if completed.lock().contains_key(source) {
+                        // completed by an earlier run, or during an earlier phase
+                        if phase > 0 {
+                            return (source.clone(), Vec::new());
+                        }
+                        let entries = context
+                            .copy_instructions
+                            .iter()
+                            .map(|(from, instr)| {
+                                instr.manifest_entry(from, CopyStatus::SkippedIncremental, None)
+                            })
+                            .collect();
+
+                        return (source.clone(), entries);
+                    }
+
+                    let entries = context
+                        .copy_instructions
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (_, instr))| priority(&instr.target) == level)
+                        .take_while(|_| !run.cancelled.load(Ordering::SeqCst))
+                        .map(|(i, (from, instr))| {
+                            if i < already_copied {
+                                instr.manifest_entry(from, CopyStatus::SkippedIncremental, None)
+                            } else {
+                                run.copy(source, from, instr)
+                            }
+                        })
+                        .collect();
+
+                    let files = context.copy_instructions.keys().count();
+                    if src_progress.load(Ordering::SeqCst) as usize == files {
+                        let mut completed = completed.lock();
+                        completed.insert(source.clone(), files);
+                        let _ = write_yaml_atomically("completed.yaml", &*completed);
+                    }
+
+                    (source.clone(), entries)
+                })
+                .collect::<Vec<_>>();
+            for (source, entries) in phase_entries {
+                sources.entry(source).or_default().extend(entries);
+            }
+        }
+
+        *done.lock() = true;
+        finished.notify_all();
+
+        sources
+    });
+
+    let (stopped, out_of_space, targets) = copy_run.finish();
+
+    if stopped.is_some() {
+        // kept for --continue
+        pb.abandon();
+        let _ = saved.save();
+    } else {
+        if config.settings.keep_progress {
+            let _ = saved.save();
+        } else {
+            let _ = std::fs::remove_file("progress.yaml");
+            let _ = std::fs::remove_file("completed.yaml");
+        }
+        if !config.settings.keep_index {
+            let _ = std::fs::remove_file("index.yaml");
+        }
+
+        pb.finish_with_message("copied");
+    }
+    if let Some(stream) = progress_stream.filter(|_| stopped.is_none()) {
+        stream.finish();
+    }
+
+    println!(
+        "Copying files... {}",
+        if stopped.is_some() { "Stopped" } else { "Done" }
+    );
+
+    Ok(Manifest {
+        sources,
+        out_of_space,
+        targets,
+        stopped,
+    })
+}
+
+/// What the sources copied at the same time share while copying their files.
+struct CopyRun<'a> {
+    config: &'a Config,
+    remotes: &'a Remotes,
+    saved: &'a SavedProgress,
+    conflict_prompt: Option<&'a ConflictPrompt>,
+    progress_stream: Option<&'a ProgressStream>,
+    pb: &'a ProgressBar,
+    default_target: Target,
+    target_jobs: HashMap<&'a str, Semaphore>,
+    out_of_space: Mutex<BTreeSet<String>>,
+    target_stats: Mutex<BTreeMap<String, TargetStats>>,
+    /// With `fail_fast` or `max_errors`, the failure reaching the limit stops all sources before
+    /// their next file.
+    max_errors: Option<usize>,
+    failures: AtomicUsize,
+    cancelled: AtomicBool,
+    stop_error: Mutex<Option<anyhow::Error>>,
+}
+
+impl<'a> CopyRun<'a> {
+    fn new(
+        config: &'a Config,
+        remotes: &'a Remotes,
+        saved: &'a SavedProgress,
+        conflict_prompt: Option<&'a ConflictPrompt>,
+        progress_stream: Option<&'a ProgressStream>,
+        pb: &'a ProgressBar,
+        options: &Options,
+    ) -> Self {
+        CopyRun {
+            config,
+            remotes,
+            saved,
+            conflict_prompt,
+            progress_stream,
+            pb,
+            default_target: Target::default(),
+            target_jobs: config
+                .targets
+                .iter()
+                .filter_map(|(name, target)| {
+                    Some((name.as_str(), Semaphore::new(target.jobs?.max(1))))
+                })
+                .collect(),
+            out_of_space: Mutex::new(BTreeSet::new()),
+            target_stats: Mutex::new(BTreeMap::new()),
+            max_errors: if config.settings.fail_fast {
+                Some(1)
+            } else {
+                options.max_errors.or(config.settings.max_errors)
+            },
+            failures: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            stop_error: Mutex::new(None),
+        }
+    }
+
+    /// Copies the file `from` of `source` as instructed by `instr` and counts it as done.
+    fn copy(&self, source: &str, from: &Path, instr: &CopyInstruction) -> ManifestEntry {
+        let config = self.config;
+        let remotes = self.remotes;
+        let saved = self.saved;
+        let src_progress: &AtomicU32 = &saved.sources[source];
+        let entry = |status, error| instr.manifest_entry(from, status, error);
+
+        let mut to = &instr.to;
+        let target = config
+            .targets
+            .get(&instr.target)
+            .unwrap_or(&self.default_target);
+        // once a target is full, the other targets are still copied to
+        let target_full = self.out_of_space.lock().contains(&instr.target);
+        let _permit = self
+            .target_jobs
+            .get(instr.target.as_str())
+            .filter(|_| !target_full)
+            .map(Semaphore::acquire);
+        let resume_offset = saved
+            .interrupted
+            .get(source)
+            .filter(|_| remotes.get(&instr.target).is_none())
+            .and_then(|file| file.resume_offset(from, to));
+        // remote targets and resumed copies are never asked about
+        let is_conflict = || {
+            remotes.get(&instr.target).is_none()
+                && resume_offset.is_none()
+                && to.exists()
+                && !(config.settings.skip_identical && is_identical(from, to))
+        };
+        let resolution = match self.conflict_prompt {
+            Some(prompt) if !target_full && is_conflict() => prompt.resolve(from, to, self.pb),
+            _ => Ok(Resolution::Overwrite),
+        };
+        let renamed;
+        let started = Instant::now();
+        let result = match (resolution, remotes.get(&instr.target)) {
+            _ if target_full => Ok(CopyStatus::SkippedTargetFull),
+            (Err(e), _) => Err(e),
+            (Ok(Resolution::Skip), _) => Ok(CopyStatus::SkippedConflict),
+            (Ok(Resolution::Rename(path)), _) => {
+                renamed = path;
+                to = &renamed;
+                copy_file(from, to, &config.settings, target)
+            }
+            (Ok(Resolution::Overwrite), Some(pool)) => {
+                pool.copy(from, to, &config.settings, target)
+            }
+            (Ok(Resolution::Overwrite), None) => {
+                let _ = std::fs::create_dir_all(to.parent().unwrap());
+                let in_flight = InFlightFile::new(from, to, target);
+                if let Some(file) = in_flight {
+                    saved.in_flight.0.lock().insert(source.to_owned(), file);
+                }
+                let result = match resume_offset {
+                    Some(offset) => resume_copy(from, to, offset, &config.settings, target),
+                    None => copy_file(from, to, &config.settings, target),
+                };
+                if result.is_ok() {
+                    saved.in_flight.0.lock().remove(source);
+                }
+
+                result
+            }
+        };
+        if let Ok(CopyStatus::Copied | CopyStatus::Verified) = result {
+            self.target_stats
+                .lock()
+                .entry(instr.target.clone())
+                .or_default()
+                .add(instr.file_size, started.elapsed());
+        }
+        let mut stopped = false;
+        let entry = match result {
+            Ok(status) => ManifestEntry {
+                to: to.clone(),
+                ..entry(status, None)
+            },
+            Err(e) => {
+                eprintln!(
+                    "Failed to copy {} to {}: {:#}",
+                    from.display(),
+                    to.display(),
+                    e
+                );
+                if is_storage_full(&e) {
+                    self.out_of_space.lock().insert(instr.target.clone());
+                }
+
+                let entry = entry(CopyStatus::Failed, Some(format!("{:#}", e)));
+                let failed = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+                stopped = self.max_errors.is_some_and(|max| failed >= max);
+                if stopped && !self.cancelled.swap(true, Ordering::SeqCst) {
+                    *self.stop_error.lock() = Some(e.context(format!(
+                        "failed to copy {} to {}",
+                        from.display(),
+                        to.display()
+                    )));
+                }
+
+                entry
+            }
+        };
+        // a file that stopped the copy is copied again by --continue
+        if !stopped {
+            src_progress.fetch_add(1, Ordering::SeqCst);
+        }
+        self.pb.inc(instr.file_size);
+        if let Some(stream) = self.progress_stream {
+            stream.file(source, &entry);
+        }
+
+        entry
+    }
+
+    /// Returns why copying was stopped early, if it was, the targets that ran out of space and
+    /// what was copied to each target.
+    fn finish(
+        self,
+    ) -> (
+        Option<String>,
+        BTreeSet<String>,
+        BTreeMap<String, TargetStats>,
+    ) {
+        let failures = self.failures.load(Ordering::SeqCst);
+        let fail_fast = self.config.settings.fail_fast;
+        let max_errors = self.max_errors;
+        let stopped = self.stop_error.into_inner().map(|e| {
+            let reason = match max_errors {
+                Some(1) if fail_fast => "the first failure (fail_fast is set)".to_owned(),
+                max => format!(
+                    "{} failures (max_errors is {})",
+                    failures,
+                    max.unwrap_or_default()
+                ),
+            };
+
+            format!(
+                "{:#}",
+                e.context(format!(
+                    "stopped copying after {}, resume with --continue",
+                    reason
+                ))
+            )
+        });
+
+        (
+            stopped,
+            self.out_of_space.into_inner(),
+            self.target_stats.into_inner(),
+        )
+    }
+}
+
+/// Removes the part files left next to the destinations of local `staging` targets by earlier
+/// runs, except those of the `interrupted` copies that are continued.
+fn remove_stale_parts(
+    config: &Config,
+    index: &Index,
+    remotes: &Remotes,
+    interrupted: &BTreeMap<String, InFlightFile>,
+) -> usize {
+    let staging = |target: &str| {
+        remotes.get(target).is_none() && config.targets.get(target).is_some_and(|t| t.staging)
+    };
+
+    let mut removed = 0;
+    for (source, context) in index {
+        let continued = interrupted.get(source).map(|file| &file.dest);
+        for instr in context.copy_instructions.values() {
+            let part = part_path(&instr.to);
+            if !staging(&instr.target) || continued == Some(&part) {
+                continue;
+            }
+
+            match std::fs::remove_file(&part) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("WARNING: Cannot remove {}: {}", part.display(), e),
+            }
+        }
+    }
+
+    removed
+}
+
+/// Lists of missing and unexpected files are shortened to this many entries.
+const MAX_REPORTED_FILES: usize = 20;
+
+/// Compares the files expected on each target with the files present there now, with
+/// `compare_content` also the content of those of the right size with their originals. Returns
+/// whether all expected files are present and match.
+fn print_integrity_report(
+    config: &Config,
+    index: &Index,
+    remotes: &Remotes,
+    fmt_size: &dyn Fn(u64) -> String,
+    compare_content: bool,
+) -> bool {
+    let mut expected = HashMap::<&str, HashMap<PathBuf, u64>>::new();
+    let mut originals = HashMap::<&Path, &Path>::new();
+    for (from, instr) in index
+        .values()
+        .flat_map(|context| context.copy_instructions.iter())
+    {
+        expected
+            .entry(instr.target.as_str())
+            .or_default()
+            .insert(instr.to.clone(), instr.file_size);
+        originals.insert(&instr.to, from);
+    }
+    let mut expected = expected.into_iter().collect::<Vec<_>>();
+    expected.sort_unstable_by_key(|(target, _)| *target);
+
+    let print_files = |label: &str, files: &BTreeSet<PathBuf>| {
+        if files.is_empty() {
+            return;
+        }
+
+        println!("    {}:", label);
+        for file in files.iter().take(MAX_REPORTED_FILES) {
+            println!("      {}", file.display());
+        }
+        if files.len() > MAX_REPORTED_FILES {
+            println!("      ... ({} more)", files.len() - MAX_REPORTED_FILES);
+        }
+    };
+
+    println!();
+    println!("Integrity report:");
+    let mut complete = true;
+    for (target, expected) in expected {
+        let root = match config.targets.get(target) {
+            Some(target) => &target.path,
+            None => continue,
+        };
+        if remotes.get(target).is_some() {
+            println!("  Target '{}': not checked (SFTP target)", target);
+            continue;
+        }
+        let mut report = check_target(root, &expected);
+        if compare_content {
+            let same_size = expected
+                .keys()
+                .filter(|to| !report.missing.contains(*to) && !report.mismatched.contains(*to))
+                .map(|to| (originals[to.as_path()], to.as_path()))
+                .collect();
+            report.mismatched.extend(differing_content(same_size));
+        }
+
+        println!(
+            "  Target '{}': {} of {} files present ({} of {}), {} missing, {} mismatched, {} unexpected",
+            target,
+            report.present_files,
+            report.expected_files,
+            fmt_size(report.present_bytes),
+            fmt_size(report.expected_bytes),
+            report.missing.len(),
+            report.mismatched.len(),
+            report.unexpected.len()
+        );
+        print_files("Missing", &report.missing);
+        print_files("Mismatched", &report.mismatched);
+        print_files("Unexpected", &report.unexpected);
+        complete &= report.missing.is_empty() && report.mismatched.is_empty();
+    }
+
+    complete
+}
+
+/// Lists the files only on target `a` or `b` (by path below the target) and those of different
+/// sizes on both (`--compare-targets`).
+fn print_target_diff(config: &Config, remotes: &Remotes, a: &str, b: &str) -> Result<()> {
+    for name in [a, b] {
+        if remotes.get(name).is_some() {
+            bail!("cannot compare SFTP target '{}'", name);
+        }
+    }
+    let (root_a, root_b) = (&config.target(a)?.path, &config.target(b)?.path);
+    for root in [root_a, root_b] {
+        if !root.is_dir() {
+            bail!("{} doesn't exist", root.display());
+        }
+    }
+
+    println!("Comparing target '{}' with target '{}'...", a, b);
+    let diff = compare_trees(root_a, root_b);
+
+    let print_files = |label: String, files: &BTreeSet<PathBuf>| {
+        println!("{}: {}", label, files.len());
+        for file in files {
+            println!("  {}", file.display());
+        }
+    };
+    print_files(
+        format!("Only on '{}' ({})", a, root_a.display()),
+        &diff.only_in_a,
+    );
+    print_files(
+        format!("Only on '{}' ({})", b, root_b.display()),
+        &diff.only_in_b,
+    );
+    print_files("Different size".to_owned(), &diff.different_size);
+
+    Ok(())
+}
+
+/// Deletes the copies of files removed from their sources and returns those that are gone.
+///
+/// Copies outside of their target and paths another file has just been copied to are kept.
+fn delete_removed(
     config: &Config,
-    src_name: &str,
-    src: &Source,
-    dir_path: &Path,
-    context: &mut Context,
-    pb: &ProgressBar,
-) -> Result<()> {
-    for entry in WalkDir::new(dir_path).min_depth(1).max_depth(1) {
+    index: &Index,
+    remotes: &Remotes,
+    removed: Vec<Removed>,
+) -> Vec<Removed> {
+    let copied_to = index
+        .values()
+        .flat_map(|context| context.copy_instructions.values())
+        .map(|instr| instr.to.as_path())
+        .collect::<HashSet<_>>();
+
+    removed
+        .into_iter()
+        .filter(|removed| {
+            let to = &removed.backed_up.to;
+            let in_target = config
+                .targets
+                .get(&removed.backed_up.target)
+                .is_some_and(|target| is_root_path_of(to, &target.path));
+            if !in_target {
+                eprintln!(
+                    "WARNING: Not deleting {} which is not part of target '{}'",
+                    to.display(),
+                    removed.backed_up.target
+                );
+                return false;
+            }
+            if copied_to.contains(to.as_path()) {
+                return true;
+            }
+
+            let result = match remotes.get(&removed.backed_up.target) {
+                Some(pool) => pool.remove_file(to),
+                None => match std::fs::remove_file(to) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    result => result.map_err(Into::into),
+                },
+            };
+            if let Err(e) = &result {
+                eprintln!("Failed to delete {}: {:#}", to.display(), e);
+            }
+
+            result.is_ok()
+        })
+        .collect()
+}
+
+fn is_storage_full(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    })
+}
+
+/// Everything `walk_dir` needs that stays the same while walking a source.
+struct Walk<'a> {
+    config: &'a Config,
+    src_name: &'a str,
+    src: &'a Source,
+    log_files: &'a LogFiles,
+    pb: &'a ProgressBar,
+    /// Only files modified after this are considered (`--since`).
+    since: Option<SystemTime>,
+    /// Print the file group and rule of every file (`--verbose`).
+    verbose: bool,
+    /// Where the time spent in filters is recorded (`--profile`).
+    profile: Option<&'a Profile>,
+}
+
+/// A folder being walked by `walk_dir`.
+struct WalkedDir {
+    path: PathBuf,
+    /// To notice when a (symlinked) folder leads back to one of its parents.
+    canonical: Option<PathBuf>,
+    /// Read at once, so no file handles are kept open for the parents of deep folders.
+    entries: std::vec::IntoIter<walkdir::Result<walkdir::DirEntry>>,
+}
+
+impl WalkedDir {
+    fn read(path: PathBuf, canonical: Option<PathBuf>) -> Self {
+        WalkedDir {
+            canonical,
+            entries: WalkDir::new(&path)
+                .min_depth(1)
+                .max_depth(1)
+                .into_iter()
+                .collect::<Vec<_>>()
+                .into_iter(),
+            path,
+        }
+    }
+}
+
+/// Walks `dir_path` and the folders below it using the `Traverse` rule. The folders being walked
+/// are kept in a list instead of recursing, so deep trees can't overflow the stack.
+fn walk_dir(walk: &Walk, dir_path: &Path, context: &mut Context) -> Result<()> {
+    let Walk {
+        config,
+        src_name,
+        src,
+        log_files,
+        pb,
+        since,
+        verbose,
+        profile,
+    } = *walk;
+
+    let root = WalkedDir::read(dir_path.to_owned(), dir_path.canonicalize().ok());
+    let mut ancestors = root.canonical.iter().cloned().collect::<HashSet<_>>();
+    let mut stack = vec![root];
+    while let Some(dir) = stack.last_mut() {
+        let entry = match dir.entries.next() {
+            Some(entry) => entry,
+            None => {
+                if let Some(canonical) = stack.pop().and_then(|dir| dir.canonical) {
+                    ancestors.remove(&canonical);
+                }
+                continue;
+            }
+        };
+
         if let Ok(entry) = entry {
+            let is_symlink = entry.path_is_symlink();
+            let is_file = entry.file_type().is_file();
             let path = entry.into_path();
             let sub_path = diff_paths(&path, &src.path).unwrap();
 
-            if src.ignore_paths.iter().any(|x| *x == sub_path) {
+            let ignored_name = path.file_name().is_some_and(|name| {
+                config.settings.is_ignored_file_name(name)
+                    || (is_file && config.settings.is_skipped_apple_double(name))
+            });
+            if ignored_name || src.ignore_paths.contains(&sub_path) {
                 //println!("[{}]: Ignore {}", src_name, sub_path.display());
                 //context.ignored.push(sub_path);
                 continue;
@@ -374,95 +2378,843 @@ fn walk_dir(
             let mut fp = FilePath::new(&src.path, sub_path);
             assert_eq!(path, fp.full_path);
 
-            let rule = if let Some((_group_name, file_group)) = config.file_group(src_name, &mut fp)
+            if let Some(since) = since {
+                let modified = fp.metadata().and_then(|m| m.modified().ok());
+                if !fp.full_path.is_dir() && modified.is_some_and(|modified| modified <= since) {
+                    continue;
+                }
+            }
+
+            let (group_name, rule) = if let Some((group_name, file_group)) =
+                config.file_group(src_name, &mut fp, profile)
             {
-                &file_group.rule
+                (group_name, &file_group.rule)
+            } else if fp.full_path.is_dir() {
+                ("", &Rule::Traverse)
             } else {
-                if fp.full_path.is_dir() {
-                    &Rule::Traverse
-                } else {
-                    &Rule::Ignore
-                }
+                ("", &Rule::Ignore)
             };
 
             pb.tick();
+            if verbose {
+                let group = match group_name {
+                    "" => "no group matched".to_owned(),
+                    name => format!("matched group '{}'", name),
+                };
+                pb.suspend(|| {
+                    println!(
+                        "[{}] {}: {} -> {}",
+                        src_name,
+                        fp.path.display(),
+                        group,
+                        rule
+                    )
+                });
+            }
 
             match rule {
                 Rule::Ignore => {}
+                _ if context.skip_outside_size_range(rule, &mut fp) => {}
                 Rule::CopyExact {
                     target,
-                    skip_duplicates: ignore_duplicates,
+                    skip_duplicates,
+                    ..
                 } => {
-                    if *ignore_duplicates && context.check_duplicate(&fp.full_path)? {
-                        return Ok(());
-                    }
-                    let to = config.target(target)?.join(&fp.path);
-                    let file_size = fp.metadata().map(|m| m.len()).unwrap_or(0);
-                    *context
-                        .file_size_per_target
-                        .entry(target.clone())
-                        .or_default() += file_size;
-                    context
-                        .copy_instructions
-                        .insert(fp.full_path, CopyInstruction { to, file_size });
+                    let to = config.target_path_of(target, &fp.path)?;
+                    let to = config.namespaced(src_name, target, to)?;
+                    context.add_copy_instruction(
+                        &config.settings,
+                        fp,
+                        target,
+                        to,
+                        *skip_duplicates,
+                    )?;
                 }
                 Rule::CopyTo {
                     target,
                     path,
-                    skip_duplicates: ignore_duplicates,
+                    skip_duplicates,
+                    ..
                 } => {
-                    if *ignore_duplicates && context.check_duplicate(&fp.full_path)? {
-                        return Ok(());
-                    }
                     let to = config.target_path(target, path, &mut fp)?;
-                    let file_size = fp.metadata().map(|m| m.len()).unwrap_or(0);
-                    *context
-                        .file_size_per_target
-                        .entry(target.clone())
-                        .or_default() += file_size;
-                    context
-                        .copy_instructions
-                        .insert(fp.full_path, CopyInstruction { to, file_size });
+                    let to = config.namespaced(src_name, target, to)?;
+                    context.add_copy_instruction(
+                        &config.settings,
+                        fp,
+                        target,
+                        to,
+                        *skip_duplicates,
+                    )?;
+                }
+                Rule::CopyToMatchedTarget {
+                    target,
+                    path,
+                    skip_duplicates,
+                } => {
+                    let target = config.matched_target(target, &mut fp)?;
+                    let to = config.target_path(&target, path, &mut fp)?;
+                    let to = config.namespaced(src_name, &target, to)?;
+                    context.add_copy_instruction(
+                        &config.settings,
+                        fp,
+                        &target,
+                        to,
+                        *skip_duplicates,
+                    )?;
                 }
                 Rule::Traverse => {
-                    walk_dir(config, src_name, src, &path, context, pb)?;
+                    // the folders of the stack are the ones above this one
+                    if config
+                        .settings
+                        .max_depth
+                        .is_some_and(|max| stack.len() > max)
+                    {
+                        eprintln!(
+                            "[{}]: Not walking {}, it is nested deeper than max_depth",
+                            src_name,
+                            path.display()
+                        );
+                        continue;
+                    }
+
+                    // resolving every folder would take quadratic time in deep trees
+                    let parent = stack.last().unwrap().canonical.as_ref();
+                    let canonical = match (is_symlink, parent, path.file_name()) {
+                        (false, Some(parent), Some(name)) => Some(parent.join(name)),
+                        _ => path.canonicalize().ok(),
+                    };
+                    let dir = WalkedDir::read(path, canonical);
+                    if let Some(canonical) = &dir.canonical {
+                        if !ancestors.insert(canonical.clone()) {
+                            eprintln!(
+                                "[{}]: Not walking {}, it leads back to one of its parent folders",
+                                src_name,
+                                dir.path.display()
+                            );
+                            continue;
+                        }
+                    }
+                    stack.push(dir);
                 }
                 Rule::LogFile {
                     target,
                     log_file,
                     full_path,
+                    format,
+                    columns,
+                    mode,
                 } => {
-                    let log_file = config.target_path(target, log_file, &mut fp)?;
-                    let mut file = OpenOptions::new()
-                        .create(true)
-                        .append(true)
-                        .open(&log_file)
-                        .with_context(|| {
-                            format!("Failed to open log file at {}", log_file.display())
-                        })?;
-                    let log_line = if *full_path {
-                        fp.full_path.display()
-                    } else {
-                        fp.path.display()
+                    let log_file = config.log_file_path(target, log_file, &mut fp)?;
+                    let metadata = fp.metadata();
+                    let entry = LogEntry {
+                        path: if *full_path {
+                            fp.full_path.display().to_string()
+                        } else {
+                            fp.path.display().to_string()
+                        },
+                        size: metadata.as_ref().map(|m| m.len()),
+                        modified: metadata
+                            .and_then(|m| m.modified().ok())
+                            .map(|t| DateTime::<Local>::from(t).to_rfc3339()),
+                        group: group_name,
+                        source: src_name,
                     };
-                    writeln!(file, "{}", log_line).with_context(|| {
-                        format!("Failed to write to log file {}", log_file.display())
-                    })?;
+                    log_files.write(&log_file, *format, columns, *mode, &entry)?;
                 }
             }
         } else {
-            eprintln!("{}", entry.err().unwrap());
+            let e = entry.err().unwrap();
+            eprintln!("[{}]: Cannot read: {}", src_name, e);
+            context
+                .inaccessible
+                .push(InaccessiblePath::new(&stack.last().unwrap().path, &e));
         }
     }
 
     Ok(())
 }
 
-fn main() {
-    if let Err(e) = app() {
-        eprintln!("error: {}", e);
-        e.chain()
-            .skip(1)
-            .for_each(|c| eprintln!("caused by: {}", c));
-    };
+fn main() -> ExitCode {
+    match app() {
+        Ok(outcome) => ExitCode::from(outcome as u8),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            e.chain()
+                .skip(1)
+                .for_each(|c| eprintln!("caused by: {}", c));
+
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_export_index() {
+        let mut index = Index::new();
+        let instructions = &mut index
+            .entry("home".to_owned())
+            .or_default()
+            .copy_instructions;
+        for (from, to) in [
+            ("/home/b.txt", "/backup/b.txt"),
+            ("/home/a, 1.txt", "/backup/a.txt"),
+        ] {
+            instructions.insert(
+                from.into(),
+                CopyInstruction {
+                    to: to.into(),
+                    file_size: 3,
+                    target: "usb".to_owned(),
+                },
+            );
+        }
+        let dir = TempDir::new("export");
+        let path = dir.join("index.csv");
+
+        export_index(&index, &path, ExportFormat::Csv).unwrap();
+        assert_eq!(
+            "source,from,to,size,target
+home,\"/home/a, 1.txt\",/backup/a.txt,3,usb
+home,/home/b.txt,/backup/b.txt,3,usb
+",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        export_index(&index, &path, ExportFormat::Json).unwrap();
+        let rows: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!("/backup/a.txt", rows[0]["to"]);
+        assert_eq!(3, rows[1]["size"]);
+    }
+
+    #[test]
+    fn test_assign_counters() {
+        let instr = |to: &str| CopyInstruction {
+            to: to.into(),
+            file_size: 0,
+            target: "t".to_owned(),
+        };
+        let counter = |name: &str| format!("/backup/{}_{}.jpg", name, COUNTER_PLACEHOLDER);
+        let mut index = Index::new();
+        for (source, files) in [("b", ["x", "a"]), ("a", ["z", "y"])] {
+            let source_index = index.entry(source.to_owned()).or_default();
+            for file in files {
+                source_index
+                    .copy_instructions
+                    .insert(file.into(), instr(&counter(file)));
+            }
+        }
+        index
+            .get_mut("a")
+            .unwrap()
+            .copy_instructions
+            .insert("plain".into(), instr("/backup/plain.jpg"));
+
+        assign_counters(&mut index);
+
+        let to = |source: &str, from: &str| {
+            let instr = index[source].copy_instructions.get(Path::new(from));
+            instr.unwrap().to.to_str().unwrap().to_owned()
+        };
+        assert_eq!("/backup/y_1.jpg", to("a", "y"));
+        assert_eq!("/backup/z_2.jpg", to("a", "z"));
+        assert_eq!("/backup/a_3.jpg", to("b", "a"));
+        assert_eq!("/backup/x_4.jpg", to("b", "x"));
+        assert_eq!("/backup/plain.jpg", to("a", "plain"));
+    }
+
+    #[test]
+    fn test_resolve_duplicate_sources() {
+        let dir = TempDir::new("dup");
+        let now = SystemTime::now();
+        let build = || {
+            let mut index = Index::new();
+            for (source, content, age) in
+                [("a", "big file", 60), ("b", "small", 0), ("c", "small", 60)]
+            {
+                let from = dir.join(source);
+                std::fs::write(&from, content).unwrap();
+                std::fs::File::options()
+                    .write(true)
+                    .open(&from)
+                    .unwrap()
+                    .set_modified(now - Duration::from_secs(age))
+                    .unwrap();
+                let source_index = index.entry(source.to_owned()).or_default();
+                for (from, to) in [
+                    (from, "/backup/img.jpg"),
+                    (dir.join(format!("{}-own", source)), source),
+                ] {
+                    let instr = CopyInstruction {
+                        to: to.into(),
+                        file_size: std::fs::metadata(&from).map(|m| m.len()).unwrap_or(1),
+                        target: "t".to_owned(),
+                    };
+                    *source_index
+                        .file_size_per_target
+                        .entry("t".to_owned())
+                        .or_default() += instr.file_size;
+                    source_index.copy_instructions.insert(from, instr);
+                }
+            }
+            index
+        };
+        let kept = |index: &Index| {
+            let mut kept = index
+                .iter()
+                .filter(|(_, context)| context.copy_instructions.keys().count() == 2)
+                .map(|(source, _)| source.as_str())
+                .collect::<Vec<_>>();
+            kept.sort();
+            kept.join(",")
+        };
+
+        for (policy, winner) in [
+            (DuplicateSource::Newest, "b"),
+            (DuplicateSource::Largest, "a"),
+            (DuplicateSource::First, "a"),
+        ] {
+            let mut index = build();
+            assert_eq!(2, resolve_duplicate_sources(&mut index, policy));
+            assert_eq!(winner, kept(&index), "{:?}", policy);
+            assert_eq!(1, index["c"].copy_instructions.keys().count());
+            assert_eq!(1, index["c"].file_size_per_target["t"]);
+        }
+    }
+
+    #[test]
+    fn test_resolve_case_collisions() {
+        let instr = |to: &str| CopyInstruction {
+            to: to.into(),
+            file_size: 0,
+            target: "t".to_owned(),
+        };
+        let build = || {
+            let mut index = Index::new();
+            let source_index = index.entry("s".to_owned()).or_default();
+            for (from, to) in [
+                ("Photo.jpg", "/backup/Photo.jpg"),
+                ("photo.jpg", "/backup/photo.jpg"),
+                ("other", "/backup/PHOTO (1).jpg"),
+                ("notes", "/backup/notes.txt"),
+            ] {
+                source_index
+                    .copy_instructions
+                    .insert(from.into(), instr(to));
+            }
+            index
+        };
+        let to = |index: &Index, from: &str| {
+            let instr = index["s"].copy_instructions.get(Path::new(from));
+            instr.unwrap().to.to_str().unwrap().to_owned()
+        };
+
+        let mut index = build();
+        resolve_case_collisions(&mut index, CaseCollisions::Warn).unwrap();
+        assert_eq!("/backup/photo.jpg", to(&index, "photo.jpg"));
+        assert!(resolve_case_collisions(&mut index, CaseCollisions::Error).is_err());
+
+        resolve_case_collisions(&mut index, CaseCollisions::Rename).unwrap();
+        assert_eq!("/backup/Photo.jpg", to(&index, "Photo.jpg"));
+        assert_eq!("/backup/photo (2).jpg", to(&index, "photo.jpg"));
+        assert_eq!("/backup/PHOTO (1).jpg", to(&index, "other"));
+        assert_eq!("/backup/notes.txt", to(&index, "notes"));
+        resolve_case_collisions(&mut index, CaseCollisions::Error).unwrap();
+    }
+
+    #[test]
+    fn test_order_by_priority() {
+        let yaml = r#"
+settings:
+  file_size_style: binary
+sources: {}
+targets:
+  archive: /archive
+  mirror:
+    path: /mirror
+    priority: 2
+  offsite:
+    path: /offsite
+    priority: 1
+file_groups: {}
+"#;
+        let config = Config::from_reader(yaml.as_bytes()).unwrap();
+        let mut index = Index::new();
+        let source_index = index.entry("home".to_owned()).or_default();
+        for (from, target) in [
+            ("a", "archive"),
+            ("b", "offsite"),
+            ("c", "mirror"),
+            ("d", "archive"),
+            ("e", "mirror"),
+        ] {
+            source_index.copy_instructions.insert(
+                from.into(),
+                CopyInstruction {
+                    to: Path::new("/").join(target).join(from),
+                    file_size: 0,
+                    target: target.to_owned(),
+                },
+            );
+        }
+
+        order_by_priority(&mut index, &config);
+
+        let order = index["home"]
+            .copy_instructions
+            .keys()
+            .map(|from| from.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["c", "e", "b", "a", "d"], order);
+    }
+
+    #[test]
+    fn test_sample_changed_files() {
+        let dir = TempDir::new("stale");
+        let mut index = Index::new();
+        let source_index = index.entry("home".to_owned()).or_default();
+        for name in ["copied", "same", "resized", "deleted"] {
+            std::fs::write(dir.join(name), "abc").unwrap();
+            source_index.copy_instructions.insert(
+                dir.join(name),
+                CopyInstruction {
+                    to: dir.join("backup").join(name),
+                    file_size: 3,
+                    target: "t".to_owned(),
+                },
+            );
+        }
+        let indexed_at = SystemTime::now() + Duration::from_secs(60);
+        let mut progress = Progress::new();
+        progress.insert("home".to_owned(), AtomicU32::new(1));
+
+        assert_eq!((3, 0), sample_changed_files(&index, &progress, indexed_at));
+
+        std::fs::remove_file(dir.join("copied")).unwrap();
+        std::fs::write(dir.join("resized"), "abcd").unwrap();
+        std::fs::remove_file(dir.join("deleted")).unwrap();
+        assert_eq!((3, 2), sample_changed_files(&index, &progress, indexed_at));
+        // modified after the index was built
+        let indexed_at = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!((3, 3), sample_changed_files(&index, &progress, indexed_at));
+    }
+
+    #[test]
+    fn test_inaccessible_path() {
+        let e = WalkDir::new("does-not-exist")
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap_err();
+
+        let path = InaccessiblePath::new(Path::new("."), &e);
+
+        assert_eq!(Path::new("does-not-exist"), path.path);
+        assert_eq!(WalkErrorKind::NotFound, path.kind);
+    }
+
+    fn walk_source(config: &Config) -> Context {
+        let mut context = Context::default();
+        let walk = Walk {
+            config,
+            src_name: "deep",
+            src: &config.sources["deep"],
+            log_files: &LogFiles::default(),
+            pb: &ProgressBar::hidden(),
+            since: None,
+            verbose: false,
+            profile: None,
+        };
+        walk_dir(&walk, &walk.src.path, &mut context).unwrap();
+
+        context
+    }
+
+    #[test]
+    fn test_walk_deep_tree() {
+        // as deep as the maximum path length (4096 on Linux) allows
+        const DEPTH: usize = 1500;
+        let dir = TempDir::new("deep");
+        let deepest = (0..DEPTH).fold(dir.to_path_buf(), |path, _| path.join("d"));
+        std::fs::create_dir_all(&deepest).unwrap();
+        std::fs::write(deepest.join("f"), "").unwrap();
+        std::fs::write(dir.join("d/f"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("d/loop")).unwrap();
+
+        let yaml = |max_depth: &str| {
+            format!(
+                r#"
+settings:
+  file_size_style: binary
+  max_depth: {}
+sources:
+  deep:
+    path: "{}"
+targets:
+  t: /backup
+file_groups:
+  files:
+    sources: all
+    filter: is_file
+    rule:
+      copy_exact:
+        target: t
+"#,
+                max_depth,
+                dir.display()
+            )
+        };
+        let config = Config::from_reader(yaml("~").as_bytes()).unwrap();
+        let max_depth = Config::from_reader(yaml("1").as_bytes()).unwrap();
+
+        // a small stack, which recursing per folder would overflow
+        let (all, shallow) = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || (walk_source(&config), walk_source(&max_depth)))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let files = |context: &Context| {
+            context
+                .copy_instructions
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(2, files(&all).len());
+        assert!(files(&all).contains(&deepest.join("f")));
+        assert_eq!(vec![dir.join("d/f")], files(&shallow));
+    }
+
+    #[test]
+    fn test_identity_copy_is_skipped() {
+        let dir = TempDir::new("identity");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "original").unwrap();
+        let mut context = Context::default();
+
+        // e.g. a target lying within the source
+        context
+            .add_copy_instruction(
+                &Settings::default(),
+                FilePath::new(&dir, "a.txt"),
+                "t",
+                dir.join("sub/../a.txt"),
+                false,
+            )
+            .unwrap();
+        assert!(context.copy_instructions.get(&dir.join("a.txt")).is_none());
+
+        let strict = Settings {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(context
+            .add_copy_instruction(
+                &strict,
+                FilePath::new(&dir, "a.txt"),
+                "t",
+                dir.join("a.txt"),
+                false
+            )
+            .is_err());
+
+        context
+            .add_copy_instruction(
+                &strict,
+                FilePath::new(&dir, "a.txt"),
+                "t",
+                dir.join("sub/a.txt"),
+                false,
+            )
+            .unwrap();
+        assert!(context.copy_instructions.get(&dir.join("a.txt")).is_some());
+        assert_eq!(
+            "original",
+            std::fs::read_to_string(dir.join("a.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unreadable_files_are_reported() {
+        let settings = Settings {
+            verify_readable: true,
+            ..Default::default()
+        };
+        let mut context = Context::default();
+
+        context
+            .add_copy_instruction(
+                &settings,
+                FilePath::new(".", "does-not-exist.txt"),
+                "t",
+                "/backup/does-not-exist.txt".into(),
+                false,
+            )
+            .unwrap();
+        context
+            .add_copy_instruction(
+                &settings,
+                FilePath::new(".", "Cargo.toml"),
+                "t",
+                "/backup/Cargo.toml".into(),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(1, context.copy_instructions.keys().count());
+        assert_eq!(1, context.unreadable.len());
+        assert_eq!(
+            Path::new("./does-not-exist.txt"),
+            context.unreadable[0].path
+        );
+        assert_eq!(
+            std::io::ErrorKind::NotFound.to_string(),
+            context.unreadable[0].error
+        );
+        assert!(context.file_size_per_target["t"] > 0);
+        assert_eq!(
+            context.file_size_per_target["t"],
+            context.file_size_per_extension["toml"]
+        );
+    }
+
+    #[test]
+    fn test_skip_outside_size_range() {
+        let rule = |min_size, max_size| Rule::CopyExact {
+            target: "t".to_owned(),
+            skip_duplicates: false,
+            min_size,
+            max_size,
+        };
+        let size = std::fs::metadata("Cargo.toml").unwrap().len();
+        let mut context = Context::default();
+        let mut skipped = |rule: &Rule, path: &str| {
+            context.skip_outside_size_range(rule, &mut FilePath::new(".", path))
+        };
+
+        assert!(!skipped(&rule(Some(size), Some(size)), "Cargo.toml"));
+        assert!(skipped(&rule(Some(size + 1), None), "Cargo.toml"));
+        assert!(skipped(&rule(None, Some(size - 1)), "Cargo.toml"));
+        assert!(!skipped(&rule(Some(1), None), "does-not-exist.txt"));
+        assert!(!skipped(&Rule::Traverse, "Cargo.toml"));
+
+        assert_eq!(2, context.outside_size_range.len());
+        assert_eq!(size, context.outside_size_range[0].size);
+    }
+
+    #[test]
+    fn test_keep_newest() {
+        let mut context = Context::default();
+        let now = SystemTime::now();
+        for (i, name) in ["old.txt", "newest.jpg", "unknown.txt", "new.jpg"]
+            .iter()
+            .enumerate()
+        {
+            let from = PathBuf::from(name);
+            let instr = CopyInstruction {
+                to: from.clone(),
+                file_size: 10,
+                target: "t".to_owned(),
+            };
+            context.add_size(&from, &instr);
+            context.copy_instructions.insert(from.clone(), instr);
+            if *name != "unknown.txt" {
+                context
+                    .modified
+                    .insert(from, now + Duration::from_secs(i as u64));
+            }
+        }
+
+        context.keep_newest(2);
+
+        let kept = context.copy_instructions.keys().collect::<Vec<_>>();
+        assert_eq!(vec![Path::new("newest.jpg"), Path::new("new.jpg")], kept);
+        assert_eq!(20, context.file_size_per_target["t"]);
+        assert_eq!(20, context.file_size_per_extension["jpg"]);
+        assert!(!context.file_size_per_extension.contains_key("txt"));
+    }
+
+    #[test]
+    fn test_failed_index() {
+        let entry = |from: &str, target, status| ManifestEntry {
+            from: from.into(),
+            to: Path::new("/backup").join(from),
+            ..ManifestEntry::for_test(target, status, 2)
+        };
+        let mut manifest = Manifest::default();
+        manifest.sources.insert(
+            "home".to_owned(),
+            vec![
+                entry("a", "nas", CopyStatus::Copied),
+                entry("b", "nas", CopyStatus::Failed),
+                entry("c", "usb", CopyStatus::SkippedTargetFull),
+            ],
+        );
+        manifest.sources.insert(
+            "work".to_owned(),
+            vec![entry("d", "nas", CopyStatus::SkippedIdentical)],
+        );
+        let mut targets = HashMap::new();
+        targets.insert("nas".to_owned(), Target::default());
+        targets.insert("usb".to_owned(), Target::default());
+        let mut config = Config::with_targets(targets);
+
+        let index = failed_index(&manifest, &config).unwrap();
+        assert_eq!(vec!["home"], index.keys().collect::<Vec<_>>());
+        let home = &index["home"];
+        assert_eq!(
+            vec![Path::new("b"), Path::new("c")],
+            home.copy_instructions.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Path::new("/backup/c"),
+            home.copy_instructions.get(Path::new("c")).unwrap().to
+        );
+        assert_eq!(2, home.file_size_per_target["usb"]);
+
+        config.targets.remove("usb");
+        assert!(failed_index(&manifest, &config).is_err());
+    }
+
+    #[test]
+    fn test_outcome() {
+        let entry = |status| ManifestEntry::for_test("t", status, 1);
+        let mut manifest = Manifest::default();
+        manifest
+            .sources
+            .insert("s".to_owned(), vec![entry(CopyStatus::Copied)]);
+        assert_eq!(Outcome::Success, Outcome::of(&manifest));
+
+        manifest
+            .sources
+            .get_mut("s")
+            .unwrap()
+            .push(entry(CopyStatus::Failed));
+        assert_eq!(Outcome::CopyFailed, Outcome::of(&manifest));
+
+        manifest.out_of_space.insert("t".to_owned());
+        assert_eq!(Outcome::OutOfSpace, Outcome::of(&manifest));
+
+        let full = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::StorageFull))
+            .context("cannot copy");
+        assert!(is_storage_full(&full));
+        assert!(!is_storage_full(&anyhow::anyhow!("other")));
+    }
+
+    #[test]
+    fn test_extension_key() {
+        assert_eq!("jpg", extension_key(Path::new("a/b/IMG_001.JPG")));
+        assert_eq!("gz", extension_key(Path::new("backup.tar.gz")));
+        assert_eq!("(no extension)", extension_key(Path::new("Makefile")));
+    }
+
+    #[test]
+    fn test_reconcile_progress() {
+        let index: Index = vec![
+            ("a".to_owned(), SourceIndex::default()),
+            ("b".to_owned(), SourceIndex::default()),
+        ]
+        .into_iter()
+        .collect();
+        let progress: Progress = vec![
+            ("a".to_owned(), AtomicU32::new(3)),
+            ("removed".to_owned(), AtomicU32::new(7)),
+        ]
+        .into_iter()
+        .collect();
+
+        let progress = reconcile_progress(&index, progress);
+
+        assert_eq!(2, progress.len());
+        assert_eq!(3, progress["a"].load(Ordering::SeqCst));
+        assert_eq!(0, progress["b"].load(Ordering::SeqCst));
+        assert!(!progress.contains_key("removed"));
+    }
+
+    #[test]
+    fn test_in_flight_file() {
+        let dir = TempDir::new("in-flight");
+        let from = dir.join("video.mp4");
+        let to = dir.join("backup/video.mp4");
+        std::fs::create_dir_all(dir.join("backup")).unwrap();
+        std::fs::write(&from, [1; 100]).unwrap();
+        // the previous backup, from before the copy started
+        std::fs::write(&to, [1; 60]).unwrap();
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&to)
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+
+        let mut file = InFlightFile::new(&from, &to, &Target::default()).unwrap();
+        file.update_offset();
+        assert_eq!(0, file.offset);
+        assert_eq!(None, file.resume_offset(&from, &to));
+
+        std::fs::write(&to, [1; 40]).unwrap();
+        file.update_offset();
+        assert_eq!(40, file.offset);
+        let yaml = serde_yaml::to_string(&file).unwrap();
+        let file: InFlightFile = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(Some(40), file.resume_offset(&from, &to));
+        // only for the same copy
+        assert_eq!(None, file.resume_offset(&from, &dir.join("other.mp4")));
+        assert_eq!(None, file.resume_offset(&to, &to));
+
+        // a changed source is copied from scratch
+        std::fs::write(&from, [2; 101]).unwrap();
+        assert_eq!(None, file.resume_offset(&from, &to));
+    }
+
+    #[test]
+    fn test_check_progress() {
+        let saved = |config: &str, index: &str| SavedProgress {
+            version: PROGRESS_VERSION,
+            config: config.to_owned(),
+            index: index.to_owned(),
+            sources: Progress::new(),
+            in_flight: Default::default(),
+            interrupted: BTreeMap::new(),
+        };
+
+        assert!(check_progress(&saved("c", "i"), "c", "i", true).is_ok());
+        assert!(check_progress(&saved("c", "i"), "c", "other", false).is_err());
+        assert!(check_progress(&saved("c", "i"), "changed", "i", false).is_ok());
+        assert!(check_progress(&saved("c", "i"), "changed", "i", true).is_err());
+        // written before the hashes were saved
+        assert!(check_progress(&saved("", ""), "c", "i", true).is_ok());
+    }
+
+    #[test]
+    fn test_reconcile_completed() {
+        let index: Index = vec![
+            ("a".to_owned(), SourceIndex::default()),
+            ("b".to_owned(), SourceIndex::default()),
+        ]
+        .into_iter()
+        .collect();
+        let progress = reconcile_progress(&index, Progress::new());
+        let completed: Completed = vec![
+            ("a".to_owned(), 0),
+            ("b".to_owned(), 5),
+            ("removed".to_owned(), 0),
+        ]
+        .into_iter()
+        .collect();
+
+        let completed = reconcile_completed(&index, completed, &progress);
+
+        // "b" had other files when it was completed
+        assert_eq!(vec!["a"], completed.keys().collect::<Vec<_>>());
+    }
 }
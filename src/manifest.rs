@@ -0,0 +1,253 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// What happened to a single file during the copy phase.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum CopyStatus {
+    #[serde(rename = "copied")]
+    Copied,
+    /// Copied and the copy's hash matched the source.
+    #[serde(rename = "verified")]
+    Verified,
+    /// Already copied by a previous run (`--continue`).
+    #[serde(rename = "skipped_incremental")]
+    SkippedIncremental,
+    /// The target already had a file with the same size and modification time (`skip_identical`).
+    #[serde(rename = "skipped_identical")]
+    SkippedIdentical,
+    /// The destination already existed and skipping was chosen (`--interactive`).
+    #[serde(rename = "skipped_conflict")]
+    SkippedConflict,
+    /// Not tried because the target ran out of space before.
+    #[serde(rename = "skipped_target_full")]
+    SkippedTargetFull,
+    #[serde(rename = "failed")]
+    Failed,
+}
+
+impl fmt::Display for CopyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CopyStatus::Copied => "copied",
+            CopyStatus::Verified => "verified",
+            CopyStatus::SkippedIncremental => "skipped (already copied)",
+            CopyStatus::SkippedIdentical => "skipped (identical)",
+            CopyStatus::SkippedConflict => "skipped (already exists)",
+            CopyStatus::SkippedTargetFull => "skipped (target full)",
+            CopyStatus::Failed => "failed",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub target: String,
+    pub file_size: u64,
+    pub status: CopyStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+impl ManifestEntry {
+    /// An entry copying `a` to `b` without an error, for tests.
+    pub fn for_test(target: &str, status: CopyStatus, file_size: u64) -> Self {
+        ManifestEntry {
+            from: "a".into(),
+            to: "b".into(),
+            target: target.to_owned(),
+            file_size,
+            status,
+            error: None,
+        }
+    }
+}
+
+/// Record of every file handled by the copy phase, per source.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub sources: BTreeMap<String, Vec<ManifestEntry>>,
+    /// Targets for which a copy failed because the disk was full; the remaining files for them were
+    /// skipped.
+    #[serde(default)]
+    pub out_of_space: BTreeSet<String>,
+    /// Time spent copying per target.
+    #[serde(default)]
+    pub targets: BTreeMap<String, TargetStats>,
+    /// Why copying was stopped early (`max_errors` / `fail_fast`); files after that weren't
+    /// attempted and aren't listed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<String>,
+}
+
+/// The files actually copied to a target (skipped ones don't count) and the time it took.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TargetStats {
+    pub files: usize,
+    pub bytes: u64,
+    /// Sum of the durations of all copies; files are copied in parallel, so this can be longer
+    /// than the copy phase.
+    pub copy_time_secs: f64,
+}
+
+impl TargetStats {
+    pub fn add(&mut self, bytes: u64, duration: Duration) {
+        self.files += 1;
+        self.bytes += bytes;
+        self.copy_time_secs += duration.as_secs_f64();
+    }
+
+    /// Average bytes per second of a single copy.
+    pub fn throughput(&self) -> u64 {
+        if self.copy_time_secs > 0.0 {
+            (self.bytes as f64 / self.copy_time_secs) as u64
+        } else {
+            0
+        }
+    }
+}
+
+impl Manifest {
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.sources.values().flatten()
+    }
+
+    /// Number of files and bytes per status.
+    pub fn status_counts(&self) -> BTreeMap<CopyStatus, (usize, u64)> {
+        self.entries().fold(BTreeMap::new(), |mut map, entry| {
+            let (count, size) = map.entry(entry.status).or_default();
+            *count += 1;
+            *size += entry.file_size;
+
+            map
+        })
+    }
+
+    /// Number of files and bytes that failed or were skipped because `target` was full.
+    pub fn not_copied_to(&self, target: &str) -> (usize, u64) {
+        self.entries()
+            .filter(|entry| {
+                entry.target == target
+                    && matches!(
+                        entry.status,
+                        CopyStatus::Failed | CopyStatus::SkippedTargetFull
+                    )
+            })
+            .fold((0, 0), |(count, size), entry| {
+                (count + 1, size + entry.file_size)
+            })
+    }
+
+    /// The entries to try again with `--retry-failed`: failed ones and those skipped because their
+    /// target was full.
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &ManifestEntry)> {
+        self.sources
+            .iter()
+            .flat_map(|(source, entries)| entries.iter().map(move |entry| (source.as_str(), entry)))
+            .filter(|(_, entry)| {
+                matches!(
+                    entry.status,
+                    CopyStatus::Failed | CopyStatus::SkippedTargetFull
+                )
+            })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        serde_yaml::from_reader(
+            File::open(path).with_context(|| format!("cannot open {}", path.display()))?,
+        )
+        .with_context(|| format!("cannot parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        serde_yaml::to_writer(
+            File::create(path).with_context(|| format!("cannot create {}", path.display()))?,
+            self,
+        )
+        .with_context(|| format!("cannot write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_status_counts() {
+        let entry = |status, file_size| ManifestEntry::for_test("t", status, file_size);
+        let mut manifest = Manifest::default();
+        manifest.sources.insert(
+            "src".to_owned(),
+            vec![
+                entry(CopyStatus::Copied, 10),
+                entry(CopyStatus::Copied, 5),
+                entry(CopyStatus::Failed, 7),
+            ],
+        );
+
+        let counts = manifest.status_counts();
+
+        assert_eq!(Some(&(2, 15)), counts.get(&CopyStatus::Copied));
+        assert_eq!(Some(&(1, 7)), counts.get(&CopyStatus::Failed));
+        assert_eq!(None, counts.get(&CopyStatus::Verified));
+    }
+
+    #[test]
+    fn test_not_copied_to() {
+        let entry = |target, status| ManifestEntry::for_test(target, status, 4);
+        let mut manifest = Manifest::default();
+        manifest.sources.insert(
+            "src".to_owned(),
+            vec![
+                entry("usb", CopyStatus::Copied),
+                entry("usb", CopyStatus::Failed),
+                entry("usb", CopyStatus::SkippedTargetFull),
+                entry("usb", CopyStatus::SkippedTargetFull),
+                entry("nas", CopyStatus::Copied),
+            ],
+        );
+
+        assert_eq!((3, 12), manifest.not_copied_to("usb"));
+        assert_eq!((0, 0), manifest.not_copied_to("nas"));
+    }
+
+    #[test]
+    fn test_stopped() {
+        let dir = TempDir::new("manifest-stopped");
+        let path = dir.join("manifest.yaml");
+
+        Manifest::default().save(&path).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("stopped"));
+        assert_eq!(None, Manifest::load(&path).unwrap().stopped);
+
+        let manifest = Manifest {
+            stopped: Some("stopped copying after 3 failed copies".to_owned()),
+            ..Manifest::default()
+        };
+        manifest.save(&path).unwrap();
+        assert_eq!(manifest.stopped, Manifest::load(&path).unwrap().stopped);
+    }
+
+    #[test]
+    fn test_target_stats() {
+        let mut stats = TargetStats::default();
+        assert_eq!(0, stats.throughput());
+
+        stats.add(3_000_000, Duration::from_secs(1));
+        stats.add(1_000_000, Duration::from_secs(3));
+
+        assert_eq!(2, stats.files);
+        assert_eq!(1_000_000, stats.throughput());
+    }
+}
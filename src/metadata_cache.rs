@@ -0,0 +1,195 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::img::ImageMetadata;
+
+/// Version of the cache file; a cache of another version is discarded and built again.
+const CACHE_VERSION: u32 = 1;
+
+const CACHE_FILE: &str = "img_metadata.json";
+
+/// The image metadata read in earlier runs (`cache_dir`), which is only set up if configured.
+static CACHE: OnceLock<MetadataCache> = OnceLock::new();
+
+/// Image metadata by path. An entry is only used while the file has the size and modification
+/// time it had when the metadata was read.
+pub struct MetadataCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+    /// The paths looked up in this run; other entries are dropped when saving if their file is
+    /// gone.
+    used: Mutex<HashSet<PathBuf>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Entry {
+    size: u64,
+    /// Nanoseconds since the Unix epoch.
+    modified: u128,
+    /// `None` for files that aren't images, so they aren't read again either.
+    metadata: Option<ImageMetadata>,
+}
+
+/// Loads the cache from `dir`, for all later calls of [`get_or_read`].
+pub fn init(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("cannot create cache directory {}", dir.display()))?;
+    let _ = CACHE.set(MetadataCache::load(&dir.join(CACHE_FILE)));
+
+    Ok(())
+}
+
+/// Writes the cache back, if there is one.
+pub fn save() -> Result<()> {
+    match CACHE.get() {
+        Some(cache) => cache.save(),
+        None => Ok(()),
+    }
+}
+
+/// The cached metadata of the file at `path`, or the result of `read` (which is then cached).
+pub fn get_or_read(
+    path: &Path,
+    read: impl FnOnce() -> Option<ImageMetadata>,
+) -> Option<ImageMetadata> {
+    match CACHE.get() {
+        Some(cache) => cache.get_or_read(path, read),
+        None => read(),
+    }
+}
+
+impl MetadataCache {
+    /// A missing, unreadable or outdated cache file gives an empty cache.
+    fn load(path: &Path) -> Self {
+        let entries = File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, CacheFile>(BufReader::new(file)).ok())
+            .filter(|cache| cache.version == CACHE_VERSION)
+            .map(|cache| cache.entries)
+            .unwrap_or_default();
+
+        MetadataCache {
+            path: path.to_owned(),
+            entries: Mutex::new(entries),
+            used: Default::default(),
+        }
+    }
+
+    fn get_or_read(
+        &self,
+        path: &Path,
+        read: impl FnOnce() -> Option<ImageMetadata>,
+    ) -> Option<ImageMetadata> {
+        let stamp = std::fs::metadata(path).ok().and_then(|meta| {
+            let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+            Some((meta.len(), modified.as_nanos()))
+        });
+        let (size, modified) = match stamp {
+            Some(stamp) => stamp,
+            // without a modification time, a changed file couldn't be noticed
+            None => return read(),
+        };
+
+        self.used.lock().insert(path.to_owned());
+        if let Some(entry) = self.entries.lock().get(path) {
+            if entry.size == size && entry.modified == modified {
+                return entry.metadata.clone();
+            }
+        }
+
+        let metadata = read();
+        self.entries.lock().insert(
+            path.to_owned(),
+            Entry {
+                size,
+                modified,
+                metadata: metadata.clone(),
+            },
+        );
+
+        metadata
+    }
+
+    fn save(&self) -> Result<()> {
+        let used = self.used.lock();
+        let mut entries = self.entries.lock().clone();
+        entries.retain(|path, _| used.contains(path) || path.exists());
+
+        let cache = CacheFile {
+            version: CACHE_VERSION,
+            entries,
+        };
+        let file = File::create(&self.path)
+            .with_context(|| format!("cannot create {}", self.path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), &cache)
+            .with_context(|| format!("cannot write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::ImageDimensions;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_cache() {
+        let dir = TempDir::new("cache");
+        let image = dir.join("a.jpg");
+        std::fs::write(&image, "not really an image").unwrap();
+        let metadata = |width| ImageMetadata {
+            date_time: None,
+            camera_make: Some("Camera".to_owned()),
+            camera_model: None,
+            dimensions: ImageDimensions { width, height: 1 },
+            orientation: Some(6),
+            flash_fired: None,
+        };
+        let width = |metadata: Option<ImageMetadata>| metadata.map(|m| m.dimensions.width);
+
+        let cache = MetadataCache::load(&dir.join(CACHE_FILE));
+        assert_eq!(
+            Some(10),
+            width(cache.get_or_read(&image, || Some(metadata(10))))
+        );
+        cache.save().unwrap();
+
+        // read again from the saved cache
+        let cache = MetadataCache::load(&dir.join(CACHE_FILE));
+        assert_eq!(
+            Some(10),
+            width(cache.get_or_read(&image, || unreachable!()))
+        );
+
+        // a changed file is read again
+        std::fs::write(&image, "a changed file").unwrap();
+        assert_eq!(
+            Some(20),
+            width(cache.get_or_read(&image, || Some(metadata(20))))
+        );
+        assert_eq!(
+            Some(20),
+            width(cache.get_or_read(&image, || unreachable!()))
+        );
+
+        std::fs::write(dir.join(CACHE_FILE), "{\"version\":0,\"entries\":{}}").unwrap();
+        let cache = MetadataCache::load(&dir.join(CACHE_FILE));
+        assert_eq!(None, width(cache.get_or_read(&image, || None)));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, Context, CopyInstruction, Index};
+
+/// Version of the format written by `--save-plan`. Plans of other versions are rejected instead of
+/// being guessed at; increase it whenever the meaning of a plan changes.
+pub const PLAN_VERSION: u32 = 1;
+
+/// All copy instructions, resolved: `--run-plan` copies exactly these without evaluating the
+/// file groups.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Plan {
+    version: u32,
+    /// Per source, ordered by original path.
+    sources: BTreeMap<String, Vec<PlannedCopy>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PlannedCopy {
+    from: PathBuf,
+    to: PathBuf,
+    /// In bytes, when the plan was made.
+    size: u64,
+    target: String,
+}
+
+/// Only the version, to reject other versions before trying to parse the rest.
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+pub fn save(index: &Index, path: &Path) -> Result<()> {
+    let sources = index
+        .iter()
+        .map(|(source, source_index)| {
+            let mut copies = source_index
+                .copy_instructions
+                .iter()
+                .map(|(from, instr)| PlannedCopy {
+                    from: from.clone(),
+                    to: instr.to.clone(),
+                    size: instr.file_size,
+                    target: instr.target.clone(),
+                })
+                .collect::<Vec<_>>();
+            copies.sort_by(|a, b| a.from.cmp(&b.from));
+
+            (source.clone(), copies)
+        })
+        .collect();
+    let plan = Plan {
+        version: PLAN_VERSION,
+        sources,
+    };
+
+    serde_yaml::to_writer(
+        File::create(path).with_context(|| format!("cannot create {}", path.display()))?,
+        &plan,
+    )
+    .with_context(|| format!("cannot write {}", path.display()))
+}
+
+/// Reads a plan into an index. Its targets have to be part of `config`.
+pub fn load(path: &Path, config: &Config) -> Result<Index> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("cannot open {}", path.display()))?;
+    parse(&text, config).with_context(|| format!("cannot read plan {}", path.display()))
+}
+
+fn parse(text: &str, config: &Config) -> Result<Index> {
+    let header: Header = serde_yaml::from_str(text).context("not a plan (no version)")?;
+    if header.version != PLAN_VERSION {
+        bail!(
+            "unsupported plan version {} (this version of sortnbackup reads version {})",
+            header.version,
+            PLAN_VERSION
+        );
+    }
+    let plan: Plan = serde_yaml::from_str(text)?;
+
+    plan.sources
+        .into_iter()
+        .map(|(source, copies)| {
+            let mut context = Context::default();
+            for copy in copies {
+                if !config.targets.contains_key(&copy.target) {
+                    bail!(
+                        "{} is copied to target '{}', which is not in the config",
+                        copy.from.display(),
+                        copy.target
+                    );
+                }
+
+                let instr = CopyInstruction {
+                    to: copy.to,
+                    file_size: copy.size,
+                    target: copy.target,
+                };
+                context.add_size(&copy.from, &instr);
+                context.copy_instructions.insert(copy.from, instr);
+            }
+
+            Ok((source, context.into()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Target;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_save_and_load() {
+        let mut index = Index::new();
+        let source_index = index.entry("home".to_owned()).or_default();
+        for (from, to) in [("/home/b.txt", "/nas/b.txt"), ("/home/a.txt", "/nas/a.txt")] {
+            source_index.copy_instructions.insert(
+                from.into(),
+                CopyInstruction {
+                    to: to.into(),
+                    file_size: 3,
+                    target: "nas".to_owned(),
+                },
+            );
+        }
+        let mut targets = std::collections::HashMap::new();
+        targets.insert("nas".to_owned(), Target::default());
+        let config = Config::with_targets(targets);
+        let dir = TempDir::new("plan");
+        let path = dir.join("plan.yaml");
+
+        save(&index, &path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            "version: 1
+sources:
+  home:
+  - from: /home/a.txt
+    to: /nas/a.txt
+    size: 3
+    target: nas
+  - from: /home/b.txt
+    to: /nas/b.txt
+    size: 3
+    target: nas
+",
+            text
+        );
+
+        let loaded = load(&path, &config).unwrap();
+        let instructions = &loaded["home"].copy_instructions;
+        assert_eq!(
+            Path::new("/nas/b.txt"),
+            instructions.get(&PathBuf::from("/home/b.txt")).unwrap().to
+        );
+        assert_eq!(Some(&6), loaded["home"].file_size_per_target.get("nas"));
+
+        let newer = text.replace("version: 1", "version: 2");
+        let error = parse(&newer, &config).err().unwrap();
+        assert!(error.to_string().contains("unsupported plan version 2"));
+        let unknown_target = text.replace("target: nas", "target: usb");
+        assert!(parse(&unknown_target, &config).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::config::Config;
+
+/// Time spent evaluating the filter of each file group while indexing (`--profile`), to find
+/// the expensive ones.
+pub struct Profile {
+    groups: Vec<GroupStats>,
+}
+
+/// Sources are indexed in parallel, so all counters are atomic.
+struct GroupStats {
+    name: String,
+    evaluated: AtomicU64,
+    matched: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl Profile {
+    pub fn new(config: &Config) -> Self {
+        let groups = config
+            .file_groups
+            .iter()
+            .map(|(name, _)| GroupStats {
+                name: name.clone(),
+                evaluated: AtomicU64::new(0),
+                matched: AtomicU64::new(0),
+                nanos: AtomicU64::new(0),
+            })
+            .collect();
+
+        Profile { groups }
+    }
+
+    /// Records an evaluation of the filter of the `group`-th file group.
+    pub fn record(&self, group: usize, elapsed: Duration, matched: bool) {
+        let stats = &self.groups[group];
+        stats.evaluated.fetch_add(1, Ordering::Relaxed);
+        if matched {
+            stats.matched.fetch_add(1, Ordering::Relaxed);
+        }
+        stats
+            .nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// The file groups by time spent, the most expensive first. Groups never evaluated (as an
+    /// earlier one matched all files) are left out.
+    pub fn report(&self) -> String {
+        let mut groups = self
+            .groups
+            .iter()
+            .map(|stats| {
+                (
+                    stats.name.as_str(),
+                    stats.evaluated.load(Ordering::Relaxed),
+                    stats.matched.load(Ordering::Relaxed),
+                    stats.nanos.load(Ordering::Relaxed),
+                )
+            })
+            .filter(|&(_, evaluated, _, _)| evaluated > 0)
+            .collect::<Vec<_>>();
+        groups.sort_by_key(|&(name, _, _, nanos)| (std::cmp::Reverse(nanos), name));
+        let total = groups.iter().map(|&(_, _, _, nanos)| nanos).sum::<u64>();
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Time spent in filters: {:.3} s",
+            Duration::from_nanos(total).as_secs_f64()
+        );
+        for (name, evaluated, matched, nanos) in groups {
+            let _ = writeln!(
+                out,
+                "  {}: {:.3} s ({:.0}%), {} files evaluated, {} matched, {:.1} µs per file",
+                name,
+                Duration::from_nanos(nanos).as_secs_f64(),
+                100.0 * nanos as f64 / total.max(1) as f64,
+                evaluated,
+                matched,
+                nanos as f64 / 1000.0 / evaluated as f64
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let config = Config::from_reader(
+            r#"
+settings:
+  file_size_style: binary
+sources: {}
+targets: {}
+file_groups:
+  cheap:
+    sources: all
+    filter: is_dir
+    rule: traverse
+  photos:
+    sources: all
+    filter: has_img_metadata
+    rule: ignore
+  unused:
+    sources: all
+    filter: catch_all
+    rule: ignore
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let profile = Profile::new(&config);
+
+        profile.record(0, Duration::from_millis(1), true);
+        profile.record(0, Duration::from_millis(1), false);
+        profile.record(1, Duration::from_millis(6), false);
+
+        assert_eq!(
+            "Time spent in filters: 0.008 s
+  photos: 0.006 s (75%), 1 files evaluated, 0 matched, 6000.0 µs per file
+  cheap: 0.002 s (25%), 2 files evaluated, 1 matched, 1000.0 µs per file
+",
+            profile.report()
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, fs::File, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::manifest::ManifestEntry;
+
+/// Newline-delimited JSON events about the copy phase (`--progress-json`), e.g. for a GUI wrapper.
+///
+/// ```text
+/// {"event":"start","bytes_total":300,"targets":{"nas":{"bytes_total":300}}}
+/// {"event":"file","source":"home","from":"...","to":"...","target":"nas","status":"copied","file_size":100,"bytes_done":100,"bytes_total":300,"target_bytes_done":100,"target_bytes_total":300}
+/// {"event":"finish","bytes_done":300,"bytes_total":300}
+/// ```
+///
+/// Failing to write an event doesn't affect the backup, so a closed reader can't abort it.
+pub struct ProgressStream {
+    state: Mutex<State>,
+    bytes_total: u64,
+    target_bytes_total: HashMap<String, u64>,
+}
+
+struct State {
+    out: File,
+    bytes_done: u64,
+    target_bytes_done: HashMap<String, u64>,
+}
+
+impl ProgressStream {
+    /// Creates the stream at `path` and writes the `start` event with the bytes to copy per target.
+    pub fn create(path: &Path, target_bytes_total: HashMap<String, u64>) -> Result<Self> {
+        let out = File::create(path)
+            .with_context(|| format!("cannot create progress stream at {}", path.display()))?;
+        let stream = ProgressStream {
+            state: Mutex::new(State {
+                out,
+                bytes_done: 0,
+                target_bytes_done: HashMap::new(),
+            }),
+            bytes_total: target_bytes_total.values().sum(),
+            target_bytes_total,
+        };
+
+        let targets = stream
+            .target_bytes_total
+            .iter()
+            .map(|(target, total)| (target.clone(), json!({ "bytes_total": total })))
+            .collect::<serde_json::Map<_, _>>();
+        stream.state.lock().write(json!({
+                "event": "start",
+                "bytes_total": stream.bytes_total,
+                "targets": targets,
+        }));
+
+        Ok(stream)
+    }
+
+    /// Writes the `file` event for a file that has been handled.
+    pub fn file(&self, source: &str, entry: &ManifestEntry) {
+        let mut state = self.state.lock();
+        state.bytes_done += entry.file_size;
+        let target_bytes_done = state
+            .target_bytes_done
+            .entry(entry.target.clone())
+            .or_default();
+        *target_bytes_done += entry.file_size;
+        let target_bytes_done = *target_bytes_done;
+
+        let event = json!({
+            "event": "file",
+            "source": source,
+            "from": entry.from.to_string_lossy(),
+            "to": entry.to.to_string_lossy(),
+            "target": entry.target,
+            "status": entry.status,
+            "file_size": entry.file_size,
+            "bytes_done": state.bytes_done,
+            "bytes_total": self.bytes_total,
+            "target_bytes_done": target_bytes_done,
+            "target_bytes_total": self.target_bytes_total.get(&entry.target).copied().unwrap_or(0),
+        });
+        state.write(event);
+    }
+
+    /// Writes the `finish` event.
+    pub fn finish(&self) {
+        let mut state = self.state.lock();
+        let event = json!({
+            "event": "finish",
+            "bytes_done": state.bytes_done,
+            "bytes_total": self.bytes_total,
+        });
+        state.write(event);
+    }
+}
+
+impl State {
+    fn write(&mut self, event: Value) {
+        let _ = writeln!(self.out, "{}", event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::CopyStatus;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_events() {
+        let dir = TempDir::new("progress");
+        let path = dir.join("progress.json");
+        let totals = vec![("nas".to_owned(), 300), ("usb".to_owned(), 50)]
+            .into_iter()
+            .collect();
+        let entry =
+            |target, file_size| ManifestEntry::for_test(target, CopyStatus::Copied, file_size);
+
+        let stream = ProgressStream::create(&path, totals).unwrap();
+        stream.file("home", &entry("nas", 100));
+        stream.file("home", &entry("usb", 50));
+        stream.finish();
+
+        let events = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(4, events.len());
+        assert_eq!(350, events[0]["bytes_total"]);
+        assert_eq!(300, events[0]["targets"]["nas"]["bytes_total"]);
+        assert_eq!("copied", events[1]["status"]);
+        assert_eq!(100, events[1]["bytes_done"]);
+        assert_eq!(100, events[1]["target_bytes_done"]);
+        assert_eq!(150, events[2]["bytes_done"]);
+        assert_eq!(50, events[2]["target_bytes_total"]);
+        assert_eq!(
+            json!({"event": "finish", "bytes_done": 150, "bytes_total": 350}),
+            events[3]
+        );
+    }
+}
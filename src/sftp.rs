@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+
+use crate::{
+    config::{Config, Remote, Settings, Target},
+    copy::part_path,
+    manifest::CopyStatus,
+    util::{file_digest, reader_digest},
+};
+
+/// Connections to the SFTP targets of a config, opened when first needed.
+pub struct Remotes {
+    pools: HashMap<String, Pool>,
+}
+
+impl Remotes {
+    pub fn new(config: &Config) -> Self {
+        let pools = config
+            .targets
+            .iter()
+            .filter_map(|(name, target)| Some((name.clone(), Pool::new(target.remote.clone()?))))
+            .collect();
+
+        Remotes { pools }
+    }
+
+    /// The connections to `target`, if it is an SFTP target.
+    pub fn get(&self, target: &str) -> Option<&Pool> {
+        self.pools.get(target)
+    }
+}
+
+/// Idle SFTP sessions to one server. Files are copied in parallel, so a session is checked out
+/// for each operation and only returned if the operation succeeded.
+pub struct Pool {
+    remote: Remote,
+    idle: Mutex<Vec<Sftp>>,
+}
+
+impl Pool {
+    fn new(remote: Remote) -> Self {
+        Pool {
+            remote,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn with_session<T>(&self, f: impl FnOnce(&Sftp) -> Result<T>) -> Result<T> {
+        let idle = self.idle.lock().pop();
+        let sftp = match idle {
+            Some(sftp) => sftp,
+            None => connect(&self.remote)?,
+        };
+
+        let result = f(&sftp);
+        if result.is_ok() {
+            self.idle.lock().push(sftp);
+        }
+
+        result
+    }
+
+    /// Copies a local file to `to` on the server, honoring the settings like a local copy.
+    pub fn copy(
+        &self,
+        from: &Path,
+        to: &Path,
+        settings: &Settings,
+        target: &Target,
+    ) -> Result<CopyStatus> {
+        self.with_session(|sftp| copy(sftp, from, to, settings, target))
+    }
+
+    /// Available space on the file system `path` (or its closest existing ancestor) is on.
+    pub fn available_space(&self, path: &Path) -> Result<u64> {
+        self.with_session(|sftp| {
+            let dir = remote_path(path);
+            let mut dir = dir.as_path();
+            let mut handle = loop {
+                match sftp.opendir(dir) {
+                    Ok(handle) => break handle,
+                    Err(e) => dir = dir.parent().ok_or(e)?,
+                }
+            };
+            let stat = handle.statvfs()?;
+
+            Ok(stat.f_bavail * stat.f_frsize)
+        })
+    }
+
+    /// Whether `path` is an existing directory on the server.
+    pub fn is_dir(&self, path: &Path) -> Result<bool> {
+        self.with_session(|sftp| {
+            Ok(sftp
+                .stat(&remote_path(path))
+                .is_ok_and(|stat| stat.is_dir()))
+        })
+    }
+
+    /// Checks that files can be created in the existing directory `dir`.
+    pub fn check_writable(&self, dir: &Path) -> Result<()> {
+        self.with_session(|sftp| {
+            let dir = remote_path(dir);
+            let probe = dir.join(format!(".sortnbackup-preflight-{}", std::process::id()));
+            sftp.create(&probe)?;
+            sftp.unlink(&probe)?;
+
+            Ok(())
+        })
+    }
+
+    pub fn remove_file(&self, path: &Path) -> Result<()> {
+        self.with_session(|sftp| Ok(sftp.unlink(&remote_path(path))?))
+    }
+
+    /// Removes the empty directory `path`.
+    pub fn remove_dir(&self, path: &Path) -> Result<()> {
+        self.with_session(|sftp| Ok(sftp.rmdir(&remote_path(path))?))
+    }
+}
+
+fn connect(remote: &Remote) -> Result<Sftp> {
+    let Remote {
+        user, host, port, ..
+    } = remote;
+    let context = || format!("cannot connect to {}@{}:{}", user, host, port);
+
+    let mut session = Session::new().with_context(context)?;
+    session.set_tcp_stream(TcpStream::connect((host.as_str(), *port)).with_context(context)?);
+    session.handshake().with_context(context)?;
+    check_host_key(&session, host, *port).with_context(context)?;
+
+    match &remote.identity_file {
+        Some(identity_file) => session.userauth_pubkey_file(user, None, identity_file, None),
+        None => session.userauth_agent(user),
+    }
+    .with_context(context)?;
+    if !session.authenticated() {
+        bail!("{}: authentication failed", context());
+    }
+
+    session.sftp().with_context(context)
+}
+
+/// Only servers listed in `~/.ssh/known_hosts` are trusted (connect with `ssh` once to add one).
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .ok_or_else(|| anyhow!("cannot find home directory for known_hosts"))?;
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.read_file(
+        &Path::new(&home).join(".ssh/known_hosts"),
+        KnownHostFileKind::OpenSSH,
+    )?;
+
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("server sent no host key"))?;
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => bail!("host key not in known_hosts, connect with ssh once"),
+        CheckResult::Mismatch => bail!("host key differs from the one in known_hosts"),
+        CheckResult::Failure => bail!("cannot check host key"),
+    }
+}
+
+fn copy(
+    sftp: &Sftp,
+    from: &Path,
+    to: &Path,
+    settings: &Settings,
+    target: &Target,
+) -> Result<CopyStatus> {
+    let to = remote_path(to);
+    let metadata = std::fs::metadata(from)?;
+    // SFTP only transfers whole seconds
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    if settings.skip_identical {
+        if let Ok(stat) = sftp.stat(&to) {
+            if stat.is_file() && stat.size == Some(metadata.len()) && stat.mtime == Some(modified) {
+                return Ok(CopyStatus::SkippedIdentical);
+            }
+        }
+    }
+
+    if let Some(parent) = to.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+    let dest = if target.staging {
+        part_path(&to)
+    } else {
+        to.clone()
+    };
+    let mut reader = BufReader::with_capacity(
+        target.buffer_size.unwrap_or(64 * 1024).max(1),
+        File::open(from)?,
+    );
+    let mut writer = sftp.create(&dest)?;
+    io::copy(&mut reader, &mut writer)?;
+    if settings.fsync {
+        // needs the fsync@openssh.com extension of the server
+        writer
+            .fsync()
+            .with_context(|| format!("cannot flush {} to disk", dest.display()))?;
+    }
+    writer.close()?;
+
+    let mut stat = sftp.stat(&dest)?;
+    stat.perm = settings.dest_mode.or_else(|| local_mode(&metadata));
+    if settings.skip_identical {
+        stat.atime = Some(modified);
+        stat.mtime = Some(modified);
+    }
+    sftp.setstat(&dest, stat)?;
+
+    let status = if target.verify {
+        let expected =
+            file_digest(from).with_context(|| format!("cannot hash {}", from.display()))?;
+        let actual = reader_digest(sftp.open(&dest)?)
+            .with_context(|| format!("cannot hash {}", dest.display()))?;
+        if expected != actual {
+            bail!("verification failed, copy differs from source");
+        }
+
+        CopyStatus::Verified
+    } else {
+        CopyStatus::Copied
+    };
+    if target.staging {
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        sftp.rename(&dest, &to, Some(flags))
+            .with_context(|| format!("cannot rename {} into place", dest.display()))?;
+    }
+
+    Ok(status)
+}
+
+fn create_dir_all(sftp: &Sftp, dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() || sftp.stat(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+
+    sftp.mkdir(dir, 0o755)
+        .with_context(|| format!("cannot create directory {}", dir.display()))
+}
+
+#[cfg(unix)]
+fn local_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn local_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Paths on the server always use `/`, also if built with `\` on Windows.
+fn remote_path(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        path.to_string_lossy().replace('\\', "/").into()
+    } else {
+        path.to_owned()
+    }
+}
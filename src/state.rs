@@ -0,0 +1,250 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::{CopyStatus, Manifest};
+
+/// Snapshot of what has been backed up, per source and original path (`state.yaml`).
+///
+/// Comparing it with the sources finds files that have been removed since, so their copies can
+/// be deleted without walking the targets (`sync_deletions`).
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    pub sources: BTreeMap<String, BTreeMap<PathBuf, BackedUp>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BackedUp {
+    pub to: PathBuf,
+    pub target: String,
+    #[serde(default)]
+    pub file_size: u64,
+    /// Modification time of the original when it was backed up.
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+}
+
+/// How the files about to be copied differ from the snapshot.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Changes {
+    pub new: usize,
+    pub modified: usize,
+    pub deleted: usize,
+}
+
+/// A file removed from its source whose copy should be deleted.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Removed {
+    pub source: String,
+    pub from: PathBuf,
+    pub backed_up: BackedUp,
+}
+
+impl State {
+    /// Loads the snapshot at `path`; there is none before the first run.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+
+        serde_yaml::from_reader(
+            File::open(path).with_context(|| format!("cannot open {}", path.display()))?,
+        )
+        .with_context(|| format!("cannot parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        serde_yaml::to_writer(
+            File::create(path).with_context(|| format!("cannot create {}", path.display()))?,
+            self,
+        )
+        .with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.values().all(BTreeMap::is_empty)
+    }
+
+    /// Compares the files about to be copied, given as source, original path and size, with the
+    /// snapshot; `deleted` is the number of files removed since.
+    pub fn changes<'a>(
+        &self,
+        files: impl IntoIterator<Item = (&'a str, &'a Path, u64)>,
+        deleted: usize,
+    ) -> Changes {
+        let mut changes = Changes {
+            deleted,
+            ..Default::default()
+        };
+
+        for (source, from, file_size) in files {
+            match self.sources.get(source).and_then(|files| files.get(from)) {
+                None => changes.new += 1,
+                Some(backed_up) => {
+                    let modified = std::fs::metadata(from).and_then(|m| m.modified()).ok();
+                    let is_modified =
+                        backed_up.modified.is_some() && backed_up.modified != modified;
+                    if backed_up.file_size != file_size || is_modified {
+                        changes.modified += 1;
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Files of the given sources that have been backed up, but no longer exist.
+    pub fn removed<'a>(&self, sources: impl IntoIterator<Item = &'a str>) -> Vec<Removed> {
+        sources
+            .into_iter()
+            .filter_map(|source| Some((source, self.sources.get(source)?)))
+            .flat_map(|(source, files)| {
+                files
+                    .iter()
+                    // doesn't follow symlinks, a dangling one still exists
+                    .filter(|(from, _)| std::fs::symlink_metadata(from).is_err())
+                    .map(move |(from, backed_up)| Removed {
+                        source: source.to_owned(),
+                        from: from.clone(),
+                        backed_up: backed_up.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Records the files backed up by a run and forgets the given removed files.
+    pub fn update(&mut self, manifest: &Manifest, forget: &[Removed]) {
+        for removed in forget {
+            if let Some(files) = self.sources.get_mut(&removed.source) {
+                files.remove(&removed.from);
+            }
+        }
+
+        for (source, entries) in &manifest.sources {
+            let files = self.sources.entry(source.clone()).or_default();
+            for entry in entries {
+                // skipped conflicts left another file at the destination
+                if !matches!(
+                    entry.status,
+                    CopyStatus::Failed
+                        | CopyStatus::SkippedConflict
+                        | CopyStatus::SkippedTargetFull
+                ) {
+                    files.insert(
+                        entry.from.clone(),
+                        BackedUp {
+                            to: entry.to.clone(),
+                            target: entry.target.clone(),
+                            file_size: entry.file_size,
+                            modified: std::fs::metadata(&entry.from)
+                                .and_then(|m| m.modified())
+                                .ok(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+
+    #[test]
+    fn test_removed_and_update() {
+        let backed_up = |to: &str| BackedUp {
+            to: to.into(),
+            target: "t".to_owned(),
+            file_size: 0,
+            modified: None,
+        };
+        let mut state = State::default();
+        let files = state.sources.entry("src".to_owned()).or_default();
+        files.insert("Cargo.toml".into(), backed_up("/backup/Cargo.toml"));
+        files.insert("does-not-exist.txt".into(), backed_up("/backup/gone.txt"));
+        state
+            .sources
+            .entry("other".to_owned())
+            .or_default()
+            .insert("also-gone.txt".into(), backed_up("/backup/also-gone.txt"));
+
+        let removed = state.removed(["src"]);
+        assert_eq!(
+            vec![Removed {
+                source: "src".to_owned(),
+                from: "does-not-exist.txt".into(),
+                backed_up: backed_up("/backup/gone.txt"),
+            }],
+            removed
+        );
+
+        let entry = |from: &str, status| ManifestEntry {
+            from: from.into(),
+            to: Path::new("/backup").join(from),
+            ..ManifestEntry::for_test("t", status, 0)
+        };
+        let mut manifest = Manifest::default();
+        manifest.sources.insert(
+            "src".to_owned(),
+            vec![
+                entry("README.md", CopyStatus::Copied),
+                entry("broken.txt", CopyStatus::Failed),
+            ],
+        );
+
+        state.update(&manifest, &removed);
+
+        let files = state.sources["src"].keys().collect::<Vec<_>>();
+        assert_eq!(vec![Path::new("Cargo.toml"), Path::new("README.md")], files);
+        assert!(state.sources["other"].contains_key(Path::new("also-gone.txt")));
+        assert_eq!(
+            std::fs::metadata("README.md").unwrap().modified().ok(),
+            state.sources["src"][Path::new("README.md")].modified
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut state = State::default();
+        let files = state.sources.entry("src".to_owned()).or_default();
+        for (from, file_size) in [("Cargo.toml", 1), ("README.md", 2)] {
+            files.insert(
+                from.into(),
+                BackedUp {
+                    to: Path::new("/backup").join(from),
+                    target: "t".to_owned(),
+                    file_size,
+                    modified: std::fs::metadata(from).unwrap().modified().ok(),
+                },
+            );
+        }
+
+        let changes = state.changes(
+            vec![
+                ("src", Path::new("Cargo.toml"), 1),
+                ("src", Path::new("README.md"), 3),
+                ("src", Path::new("LICENSE-MIT"), 4),
+                ("other", Path::new("Cargo.toml"), 1),
+            ],
+            5,
+        );
+
+        assert_eq!(
+            Changes {
+                new: 2,
+                modified: 1,
+                deleted: 5
+            },
+            changes
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::Ordering,
+};
+
+use crate::{
+    config::Config, manifest::Manifest, sftp::Remotes, util::find_disk, Completed, Index, Progress,
+};
+
+/// Prints what is to be copied from each source and what of it is skipped, sorted by name.
+pub fn print_sources(config: &Config, index: &Index, fmt_size: &dyn Fn(u64) -> String) {
+    println!("Summary:");
+    println!();
+
+    // sorted by name, so the summary of the same backup always looks the same
+    let mut sources = index.iter().collect::<Vec<_>>();
+    sources.sort_unstable_by_key(|(source, _)| *source);
+    for (source, context) in sources {
+        if config
+            .sources
+            .get(source)
+            .map(|s| s.disabled)
+            .unwrap_or(false)
+        {
+            continue;
+        }
+
+        println!("Source '{}'", source);
+        let total: u64 = context.file_size_per_target.values().cloned().sum();
+        println!("  Data to copy [all targets]: {}", fmt_size(total));
+        if total > 0 {
+            println!("  Data per target:");
+            let mut targets = context.file_size_per_target.iter().collect::<Vec<_>>();
+            targets.sort_unstable();
+            for (target, size) in targets {
+                println!("    To target '{}': {}", target, fmt_size(*size));
+            }
+        }
+        if !context.unreadable.is_empty() {
+            println!("  Unreadable files (skipped): {}", context.unreadable.len());
+            let mut unreadable = context.unreadable.iter().collect::<Vec<_>>();
+            unreadable.sort_unstable_by_key(|file| &file.path);
+            for file in unreadable {
+                println!("    {} ({})", file.path.display(), file.error);
+            }
+        }
+        if !context.outside_size_range.is_empty() {
+            println!(
+                "  Files outside the size range of their rule (skipped): {}",
+                context.outside_size_range.len()
+            );
+            let mut files = context.outside_size_range.iter().collect::<Vec<_>>();
+            files.sort_unstable_by_key(|file| &file.path);
+            for file in files {
+                println!("    {} ({})", file.path.display(), fmt_size(file.size));
+            }
+        }
+        if !context.inaccessible.is_empty() {
+            println!(
+                "  Inaccessible paths (skipped with their contents): {}",
+                context.inaccessible.len()
+            );
+            let mut inaccessible = context.inaccessible.iter().collect::<Vec<_>>();
+            inaccessible.sort_unstable_by_key(|path| &path.path);
+            for path in inaccessible {
+                println!("    {} ({})", path.path.display(), path.kind);
+            }
+        }
+    }
+
+    println!();
+}
+
+/// Prints how much data is to be copied to each target and, where it's known, how much space is
+/// free there.
+pub fn print_targets(
+    config: &Config,
+    index: &Index,
+    remotes: &Remotes,
+    continue_: bool,
+    fmt_size: &dyn Fn(u64) -> String,
+) {
+    index
+        .values()
+        .flat_map(|context| context.file_size_per_target.iter())
+        .fold(BTreeMap::new(), |mut map, (target, size)| {
+            *map.entry(target.clone()).or_default() += *size;
+
+            map
+        })
+        .iter()
+        .for_each(|(target, size)| {
+
+            if let Some((pool, remote_target)) = remotes.get(target).zip(config.targets.get(target)) {
+                match pool.available_space(&remote_target.path) {
+                    Ok(available) => {
+                        println!("Target '{}' [{}] (free space: {})", target, fmt_size(*size), fmt_size(available));
+                        if available < *size {
+                            eprintln!("WARNING: Free space on the server is less than data to copy (would need {} more)", fmt_size(*size - available));
+                        }
+                    }
+                    Err(e) => println!("Target '{}' [{}] (free space unknown: {:#})", target, fmt_size(*size), e),
+                }
+            } else if let Some(disk_info) = config.targets.get(target).and_then(|target| find_disk(&target.path)) {
+                println!("Target '{}' [{}] (free space: {})", target, fmt_size(*size), fmt_size(disk_info.available));
+
+                if disk_info.available < *size {
+                    eprintln!("WARNING: Free disk space on target's disk ({}) is less than data to copy (would need {} more)", disk_info.mount_point.display(), fmt_size(*size - disk_info.available));
+                    if continue_ {
+                        println!("Note: Continue option is enabled, space may be sufficient due to already copied files");
+                    } else if disk_info.capacity >= *size {
+                        println!("Note: Disk has enough (total) capacity (might want to free up space)");
+                    }
+                }
+            } else {
+                println!("Target '{}' [{}] (free space unknown)", target, fmt_size(*size));
+            }
+        });
+}
+
+/// Prints how much data is to be copied per file extension, largest first.
+pub fn print_file_types(index: &Index, fmt_size: &dyn Fn(u64) -> String) {
+    let mut size_per_extension = index
+        .values()
+        .flat_map(|context| context.file_size_per_extension.iter())
+        .fold(HashMap::new(), |mut map, (extension, size)| {
+            *map.entry(extension.as_str()).or_default() += *size;
+
+            map
+        })
+        .into_iter()
+        .collect::<Vec<(&str, u64)>>();
+    size_per_extension.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    if !size_per_extension.is_empty() {
+        println!();
+        println!("Data per file type:");
+        for (extension, size) in size_per_extension {
+            println!("  {}: {}", extension, fmt_size(size));
+        }
+    }
+}
+
+/// Prints the `total` size of all files, with `--continue` also what of it remains to be copied.
+pub fn print_total(
+    index: &Index,
+    progress: &Progress,
+    completed: &Completed,
+    total: u64,
+    continue_: bool,
+    fmt_size: &dyn Fn(u64) -> String,
+) {
+    println!();
+    if continue_ {
+        let remaining = index
+            .iter()
+            .filter(|(src, _)| !completed.contains_key(*src))
+            .flat_map(|(src, context)| {
+                context
+                    .copy_instructions
+                    .values()
+                    .skip(progress[src].load(Ordering::SeqCst) as usize)
+                    .map(|ci| ci.file_size)
+            })
+            .sum();
+
+        println!(
+            "Total data to copy (remaining): {} of {}",
+            fmt_size(remaining),
+            fmt_size(total)
+        );
+        if !completed.is_empty() {
+            println!(
+                "Completed sources (skipped): {}",
+                completed.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+    } else {
+        println!("Total data to copy: {}", fmt_size(total));
+    }
+}
+
+/// Prints the outcome of copying: the files per status, the targets that ran out of space and
+/// how fast each target was copied to.
+pub fn print_results(manifest: &Manifest, fmt_size: &dyn Fn(u64) -> String) {
+    println!();
+    for (status, (count, size)) in manifest.status_counts() {
+        println!("Files {}: {} ({})", status, count, fmt_size(size));
+    }
+    for target in &manifest.out_of_space {
+        let (count, size) = manifest.not_copied_to(target);
+        eprintln!(
+            "Target '{}' ran out of space, {} files ({}) were not copied to it (run again once there \
+             is space to copy them)",
+            target,
+            count,
+            fmt_size(size)
+        );
+    }
+    for (target, stats) in &manifest.targets {
+        println!(
+            "Target '{}': copied {} in {:.1} s ({}/s on average)",
+            target,
+            fmt_size(stats.bytes),
+            stats.copy_time_secs,
+            fmt_size(stats.throughput())
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use md5::Digest;
+use parking_lot::Mutex;
+
+use crate::{config::Config, util::file_digest};
+
+/// The files below the targets with `deduplicate`, to find out whether a file is already on them.
+///
+/// Only file sizes are collected up front; a file on the target is hashed the first time a file of
+/// the same size is looked up, so usually only a small part of the target is read.
+#[derive(Default)]
+pub struct TargetContents {
+    targets: HashMap<String, Contents>,
+}
+
+#[derive(Default)]
+struct Contents {
+    by_size: HashMap<u64, Vec<PathBuf>>,
+    digests: Mutex<HashMap<PathBuf, Option<Digest>>>,
+}
+
+impl TargetContents {
+    /// Lists the files of all targets with `deduplicate`. Targets that don't exist yet are empty.
+    pub fn scan(config: &Config) -> Result<Self> {
+        let targets = config
+            .targets
+            .iter()
+            .filter(|(_, target)| target.deduplicate)
+            .map(|(name, target)| {
+                let mut contents = Contents::default();
+                if target.path.exists() {
+                    contents
+                        .add_dir(&target.path)
+                        .with_context(|| format!("cannot list files of target '{}'", name))?;
+                }
+
+                Ok((name.clone(), contents))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(TargetContents { targets })
+    }
+
+    /// Whether a file with the same content as `path` (of `size` bytes) is below `target`.
+    /// Always false for targets without `deduplicate`.
+    pub fn contains(&self, target: &str, path: &Path, size: u64) -> Result<bool> {
+        let contents = match self.targets.get(target) {
+            Some(contents) => contents,
+            None => return Ok(false),
+        };
+        let candidates = match contents.by_size.get(&size) {
+            Some(candidates) => candidates,
+            None => return Ok(false),
+        };
+
+        let digest =
+            file_digest(path).with_context(|| format!("cannot hash {}", path.display()))?;
+        for candidate in candidates {
+            let existing = contents.digests.lock().get(candidate).copied();
+            let existing = match existing {
+                Some(existing) => existing,
+                None => {
+                    // unreadable files can't be compared, so they don't count as present
+                    let existing = file_digest(candidate).ok();
+                    contents.digests.lock().insert(candidate.clone(), existing);
+                    existing
+                }
+            };
+            if existing == Some(digest) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+impl Contents {
+    fn add_dir(&mut self, dir: &Path) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.add_dir(&entry.path())?;
+            } else if file_type.is_file() {
+                self.by_size
+                    .entry(entry.metadata()?.len())
+                    .or_default()
+                    .push(entry.path());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Target;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_contains() {
+        let dir = TempDir::new("contents");
+        std::fs::create_dir_all(dir.join("library/2023/trip")).unwrap();
+        std::fs::create_dir_all(dir.join("card")).unwrap();
+        std::fs::write(dir.join("library/2023/trip/IMG_1.jpg"), "photo one").unwrap();
+        std::fs::write(dir.join("card/DSC_0001.jpg"), "photo one").unwrap();
+        std::fs::write(dir.join("card/DSC_0002.jpg"), "photo two").unwrap();
+        std::fs::write(dir.join("card/DSC_0003.jpg"), "photo 3").unwrap();
+
+        let mut targets = HashMap::new();
+        for (name, deduplicate) in [("library", true), ("other", false)] {
+            targets.insert(
+                name.to_owned(),
+                Target {
+                    path: dir.join("library"),
+                    deduplicate,
+                    ..Default::default()
+                },
+            );
+        }
+        let config = Config::with_targets(targets);
+        let contents = TargetContents::scan(&config).unwrap();
+        let contains = |target, name| {
+            let path = dir.join("card").join(name);
+            let size = std::fs::metadata(&path).unwrap().len();
+            contents.contains(target, &path, size).unwrap()
+        };
+
+        // same content under another name
+        assert!(contains("library", "DSC_0001.jpg"));
+        // same size, different content
+        assert!(!contains("library", "DSC_0002.jpg"));
+        assert!(!contains("library", "DSC_0003.jpg"));
+        assert!(!contains("other", "DSC_0001.jpg"));
+    }
+}
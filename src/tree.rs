@@ -0,0 +1,125 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt::Write,
+    path::Path,
+};
+
+/// Directories with more files than this are collapsed into a single line.
+const MAX_LISTED_FILES: usize = 20;
+
+#[derive(Default)]
+struct Dir {
+    dirs: BTreeMap<OsString, Dir>,
+    files: BTreeSet<OsString>,
+}
+
+impl Dir {
+    fn insert(&mut self, path: &Path) {
+        let mut components = path.iter().collect::<Vec<_>>();
+        let file = match components.pop() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let dir = components.into_iter().fold(self, |dir, component| {
+            dir.dirs.entry(component.to_owned()).or_default()
+        });
+        dir.files.insert(file.to_owned());
+    }
+
+    fn render(&self, prefix: &str, out: &mut String) {
+        let mut lines = self
+            .dirs
+            .iter()
+            .map(|(name, dir)| (name.to_string_lossy(), Some(dir)))
+            .collect::<Vec<_>>();
+        if self.files.len() > MAX_LISTED_FILES {
+            lines.push((format!("... ({} files)", self.files.len()).into(), None));
+        } else {
+            lines.extend(self.files.iter().map(|name| (name.to_string_lossy(), None)));
+        }
+
+        let num_lines = lines.len();
+        for (i, (name, dir)) in lines.into_iter().enumerate() {
+            let last = i + 1 == num_lines;
+            let _ = writeln!(
+                out,
+                "{}{}{}",
+                prefix,
+                if last { "└── " } else { "├── " },
+                name
+            );
+
+            if let Some(dir) = dir {
+                let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                dir.render(&prefix, out);
+            }
+        }
+    }
+}
+
+/// Renders the given paths as a tree (like the `tree` command), relative to `root` if possible.
+pub fn render<'a>(root: &Path, paths: impl IntoIterator<Item = &'a Path>) -> String {
+    let mut tree = Dir::default();
+    for path in paths {
+        tree.insert(path.strip_prefix(root).unwrap_or(path));
+    }
+
+    let mut out = format!("{}\n", root.display());
+    tree.render("", &mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let paths = [
+            "backup/Images/2021/a.jpg",
+            "backup/Images/2021/b.jpg",
+            "backup/Images/2022/c.jpg",
+            "backup/notes.txt",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+        let tree = render(Path::new("backup"), paths.iter().map(PathBuf::as_path));
+
+        assert_eq!(
+            "backup
+├── Images
+│   ├── 2021
+│   │   ├── a.jpg
+│   │   └── b.jpg
+│   └── 2022
+│       └── c.jpg
+└── notes.txt
+",
+            tree
+        );
+    }
+
+    #[test]
+    fn test_render_collapses_large_dirs() {
+        let paths = (0..=MAX_LISTED_FILES)
+            .map(|i| PathBuf::from(format!("backup/Misc/{}.txt", i)))
+            .collect::<Vec<_>>();
+
+        let tree = render(Path::new("backup"), paths.iter().map(PathBuf::as_path));
+
+        assert_eq!(
+            format!(
+                "backup\n└── Misc\n    └── ... ({} files)\n",
+                MAX_LISTED_FILES + 1
+            ),
+            tree
+        );
+    }
+}
@@ -1,8 +1,11 @@
 use std::{
-    fs::canonicalize,
-    path::{Path, PathBuf},
+    fs::{canonicalize, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
 };
 
+use md5::Digest;
+use parking_lot::{Condvar, Mutex};
 use sysinfo::{DiskExt, RefreshKind, System, SystemExt};
 
 pub fn fix_cross_path(path: &str) -> PathBuf {
@@ -11,26 +14,69 @@ pub fn fix_cross_path(path: &str) -> PathBuf {
         .into()
 }
 
-/*
-pub fn dir_size(path: &Path) -> anyhow::Result<u64> {
-    fn dir_size(path: &Path) -> io::Result<u64> {
-        fn dir_size(mut dir: fs::ReadDir) -> io::Result<u64> {
-            dir.try_fold(0, |acc, file| {
-                let file = file?;
-                let size = match file.metadata()? {
-                    data if data.is_dir() => dir_size(fs::read_dir(file.path())?)?,
-                    data => data.len(),
-                };
-                Ok(acc + size)
-            })
+/// Total size of all files below `path` (recursively).
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    fn dir_size(dir: std::fs::ReadDir) -> io::Result<u64> {
+        dir.into_iter().try_fold(0, |acc, file| {
+            let file = file?;
+            let size = match file.metadata()? {
+                data if data.is_dir() => dir_size(std::fs::read_dir(file.path())?)?,
+                data => data.len(),
+            };
+            Ok(acc + size)
+        })
+    }
+
+    dir_size(std::fs::read_dir(path)?)
+}
+
+/// Computes the md5 hash of a file without reading it into memory at once.
+pub fn file_digest(path: &Path) -> io::Result<Digest> {
+    reader_digest(File::open(path)?)
+}
+
+/// Computes the md5 hash of everything `reader` yields.
+pub fn reader_digest(mut reader: impl Read) -> io::Result<Digest> {
+    let mut context = md5::Context::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(context.compute()),
+            n => context.consume(&buf[..n]),
         }
+    }
+}
+
+/// Checks that files can be created in the existing directory `dir` by writing and removing a
+/// temporary file.
+pub fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".sortnbackup-preflight-{}", std::process::id()));
+    let result = File::create(&probe).and_then(|mut file| file.write_all(b"sortnbackup"));
+    let _ = std::fs::remove_file(&probe);
 
-        dir_size(fs::read_dir(path)?)
+    result
+}
+
+/// `path` moved below `prefix` (`/mnt/backup` with `/tmp/test` -> `/tmp/test/mnt/backup`).
+/// Windows drive letters become a folder (`C:\Backup` -> `C\Backup`).
+pub fn relocate(prefix: &Path, path: &Path) -> PathBuf {
+    let mut relocated = prefix.to_owned();
+    for component in path.components() {
+        match component {
+            Component::Prefix(drive) => relocated.push(
+                drive
+                    .as_os_str()
+                    .to_string_lossy()
+                    .replace([':', '\\', '?'], ""),
+            ),
+            Component::RootDir => {}
+            component => relocated.push(component),
+        }
     }
 
-    dir_size(path).with_context(|| anyhow!("failed to calc dir size of {}", path.display()))
+    relocated
 }
-*/
 
 pub fn is_root_path_of(path: &Path, root: &Path) -> bool {
     let mut path = path;
@@ -46,6 +92,7 @@ pub fn is_root_path_of(path: &Path, root: &Path) -> bool {
 }
 
 pub struct DiskInfo {
+    #[allow(dead_code)]
     pub name: String,
     pub available: u64,
     pub capacity: u64,
@@ -74,3 +121,199 @@ pub fn find_disk(path: &Path) -> Option<DiskInfo> {
         })
         .next()
 }
+
+/// The id of the user called `name`, if there is one.
+#[cfg(unix)]
+pub fn user_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `passwd` is plain data, filled in by `getpwnam_r` before it's read.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+
+    // SAFETY: all pointers are valid for the call, `buf` for `buf.len()` bytes.
+    lookup(|buf, result: &mut *mut libc::passwd| unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            result,
+        )
+    })
+    .map(|()| passwd.pw_uid)
+}
+
+/// The id of the group called `name`, if there is one.
+#[cfg(unix)]
+pub fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `group` is plain data, filled in by `getgrnam_r` before it's read.
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+
+    // SAFETY: all pointers are valid for the call, `buf` for `buf.len()` bytes.
+    lookup(|buf, result: &mut *mut libc::group| unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            result,
+        )
+    })
+    .map(|()| group.gr_gid)
+}
+
+/// Calls a `get*nam_r` function with a growing buffer for the strings of the entry until it fits.
+#[cfg(unix)]
+fn lookup<T>(mut f: impl FnMut(&mut [libc::c_char], &mut *mut T) -> libc::c_int) -> Option<()> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut result = std::ptr::null_mut();
+        match f(&mut buf, &mut result) {
+            0 if result.is_null() => return None,
+            0 => return Some(()),
+            libc::ERANGE if buf.len() < 1024 * 1024 => buf.resize(buf.len() * 2, 0),
+            _ => return None,
+        }
+    }
+}
+
+/// A counting semaphore limiting how many threads may hold a permit at the same time.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut permits = self.permits.lock();
+        while *permits == 0 {
+            self.released.wait(&mut permits);
+        }
+        *permits -= 1;
+
+        SemaphoreGuard(self)
+    }
+}
+
+pub struct SemaphoreGuard<'a>(&'a Semaphore);
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.permits.lock() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// A fresh directory for a test, removed again when dropped, also if the test panics.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// `name` has to be unique among the tests, as they run in parallel.
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("sortnbackup-{}-{}", name, std::process::id()));
+        // left behind by an aborted run
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl From<&TempDir> for PathBuf {
+    fn from(dir: &TempDir) -> Self {
+        dir.0.clone()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relocate() {
+        let prefix = Path::new("/tmp/test");
+
+        assert_eq!(
+            Path::new("/tmp/test/mnt/backup"),
+            relocate(prefix, Path::new("/mnt/backup"))
+        );
+        assert_eq!(
+            Path::new("/tmp/test/backup"),
+            relocate(prefix, Path::new("backup"))
+        );
+        assert_eq!(
+            Path::new("/tmp/test/mnt/backup/Images"),
+            relocate(prefix, Path::new("/mnt/backup")).join("Images")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_user_and_group_id() {
+        assert_eq!(Some(0), user_id("root"));
+        assert_eq!(None, user_id("no-such-user-sortnbackup"));
+        assert_eq!(None, user_id("nul\0byte"));
+        assert!(group_id("root").or_else(|| group_id("wheel")).is_some());
+        assert_eq!(None, group_id("no-such-group-sortnbackup"));
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let dir = TempDir::new("writable");
+
+        assert!(check_writable(&dir).is_ok());
+        assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+
+        // e.g. the mount point of a drive that isn't mounted, which must not be created
+        assert!(check_writable(&dir.join("unmounted")).is_err());
+        assert!(!dir.join("unmounted").exists());
+        assert!(check_writable(Path::new("Cargo.toml")).is_err());
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let dir = TempDir::new("dir-size");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), [0; 10]).unwrap();
+        std::fs::write(dir.join("sub/b"), [0; 5]).unwrap();
+
+        let size = dir_size(&dir).unwrap();
+
+        assert_eq!(15, size);
+        assert!(dir_size(Path::new("Cargo.toml")).is_err());
+    }
+}